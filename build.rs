@@ -26,6 +26,8 @@ fn main() {
     }
 
     config.define("LUA_USER_H", "\"../src/ulua.h\"");
+    // the panic function of built states unwinds through Lua
+    config.flag_if_supported("-fexceptions");
 
     config
         .include("lua")
@@ -63,4 +65,9 @@ fn main() {
         .file("lua/lvm.c")
         .file("lua/lzio.c")
         .compile("liblua5.3.a");
+
+    // cc prints rerun-if-env-changed, which turns off rerunning on any change
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=lua");
+    println!("cargo:rerun-if-changed=src/ulua.h");
 }
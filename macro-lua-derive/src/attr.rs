@@ -0,0 +1,146 @@
+use proc_macro2::Span;
+use syn::{Attribute, ExprPath, Fields, Ident, LitStr, Member, Type, Variant};
+use syn::spanned::Spanned;
+
+/// How an enum is laid out in Lua, set with `#[lua(tag = "..")]` and
+/// `#[lua(tag = "..", content = "..")]` on the enum.
+pub enum Tagging {
+    /// `"Unit"` or `{ Variant = content }`, the default.
+    External,
+    /// `{ [tag] = "Variant", fields... }`.
+    Internal(String),
+    /// `{ [tag] = "Variant", [content] = content }`.
+    Adjacent(String, String),
+}
+
+pub enum Default {
+    None,
+    Trait,
+    Path(ExprPath),
+}
+
+pub struct Field<'a> {
+    pub member: Member,
+    pub binding: Ident,
+    pub ty: &'a Type,
+    /// Key of the field in the Lua table.
+    pub name: String,
+    pub default: Default,
+    pub skip: bool,
+    pub flatten: bool,
+}
+
+impl Field<'_> {
+    /// Expression producing the value of a skipped or missing field.
+    pub fn default_expr(&self) -> proc_macro2::TokenStream {
+        match &self.default {
+            Default::Path(path) => quote::quote!(#path()),
+            _ => quote::quote!(::std::default::Default::default()),
+        }
+    }
+}
+
+fn lua_attrs(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+    attrs.iter().filter(|a| a.path().is_ident("lua"))
+}
+
+pub fn tagging(attrs: &[Attribute]) -> syn::Result<Tagging> {
+    let mut tag = None;
+    let mut content = None;
+    for attr in lua_attrs(attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                tag = Some(meta.value()?.parse::<LitStr>()?);
+            } else if meta.path.is_ident("content") {
+                content = Some(meta.value()?.parse::<LitStr>()?);
+            } else {
+                return Err(meta.error("unknown container attribute, expected `tag` or `content`"));
+            }
+            Ok(())
+        })?;
+    }
+    match (tag, content) {
+        (None, None) => Ok(Tagging::External),
+        (Some(tag), None) => Ok(Tagging::Internal(tag.value())),
+        (Some(tag), Some(content)) => Ok(Tagging::Adjacent(tag.value(), content.value())),
+        (None, Some(content)) => Err(syn::Error::new_spanned(content, "`content` requires `tag`")),
+    }
+}
+
+/// Rejects container attributes on types that cannot be tagged.
+pub fn no_tagging(attrs: &[Attribute]) -> syn::Result<()> {
+    match lua_attrs(attrs).next() {
+        Some(attr) => Err(syn::Error::new_spanned(attr, "`tag` and `content` are only supported on enums")),
+        None => Ok(()),
+    }
+}
+
+pub fn variant_name(variant: &Variant) -> syn::Result<String> {
+    let mut name = variant.ident.to_string();
+    for attr in lua_attrs(&variant.attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("unknown variant attribute, expected `rename`"))
+            }
+        })?;
+    }
+    Ok(name)
+}
+
+pub fn fields(fields: &Fields) -> syn::Result<Vec<Field<'_>>> {
+    let mut result = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let (member, mut name) = match &field.ident {
+            Some(ident) => (Member::Named(ident.clone()), ident.to_string()),
+            None => (Member::Unnamed(i.into()), String::new()),
+        };
+        let mut default = Default::None;
+        let mut skip = false;
+        let mut flatten = false;
+        for attr in lua_attrs(&field.attrs) {
+            if field.ident.is_none() {
+                return Err(syn::Error::new_spanned(attr, "attributes are only supported on named fields"));
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    name = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("default") {
+                    default = if meta.input.peek(syn::Token![=]) {
+                        Default::Path(meta.value()?.parse::<LitStr>()?.parse()?)
+                    } else {
+                        Default::Trait
+                    };
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else if meta.path.is_ident("flatten") {
+                    flatten = true;
+                } else {
+                    return Err(meta.error("unknown field attribute, expected `rename`, `default`, `skip` or `flatten`"));
+                }
+                Ok(())
+            })?;
+        }
+        if flatten && (skip || !matches!(default, Default::None)) {
+            return Err(syn::Error::new_spanned(field, "`flatten` cannot be combined with `skip` or `default`"));
+        }
+        result.push(Field {
+            member,
+            binding: Ident::new(&format!("__field{}", i), Span::call_site()),
+            ty: &field.ty,
+            name,
+            default,
+            skip,
+            flatten,
+        });
+    }
+    let mut seen = std::collections::HashSet::new();
+    for (field, f) in result.iter().zip(fields.iter()) {
+        if !field.skip && !field.flatten && !field.name.is_empty() && !seen.insert(field.name.clone()) {
+            return Err(syn::Error::new(f.span(), format!("duplicate Lua key `{}`", field.name)));
+        }
+    }
+    Ok(result)
+}
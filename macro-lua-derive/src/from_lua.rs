@@ -0,0 +1,212 @@
+use crate::attr::{self, Default, Field, Tagging};
+
+use proc_macro2::{Literal, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::{Data, DeriveInput, Fields};
+use syn::spanned::Spanned;
+
+pub fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let ident = &input.ident;
+    let expected = ident.to_string();
+    let generics = crate::with_bound(&input.generics, quote!(::macro_lua::FromLua));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            attr::no_tagging(&input.attrs)?;
+            let fields = attr::fields(&data.fields)?;
+            if let Fields::Named(_) = data.fields {
+                let construct = read_named(quote!(#ident), &fields);
+                return Ok(quote! {
+                    impl #impl_generics ::macro_lua::FromLuaFields for #ident #ty_generics #where_clause {
+                        fn from_lua_fields(state: &::macro_lua::State, index: ::macro_lua::Index)
+                            -> ::std::result::Result<Self, ::macro_lua::ConversionError>
+                        {
+                            let index = state.abs_index(index);
+                            Ok(#construct)
+                        }
+                    }
+
+                    impl #impl_generics ::macro_lua::FromLua for #ident #ty_generics #where_clause {
+                        fn from_lua(state: &::macro_lua::State, index: ::macro_lua::Index) -> Option<Self> {
+                            Self::try_from_lua(state, index).ok()
+                        }
+
+                        fn try_from_lua(state: &::macro_lua::State, index: ::macro_lua::Index)
+                            -> ::std::result::Result<Self, ::macro_lua::ConversionError>
+                        {
+                            if !state.is_table(index) {
+                                return Err(::macro_lua::ConversionError::new(state, index, #expected));
+                            }
+                            ::macro_lua::FromLuaFields::from_lua_fields(state, index)
+                        }
+                    }
+                });
+            }
+            let content = read_content(quote!(#ident), &data.fields, &fields, &expected);
+            quote! {
+                let index = state.abs_index(index);
+                #content
+            }
+        }
+        Data::Enum(data) => {
+            let tagging = attr::tagging(&input.attrs)?;
+            let mut units = Vec::new();
+            let mut others = Vec::new();
+            for variant in &data.variants {
+                let v = &variant.ident;
+                let name = attr::variant_name(variant)?;
+                let fields = attr::fields(&variant.fields)?;
+                let path = quote!(#ident::#v);
+                let expected = format!("{}::{}", ident, v);
+                let content = match (&tagging, &variant.fields) {
+                    (_, Fields::Unit) => quote!(Ok(#path)),
+                    (Tagging::Internal(_), Fields::Named(_)) => {
+                        let construct = read_named(path, &fields);
+                        quote!(Ok(#construct))
+                    }
+                    (Tagging::Internal(_), Fields::Unnamed(_)) if fields.len() == 1 => {
+                        let ty = fields[0].ty;
+                        quote_spanned! { ty.span() =>
+                            <#ty as ::macro_lua::FromLuaFields>::from_lua_fields(state, index).map(#path)
+                        }
+                    }
+                    (Tagging::Internal(_), Fields::Unnamed(_)) => return Err(syn::Error::new_spanned(
+                        variant, "tuple variants cannot be internally tagged",
+                    )),
+                    _ => read_content(path, &variant.fields, &fields, &expected),
+                };
+                match variant.fields {
+                    Fields::Unit => units.push((name, content)),
+                    _ => others.push((name, content)),
+                }
+            }
+            let names = units.iter().chain(&others)
+                .map(|(name, _)| format!("{:?}", name))
+                .collect::<Vec<_>>();
+            let one_of = format!("one of {}", names.join(", "));
+
+            match &tagging {
+                Tagging::External => {
+                    let unit_arms = units.iter().map(|(name, content)| quote! { Some(#name) => return #content, });
+                    let other_arms = others.iter().map(|(name, content)| quote! {
+                        state.push(#name);
+                        let present = state.raw_get(index) != ::macro_lua::Type::Nil;
+                        state.pop(1);
+                        if present {
+                            return state.try_field_with(index, #name, |state, index| #content);
+                        }
+                    });
+                    quote! {
+                        let index = state.abs_index(index);
+                        if state.type_of(index) == ::macro_lua::Type::String {
+                            match state.to_str(index).as_deref() {
+                                #(#unit_arms)*
+                                _ => {}
+                            }
+                        }
+                        if state.is_table(index) {
+                            #(#other_arms)*
+                        }
+                        Err(::macro_lua::ConversionError::new(state, index, #expected))
+                    }
+                }
+                Tagging::Internal(tag) | Tagging::Adjacent(tag, _) => {
+                    let arms = units.iter().chain(&others).map(|(name, content)| {
+                        let content = match &tagging {
+                            Tagging::Adjacent(_, key) if others.iter().any(|(n, _)| n == name) => quote! {
+                                state.try_field_with(index, #key, |state, index| #content)
+                            },
+                            _ => content.clone(),
+                        };
+                        quote! { #name => #content, }
+                    });
+                    quote! {
+                        let index = state.abs_index(index);
+                        if !state.is_table(index) {
+                            return Err(::macro_lua::ConversionError::new(state, index, #expected));
+                        }
+                        let tag: String = state.try_field(index, #tag)?;
+                        match tag.as_str() {
+                            #(#arms)*
+                            _ => Err(::macro_lua::ConversionError {
+                                path: vec![::macro_lua::PathSegment::Key(#tag.into())],
+                                expected: #one_of.into(),
+                                found: "string",
+                            }),
+                        }
+                    }
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(data.union_token, "unions cannot derive FromLua"));
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::macro_lua::FromLua for #ident #ty_generics #where_clause {
+            fn from_lua(state: &::macro_lua::State, index: ::macro_lua::Index) -> Option<Self> {
+                Self::try_from_lua(state, index).ok()
+            }
+
+            fn try_from_lua(state: &::macro_lua::State, index: ::macro_lua::Index)
+                -> ::std::result::Result<Self, ::macro_lua::ConversionError>
+            {
+                #body
+            }
+        }
+    })
+}
+
+/// Builds `path { .. }` from the table at the absolute `index`, returning
+/// early on the first field that fails to convert.
+fn read_named(path: TokenStream, fields: &[Field]) -> TokenStream {
+    let items = fields.iter().map(|f| {
+        let member = &f.member;
+        let ty = f.ty;
+        let name = &f.name;
+        let value = if f.skip {
+            f.default_expr()
+        } else if f.flatten {
+            quote_spanned! { ty.span() => <#ty as ::macro_lua::FromLuaFields>::from_lua_fields(state, index)? }
+        } else if let Default::None = f.default {
+            quote_spanned! { ty.span() => state.try_field::<#ty>(index, #name)? }
+        } else {
+            let default = f.default_expr();
+            quote_spanned! { ty.span() => state.try_field_or::<#ty>(index, #name, || #default)? }
+        };
+        quote!(#member: #value)
+    });
+    quote!(#path { #(#items),* })
+}
+
+/// An expression converting the value at the absolute `index` to `path`, in
+/// the same layout `ToLua` pushes it.
+fn read_content(path: TokenStream, kind: &Fields, fields: &[Field], expected: &str) -> TokenStream {
+    let construct = match kind {
+        Fields::Named(_) => read_named(path, fields),
+        Fields::Unnamed(_) if fields.len() == 1 => {
+            let ty = fields[0].ty;
+            return quote_spanned! { ty.span() =>
+                <#ty as ::macro_lua::FromLua>::try_from_lua(state, index).map(#path)
+            };
+        }
+        Fields::Unnamed(_) => {
+            let items = fields.iter().enumerate().map(|(i, f)| {
+                let ty = f.ty;
+                let i = Literal::i64_unsuffixed(i as i64 + 1);
+                quote_spanned! { ty.span() => state.try_element::<#ty>(index, #i)? }
+            });
+            quote!(#path(#(#items),*))
+        }
+        Fields::Unit => path,
+    };
+    quote! {
+        if !state.is_table(index) {
+            Err(::macro_lua::ConversionError::new(state, index, #expected))
+        } else {
+            Ok(#construct)
+        }
+    }
+}
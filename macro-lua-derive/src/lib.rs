@@ -0,0 +1,42 @@
+//! Derive macros for the `ToLua` and `FromLua` traits of `macro-lua`,
+//! re-exported by that crate behind its `derive` feature.
+//!
+//! Structs with named fields map to tables keyed by field name, tuple structs
+//! to sequences and newtype structs to their inner value. Fields accept
+//! `#[lua(rename = "key")]`, `#[lua(default)]` or `#[lua(default = "path")]`,
+//! `#[lua(skip)]` and `#[lua(flatten)]`. Enums are externally tagged unless
+//! marked with `#[lua(tag = "type")]` or `#[lua(tag = "t", content = "c")]`,
+//! and their variants accept `#[lua(rename = "name")]`.
+
+extern crate proc_macro;
+
+mod attr;
+mod to_lua;
+mod from_lua;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{parse_macro_input, parse_quote, DeriveInput, Generics, GenericParam};
+
+#[proc_macro_derive(ToLua, attributes(lua))]
+pub fn derive_to_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    to_lua::expand(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[proc_macro_derive(FromLua, attributes(lua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_lua::expand(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// Adds `bound` to every type parameter.
+fn with_bound(generics: &Generics, bound: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in &mut generics.params {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds.push(parse_quote!(#bound));
+        }
+    }
+    generics
+}
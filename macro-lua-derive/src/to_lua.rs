@@ -0,0 +1,203 @@
+use crate::attr::{self, Field, Tagging};
+
+use proc_macro2::{Literal, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::{Data, DeriveInput, Fields};
+use syn::spanned::Spanned;
+
+pub fn expand(input: &DeriveInput) -> syn::Result<TokenStream> {
+    let ident = &input.ident;
+    let generics = crate::with_bound(&input.generics, quote!(::macro_lua::ToLua));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            attr::no_tagging(&input.attrs)?;
+            let fields = attr::fields(&data.fields)?;
+            let pattern = pattern(quote!(#ident), &data.fields, &fields);
+            if let Fields::Named(_) = data.fields {
+                let writes = write_fields(&fields);
+                let count = record_count(&fields, 0);
+                return Ok(quote! {
+                    impl #impl_generics ::macro_lua::ToLuaFields for #ident #ty_generics #where_clause {
+                        fn to_lua_fields(self, state: &::macro_lua::State, index: ::macro_lua::Index) {
+                            let index = state.abs_index(index);
+                            let #pattern = self;
+                            #writes
+                        }
+                    }
+
+                    impl #impl_generics ::macro_lua::ToLua for #ident #ty_generics #where_clause {
+                        fn to_lua(self, state: &::macro_lua::State) {
+                            state.create_table(0, #count);
+                            ::macro_lua::ToLuaFields::to_lua_fields(self, state, -1);
+                        }
+                    }
+                });
+            }
+            let push = push_content(&data.fields, &fields);
+            quote! { let #pattern = self; #push }
+        }
+        Data::Enum(data) => {
+            let tagging = attr::tagging(&input.attrs)?;
+            let mut arms = Vec::new();
+            for variant in &data.variants {
+                let v = &variant.ident;
+                let name = attr::variant_name(variant)?;
+                let fields = attr::fields(&variant.fields)?;
+                let pattern = pattern(quote!(#ident::#v), &variant.fields, &fields);
+                let body = match &tagging {
+                    Tagging::External => match variant.fields {
+                        Fields::Unit => quote! { state.push(#name); },
+                        _ => {
+                            let push = push_content(&variant.fields, &fields);
+                            quote! {
+                                state.create_table(0, 1);
+                                state.push(#name);
+                                #push
+                                state.raw_set(-3);
+                            }
+                        }
+                    },
+                    Tagging::Internal(tag) => {
+                        let writes = match &variant.fields {
+                            Fields::Named(_) => write_fields(&fields),
+                            Fields::Unnamed(_) if fields.len() == 1 => {
+                                let binding = &fields[0].binding;
+                                quote_spanned! { fields[0].ty.span() =>
+                                    ::macro_lua::ToLuaFields::to_lua_fields(#binding, state, index);
+                                }
+                            }
+                            Fields::Unnamed(_) => return Err(syn::Error::new_spanned(
+                                variant, "tuple variants cannot be internally tagged",
+                            )),
+                            Fields::Unit => quote!(),
+                        };
+                        let count = record_count(&fields, 1);
+                        quote! {
+                            state.create_table(0, #count);
+                            let index = state.abs_index(-1);
+                            state.push(#tag);
+                            state.push(#name);
+                            state.raw_set(index);
+                            #writes
+                        }
+                    }
+                    Tagging::Adjacent(tag, content) => {
+                        let content = match variant.fields {
+                            Fields::Unit => quote!(),
+                            _ => {
+                                let push = push_content(&variant.fields, &fields);
+                                quote! {
+                                    state.push(#content);
+                                    #push
+                                    state.raw_set(-3);
+                                }
+                            }
+                        };
+                        quote! {
+                            state.create_table(0, 2);
+                            state.push(#tag);
+                            state.push(#name);
+                            state.raw_set(-3);
+                            #content
+                        }
+                    }
+                };
+                arms.push(quote! { #pattern => { #body } });
+            }
+            quote! { match self { #(#arms)* } }
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(data.union_token, "unions cannot derive ToLua"));
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::macro_lua::ToLua for #ident #ty_generics #where_clause {
+            fn to_lua(self, state: &::macro_lua::State) {
+                #body
+            }
+        }
+    })
+}
+
+/// Destructures `self`, binding each field to `Field::binding`.
+fn pattern(path: TokenStream, kind: &Fields, fields: &[Field]) -> TokenStream {
+    match kind {
+        Fields::Named(_) => {
+            let items = fields.iter().map(|f| {
+                let member = &f.member;
+                let binding = &f.binding;
+                if f.skip { quote!(#member: _) } else { quote!(#member: #binding) }
+            });
+            quote!(#path { #(#items),* })
+        }
+        Fields::Unnamed(_) => {
+            let bindings = fields.iter().map(|f| &f.binding);
+            quote!(#path(#(#bindings),*))
+        }
+        Fields::Unit => path,
+    }
+}
+
+fn record_count(fields: &[Field], extra: i32) -> Literal {
+    Literal::i32_unsuffixed(fields.iter().filter(|f| !f.skip && !f.flatten).count() as i32 + extra)
+}
+
+/// Sets the bound fields on the table at the absolute `index`.
+fn write_fields(fields: &[Field]) -> TokenStream {
+    fields.iter().filter(|f| !f.skip).map(|f| {
+        let binding = &f.binding;
+        let name = &f.name;
+        if f.flatten {
+            quote_spanned! { f.ty.span() =>
+                ::macro_lua::ToLuaFields::to_lua_fields(#binding, state, index);
+            }
+        } else {
+            quote_spanned! { f.ty.span() =>
+                state.push(#name);
+                state.push(#binding);
+                state.raw_set(index);
+            }
+        }
+    }).collect()
+}
+
+/// Pushes the bound fields as a single value: a table for named fields, the
+/// inner value for a newtype and a sequence for other tuples.
+fn push_content(kind: &Fields, fields: &[Field]) -> TokenStream {
+    match kind {
+        Fields::Named(_) => {
+            let count = record_count(fields, 0);
+            let writes = write_fields(fields);
+            quote! {
+                state.create_table(0, #count);
+                {
+                    let index = state.abs_index(-1);
+                    #writes
+                }
+            }
+        }
+        Fields::Unnamed(_) if fields.len() == 1 => {
+            let binding = &fields[0].binding;
+            quote! { state.push(#binding); }
+        }
+        Fields::Unnamed(_) => {
+            let count = Literal::i32_unsuffixed(fields.len() as i32);
+            let items = fields.iter().enumerate().map(|(i, f)| {
+                let binding = &f.binding;
+                let i = Literal::i64_unsuffixed(i as i64 + 1);
+                quote! {
+                    state.push(#binding);
+                    state.raw_seti(-2, #i);
+                }
+            });
+            quote! {
+                state.create_table(#count, 0);
+                #(#items)*
+            }
+        }
+        Fields::Unit => quote! { state.create_table(0, 0); },
+    }
+}
@@ -0,0 +1,195 @@
+use crate::*;
+use crate::ffi::*;
+
+use std::alloc::{self, Layout};
+use std::panic::{self, AssertUnwindSafe};
+use std::{mem, ptr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc::size_t;
+
+/// The alignment of blocks given to Lua, that of `max_align_t` on common
+/// platforms, which is what `malloc` guarantees.
+const ALIGN: usize = 2 * mem::size_of::<usize>();
+
+/// An allocation refused because it would have gone over the memory limit,
+/// see `StateBuilder::on_alloc_failure`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocFailure {
+    /// The size of the block Lua asked for.
+    pub requested: usize,
+    /// The bytes in use when it asked.
+    pub used: usize,
+    pub limit: usize,
+}
+
+/// The memory used by a state created by `StateBuilder`, see
+/// `State::memory_usage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The bytes currently allocated.
+    pub used: usize,
+    /// The most bytes allocated at once since the state was created.
+    pub peak: usize,
+    /// The memory limit, if any.
+    pub limit: Option<usize>,
+}
+
+type FailureCallback = Box<dyn Fn(&AllocFailure) + Send + Sync>;
+
+/// The userdata of `limited_alloc`. It lives as long as the state and is
+/// freed by `Lua`'s drop after `lua_close`.
+pub(crate) struct Allocator {
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    on_failure: Option<FailureCallback>,
+}
+
+impl Allocator {
+    fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            used: self.used.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            limit: if self.limit == usize::MAX { None } else { Some(self.limit) },
+        }
+    }
+
+    fn refuse(&self, requested: usize, used: usize) -> *mut c_void {
+        if let Some(on_failure) = &self.on_failure {
+            let failure = AllocFailure { requested, used, limit: self.limit };
+            // unwinding into Lua is not an option here
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| on_failure(&failure)));
+        }
+        ptr::null_mut()
+    }
+}
+
+unsafe extern "C" fn limited_alloc(ud: *mut c_void, ptr: *mut c_void, old_size: size_t, new_size: size_t) -> *mut c_void {
+    let allocator = &*(ud as *const Allocator);
+    // when ptr is null, old_size is a type indicator, not a size
+    let old_size = if ptr.is_null() { 0 } else { old_size };
+    if new_size == 0 {
+        if !ptr.is_null() {
+            alloc::dealloc(ptr as *mut u8, Layout::from_size_align_unchecked(old_size, ALIGN));
+            allocator.used.fetch_sub(old_size, Ordering::Relaxed);
+        }
+        return ptr::null_mut();
+    }
+
+    // allocations run under the state lock, only readers race with this
+    let used = allocator.used.load(Ordering::Relaxed);
+    if new_size > old_size && new_size - old_size > allocator.limit.saturating_sub(used) {
+        return allocator.refuse(new_size, used);
+    }
+    let block = match Layout::from_size_align(new_size, ALIGN) {
+        Ok(layout) if ptr.is_null() => alloc::alloc(layout),
+        Ok(_) => alloc::realloc(ptr as *mut u8, Layout::from_size_align_unchecked(old_size, ALIGN), new_size),
+        Err(_) => ptr::null_mut(),
+    };
+    if block.is_null() {
+        // Lua assumes shrinking never fails
+        if new_size <= old_size { alloc::handle_alloc_error(Layout::from_size_align_unchecked(new_size, ALIGN)); }
+        return allocator.refuse(new_size, used);
+    }
+    let used = used + new_size - old_size;
+    allocator.used.store(used, Ordering::Relaxed);
+    allocator.peak.fetch_max(used, Ordering::Relaxed);
+    block as *mut c_void
+}
+
+/// The panic function of states created by `StateBuilder`: turns an error
+/// outside any protected call, e.g. a memory error in a host-side call, into
+/// a Rust panic instead of an abort. A panic of a native function that
+/// reached it carries on with its payload.
+unsafe extern "C-unwind" fn unwinding_panic(l: *mut lua_State) -> c_int {
+    let state = State::from_ptr(l);
+    state.resume_panic(-1);
+    // converting other values could raise again
+    let message = match state.type_of(-1) {
+        Type::String => state.to_str(-1).unwrap_or_default(),
+        ty => state.typename_of(ty).to_owned(),
+    };
+    state.pop(1);
+    panic!("unprotected error in call to Lua API ({})", message)
+}
+
+impl State {
+    /// The allocator of a state created by `StateBuilder`.
+    pub(crate) fn allocator(&self) -> *mut Allocator {
+        crate::ulua::shared(self.as_ptr()).allocator.load(Ordering::Relaxed)
+    }
+
+    /// The memory used by the state, if it was created by `StateBuilder`.
+    pub fn memory_usage(&self) -> Option<MemoryUsage> {
+        unsafe { self.allocator().as_ref() }.map(Allocator::usage)
+    }
+}
+
+/// Creates a `Lua` whose memory is allocated through Rust's global
+/// allocator and accounted for, with an optional hard limit. An allocation
+/// over the limit makes Lua run a full collection and retry; if that does
+/// not free enough, it raises a memory error (`ThreadStatus::MemoryError`,
+/// `LuaError::Memory` on the Rust side).
+///
+/// ```
+/// # use macro_lua::*;
+/// # fn main() -> Result<(), LuaError> {
+/// let lua = StateBuilder::new().memory_limit(16 << 20).build()?;
+/// lua.open_libs();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct StateBuilder {
+    limit: Option<usize>,
+    on_failure: Option<FailureCallback>,
+}
+
+impl StateBuilder {
+    pub fn new() -> StateBuilder { StateBuilder::default() }
+
+    /// Limits the memory the state can allocate to `bytes`.
+    pub fn memory_limit(mut self, bytes: usize) -> StateBuilder {
+        self.limit = Some(bytes);
+        self
+    }
+
+    /// Calls `f` when an allocation is refused. It runs inside the
+    /// allocator, so it must not use the state, and panics are ignored.
+    pub fn on_alloc_failure<F: Fn(&AllocFailure) + Send + Sync + 'static>(mut self, f: F) -> StateBuilder {
+        self.on_failure = Some(Box::new(f));
+        self
+    }
+
+    /// Creates the state. Fails with `LuaError::Memory` if the limit is too
+    /// low for even an empty state.
+    ///
+    /// An error outside any protected call, e.g. running out of memory in
+    /// `push_string` after a script used up the limit, panics instead of
+    /// aborting the process. The state can still be closed afterwards, but
+    /// the thread that raised it is left dead.
+    pub fn build(self) -> Result<Lua, LuaError> {
+        let allocator = Box::into_raw(Box::new(Allocator {
+            limit: self.limit.unwrap_or(usize::MAX),
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            on_failure: self.on_failure,
+        }));
+        unsafe {
+            let l = lua_newstate(Some(limited_alloc), allocator as *mut c_void);
+            if l.is_null() {
+                drop(Box::from_raw(allocator));
+                return Err(LuaError::Memory("cannot create state: not enough memory".to_owned()));
+            }
+            crate::ulua::shared(l).allocator.store(allocator, Ordering::Relaxed);
+            // lua_atpanic takes a C function; only the unwinding differs
+            lua_atpanic(l, Some(mem::transmute::<unsafe extern "C-unwind" fn(*mut lua_State) -> c_int, CFunction>(unwinding_panic)));
+            panic::catch_unwind(|| Lua::from_ptr(l)).map_err(|_| {
+                lua_close(l);
+                drop(Box::from_raw(allocator));
+                LuaError::Memory("cannot create state: not enough memory".to_owned())
+            })
+        }
+    }
+}
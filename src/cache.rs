@@ -0,0 +1,182 @@
+use crate::*;
+use crate::luaconf::LUA_VERSION_NUM;
+
+use sha2::{Digest, Sha256};
+
+use std::{fs, io};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the temporary files of `ChunkCache::insert`, so that threads
+/// storing the same chunk do not write to the same file.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A cache of precompiled chunks, shared by any number of states.
+///
+/// Chunks are compiled once, dumped as bytecode and stored under a hash of
+/// their chunk name and source text, so editing a script changes its key and
+/// the stale entry is no longer used. Each entry also records the
+/// length of the source and its SHA-256 digest, which must match before the
+/// bytecode is used, so a colliding or stale entry is compiled again. Entries
+/// live in memory and, with `ChunkCache::with_dir`, in a directory that
+/// outlives the process.
+///
+/// Chunks keep their debug information unless `ChunkCache::strip` is set,
+/// so errors raised by a chunk served from the cache have the same line
+/// numbers as when it is loaded from source.
+///
+/// Lua does not verify bytecode, and malformed bytecode can crash the
+/// process or run arbitrary code. The digest guards against mistakes, not
+/// against tampering: anyone who can write to the cache directory can make
+/// the states using it run code of their choice, so it must only be
+/// writable by users trusted as much as the process itself.
+#[derive(Default)]
+pub struct ChunkCache {
+    memory: Mutex<HashMap<u64, Arc<[u8]>>>,
+    dir: Option<PathBuf>,
+    strip: bool,
+}
+
+impl ChunkCache {
+    /// A cache kept in memory only.
+    pub fn new() -> ChunkCache {
+        ChunkCache::default()
+    }
+
+    /// A cache that also stores chunks as files in `dir`, which is created if
+    /// needed.
+    pub fn with_dir<P: Into<PathBuf>>(dir: P) -> io::Result<ChunkCache> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(ChunkCache { dir: Some(dir), ..ChunkCache::default() })
+    }
+
+    /// Stores chunks without debug information, which makes them smaller but
+    /// leaves errors raised by them without line numbers.
+    pub fn strip(mut self, strip: bool) -> ChunkCache {
+        self.strip = strip;
+        self
+    }
+
+    /// The cache directory, if any.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// [-0, +1, -] Loads `source` like `State::load_buffer`, serving the
+    /// bytecode from the cache when it was compiled before. Cache entries
+    /// that fail to load are compiled again, and a cache directory that
+    /// cannot be written is ignored.
+    pub fn load<'a, S: AsRef<[u8]>>(&self, state: &'a State, source: S, chunk_name: &str) -> Result<ValRef<'a>, LuaError> {
+        let source = source.as_ref();
+        let header = Header::new(source, chunk_name, self.strip);
+        let key = header.key();
+
+        if let Some(entry) = self.get(key) {
+            if let Some(chunk) = header.check(&entry) {
+                if let Ok(f) = load_binary(state, chunk, chunk_name) {
+                    return Ok(f);
+                }
+            }
+            self.memory.lock().unwrap().remove(&key);
+        }
+
+        let f = state.load_buffer(source, Some(chunk_name))?;
+        let mut entry = header.to_bytes();
+        entry.extend_from_slice(&state.dump(f.index, self.strip));
+        self.insert(key, entry.into());
+        Ok(f)
+    }
+
+    /// Removes all entries from memory and from the cache directory.
+    pub fn clear(&self) -> io::Result<()> {
+        self.memory.lock().unwrap().clear();
+        if let Some(dir) = &self.dir {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension() == Some("luac".as_ref()) {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, key: u64) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{:016x}.luac", key)))
+    }
+
+    fn get(&self, key: u64) -> Option<Arc<[u8]>> {
+        if let Some(chunk) = self.memory.lock().unwrap().get(&key) {
+            return Some(chunk.clone());
+        }
+        let chunk: Arc<[u8]> = fs::read(self.path(key)?).ok()?.into();
+        self.memory.lock().unwrap().insert(key, chunk.clone());
+        Some(chunk)
+    }
+
+    fn insert(&self, key: u64, chunk: Arc<[u8]>) {
+        if let Some(path) = self.path(key) {
+            // write to a temporary file first, so that other processes never
+            // read a partial chunk
+            let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let tmp = path.with_extension(format!("{}.{}.tmp", std::process::id(), n));
+            if fs::write(&tmp, &chunk).and_then(|_| fs::rename(&tmp, &path)).is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+        self.memory.lock().unwrap().insert(key, chunk);
+    }
+}
+
+fn load_binary<'a>(state: &'a State, chunk: &[u8], chunk_name: &str) -> Result<ValRef<'a>, LuaError> {
+    match state.load_bufferx(chunk, chunk_name, "b") {
+        ThreadStatus::Ok => Ok(state.val(-1)),
+        status => Err(state.pop_error(status)),
+    }
+}
+
+/// The start of a cache entry, followed by the bytecode: the length of the
+/// source and a SHA-256 digest of the Lua version, whether the chunk is
+/// stripped, the chunk name and the source. Unlike `DefaultHasher` it is
+/// stable across builds, as entries are kept on disk.
+struct Header {
+    source_len: u64,
+    digest: [u8; 32],
+}
+
+impl Header {
+    const LEN: usize = 8 + 32;
+
+    fn new(source: &[u8], chunk_name: &str, strip: bool) -> Header {
+        let mut hasher = Sha256::new();
+        hasher.update(LUA_VERSION_NUM.to_le_bytes());
+        hasher.update([strip as u8]);
+        hasher.update(chunk_name.as_bytes());
+        hasher.update([0]);
+        hasher.update(source);
+        Header { source_len: source.len() as u64, digest: hasher.finalize().into() }
+    }
+
+    /// The key of the entry, from the start of the digest.
+    fn key(&self) -> u64 {
+        let mut key = [0; 8];
+        key.copy_from_slice(&self.digest[..8]);
+        u64::from_le_bytes(key)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Header::LEN);
+        bytes.extend_from_slice(&self.source_len.to_le_bytes());
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    /// The bytecode of `entry`, if it was stored for the same source.
+    fn check<'a>(&self, entry: &'a [u8]) -> Option<&'a [u8]> {
+        if entry.len() < Header::LEN || entry[..Header::LEN] != self.to_bytes()[..] { return None; }
+        Some(&entry[Header::LEN..])
+    }
+}
@@ -4,11 +4,15 @@ use crate::{
     lua_Number as Number,
     CFunction, Index,
 };
-use crate::{State, ValRef, TopRef};
+use crate::{State, ValRef, TopRef, Value, LuaString, OwnedRef, OwnedTable, OwnedFunction};
 use crate::ffi::{self, lua_State};
 
 use libc::c_int;
-use std::mem;
+use std::{mem, any, fmt};
+use std::fmt::Write;
+use std::hash::Hash;
+use std::convert::TryFrom;
+use std::collections::{HashMap, BTreeMap, HashSet};
 
 /// Trait for types that can be pushed onto the stack of a Lua state.
 ///
@@ -37,19 +41,73 @@ impl<'a> ToLua for &'a [u8] {
     }
 }
 
-impl ToLua for ValRef {
+macro_rules! impl_owned {
+    ($($t:ty)*) => {
+        $(
+        impl ToLua for &$t {
+            fn to_lua(self, state: &State) {
+                self.push_to(state);
+            }
+        }
+
+        impl ToLua for $t {
+            fn to_lua(self, state: &State) {
+                self.push_to(state);
+            }
+        }
+        )*
+    }
+}
+
+impl_owned!(OwnedRef OwnedTable OwnedFunction);
+
+impl ToLua for &LuaString {
+    fn to_lua(self, state: &State) {
+        self.inner.push_to(state);
+    }
+}
+
+impl ToLua for LuaString {
+    fn to_lua(self, state: &State) {
+        self.inner.push_to(state);
+    }
+}
+
+impl ToLua for &Value {
+    fn to_lua(self, state: &State) {
+        match self {
+            Value::None | Value::Nil => state.push_nil(),
+            Value::Int(i) => state.push_integer(*i),
+            Value::Num(n) => state.push_number(*n),
+            Value::Str(s) => state.push_bytes(s),
+            Value::Bool(b) => state.push_bool(*b),
+            Value::LightUserdata(p) => state.push_light_userdata(*p),
+            Value::Table(r) => r.push_to(state),
+            Value::Function(r) => r.push_to(state),
+            Value::Userdata(r) | Value::Thread(r) => r.push_to(state),
+        }
+    }
+}
+
+impl ToLua for Value {
+    fn to_lua(self, state: &State) {
+        state.push(&self);
+    }
+}
+
+impl ToLua for ValRef<'_> {
     fn to_lua(self, state: &State) {
         state.push_value(self.index);
     }
 }
 
-impl ToLua for TopRef {
+impl ToLua for TopRef<'_> {
     fn to_lua(self, state: &State) {
         let top = state.get_top();
         if top > self.index {
             state.push_value(self.index);
         } else if top < self.index {
-            panic!("");
+            panic!("TopRef at {} is above the top of the stack ({})", self.index, top);
         }
     }
 }
@@ -101,6 +159,51 @@ impl<T: ToLua> ToLua for Vec<T> {
     }
 }
 
+impl<T: ToLua> ToLua for Box<[T]> {
+    fn to_lua(self, state: &State) {
+        state.push(self.into_vec())
+    }
+}
+
+impl<T: ToLua, const N: usize> ToLua for [T; N] {
+    fn to_lua(self, state: &State) {
+        let r = state.table(N as c_int, 0);
+        let mut i = 1;
+        for e in IntoIterator::into_iter(self) { r.seti(i, e); i += 1; }
+    }
+}
+
+macro_rules! impl_map_to_lua {
+    ($($m:ident<$k:ident: $($kb:ident)+>)*) => {
+        $(
+        impl<$k: ToLua $(+ $kb)+, V: ToLua> ToLua for $m<$k, V> {
+            fn to_lua(self, state: &State) {
+                state.create_table(0, self.len() as c_int);
+                for (k, v) in self {
+                    state.push(k);
+                    state.push(v);
+                    state.raw_set(-3);
+                }
+            }
+        }
+        )*
+    }
+}
+
+impl_map_to_lua!(HashMap<K: Eq Hash> BTreeMap<K: Ord>);
+
+/// Sets are stored as `{[k] = true}`.
+impl<T: ToLua + Eq + Hash> ToLua for HashSet<T> {
+    fn to_lua(self, state: &State) {
+        state.create_table(0, self.len() as c_int);
+        for k in self {
+            state.push(k);
+            state.push_bool(true);
+            state.raw_set(-3);
+        }
+    }
+}
+
 // impl<I: Iterator<Item=T>> ToLua for I where T: ToLua {
 //     fn to_lua(self, state: &State) {
 //         let r = state.table(0, 0);
@@ -117,27 +220,360 @@ pub trait FromLua: Sized {
     /// Converts the value on top of the stack of a Lua state to a value of type
     /// `Option<Self>`.
     fn from_lua(state: &State, index: Index) -> Option<Self>;
+
+    /// Like `from_lua`, but reports what went wrong. Container types override
+    /// this to record the key path of the failing element.
+    fn try_from_lua(state: &State, index: Index) -> Result<Self, ConversionError> {
+        Self::from_lua(state, index).ok_or_else(|| {
+            ConversionError::new(state, index, any::type_name::<Self>())
+        })
+    }
+}
+
+/// One step in the key path of a `ConversionError`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathSegment {
+    Index(Integer),
+    Key(String),
+}
+
+/// Error returned by `FromLua::try_from_lua`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConversionError {
+    /// Keys leading from the converted value to the failing element,
+    /// outermost first. Empty if the value itself failed to convert.
+    pub path: Vec<PathSegment>,
+    pub expected: String,
+    /// The Lua type name of the value that was found.
+    pub found: &'static str,
+}
+
+impl ConversionError {
+    pub fn new(state: &State, index: Index, expected: impl Into<String>) -> ConversionError {
+        ConversionError { path: Vec::new(), expected: expected.into(), found: state.typename_at(index) }
+    }
+
+    /// Prepends a key to the path, used when the error passes through a container.
+    pub fn within(mut self, segment: PathSegment) -> ConversionError {
+        self.path.insert(0, segment);
+        self
+    }
+
+    /// The path formatted like a Lua expression, e.g. `servers[2].port`.
+    pub fn path_string(&self) -> String { format_path(&self.path) }
+}
+
+pub(crate) fn format_path(path: &[PathSegment]) -> String {
+    let mut result = String::new();
+    for segment in path.iter() {
+        match segment {
+            PathSegment::Index(i) => { let _ = write!(result, "[{}]", i); }
+            PathSegment::Key(k) if is_identifier(k) => {
+                if !result.is_empty() { result.push('.'); }
+                result.push_str(k);
+            }
+            PathSegment::Key(k) => { let _ = write!(result, "[{:?}]", k); }
+        }
+    }
+    result
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => chars.all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        _ => false,
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{} expected, got {}", self.expected, self.found)
+        } else {
+            write!(f, "{} expected at '{}', got {}", self.expected, self.path_string(), self.found)
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// How `FromLua` decides whether a table is a sequence. Set per state with
+/// `State::set_sequence_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SequenceMode {
+    /// A table is a sequence if `#t > 0` (or it is empty); `t[1]..t[#t]` is
+    /// read and any other keys are ignored. This is the default.
+    Length,
+    /// A table is a sequence only if its keys are exactly `1..=n`. Holes or
+    /// extra keys make the conversion to a sequence type fail.
+    Strict,
+}
+
+static SEQUENCE_MODE_KEY: u8 = 0;
+
+impl State {
+    /// Sets the `SequenceMode` used by conversions on this state.
+    pub fn set_sequence_mode(&self, mode: SequenceMode) {
+        self.push_integer(mode as Integer);
+        self.raw_setp(ffi::LUA_REGISTRYINDEX, &SEQUENCE_MODE_KEY);
+    }
+
+    pub fn sequence_mode(&self) -> SequenceMode {
+        self.raw_getp(ffi::LUA_REGISTRYINDEX, &SEQUENCE_MODE_KEY);
+        let mode = self.to_integer(-1);
+        self.pop(1);
+        if mode == SequenceMode::Strict as Integer { SequenceMode::Strict } else { SequenceMode::Length }
+    }
+
+    /// Returns the length of the table at `index` if it is a sequence
+    /// according to the current `SequenceMode`, or `None` if the stack has
+    /// no room left to inspect it.
+    pub fn sequence_len(&self, index: Index) -> Option<usize> {
+        if !self.is_table(index) || !self.check_stack(2) { return None; }
+        let index = self.abs_index(index);
+        let len = self.raw_len(index);
+        match self.sequence_mode() {
+            SequenceMode::Length => if len > 0 || self.table_is_empty(index) { Some(len) } else { None },
+            SequenceMode::Strict => {
+                let mut count = 0;
+                self.push_nil();
+                while self.next(index) {
+                    let in_range = self.is_integer(-2) && {
+                        let k = self.to_integer(-2);
+                        k >= 1 && k as usize <= len
+                    };
+                    self.pop(1);
+                    if !in_range { self.pop(1); return None; }
+                    count += 1;
+                }
+                if count == len { Some(len) } else { None }
+            }
+        }
+    }
+
+    pub(crate) fn table_is_empty(&self, index: Index) -> bool {
+        self.push_nil();
+        if self.next(index) { self.pop(2); false } else { true }
+    }
+
+    /// Converts the value at `index`, reporting the key path on failure.
+    #[inline(always)]
+    pub fn try_arg<T: FromLua>(&self, index: Index) -> Result<T, ConversionError> {
+        T::try_from_lua(self, index)
+    }
+
+    /// Converts `t[key]` of the table at `index` without invoking metamethods.
+    /// Errors are reported with `key` prepended to their path.
+    pub fn try_field<T: FromLua>(&self, index: Index, key: &str) -> Result<T, ConversionError> {
+        self.try_field_with(index, key, |s, i| T::try_from_lua(s, i))
+    }
+
+    /// Like `try_field`, but a missing (nil) field yields `default()`.
+    pub fn try_field_or<T: FromLua>(&self, index: Index, key: &str, default: impl FnOnce() -> T)
+        -> Result<T, ConversionError>
+    {
+        self.try_field_with(index, key, |s, i| if s.is_nil(i) { Ok(default()) } else { T::try_from_lua(s, i) })
+    }
+
+    /// Pushes `t[key]` of the table at `index`, converts it with `f` and pops it.
+    pub fn try_field_with<T>(&self, index: Index, key: &str, f: impl FnOnce(&State, Index) -> Result<T, ConversionError>)
+        -> Result<T, ConversionError>
+    {
+        let index = self.abs_index(index);
+        check_nested_stack(self, index)?;
+        self.push(key);
+        self.raw_get(index);
+        let result = f(self, self.abs_index(-1));
+        self.pop(1);
+        result.map_err(|e| e.within(PathSegment::Key(key.into())))
+    }
+
+    /// Converts `t[i]` of the table at `index`, like `try_field`.
+    pub fn try_element<T: FromLua>(&self, index: Index, i: Integer) -> Result<T, ConversionError> {
+        let index = self.abs_index(index);
+        check_nested_stack(self, index)?;
+        self.raw_geti(index, i);
+        let result = T::try_from_lua(self, -1);
+        self.pop(1);
+        result.map_err(|e| e.within(PathSegment::Index(i)))
+    }
+}
+
+/// Types that write their fields into an existing table, as required by
+/// `#[lua(flatten)]`. Implemented by `#[derive(ToLua)]` for structs with
+/// named fields.
+pub trait ToLuaFields {
+    /// Sets the fields of `self` on the table at `index`.
+    fn to_lua_fields(self, state: &State, index: Index);
+}
+
+/// The reading side of `ToLuaFields`, implemented by `#[derive(FromLua)]`.
+/// Keys that do not belong to the type are ignored.
+pub trait FromLuaFields: Sized {
+    /// Reads the fields of `Self` from the table at `index`.
+    fn from_lua_fields(state: &State, index: Index) -> Result<Self, ConversionError>;
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(state: &State, index: Index) -> Option<Vec<T>> {
+        Self::try_from_lua(state, index).ok()
+    }
+
+    fn try_from_lua(state: &State, index: Index) -> Result<Vec<T>, ConversionError> {
+        let len = state.sequence_len(index)
+            .ok_or_else(|| ConversionError::new(state, index, "sequence"))?;
+        let index = state.abs_index(index);
+        check_nested_stack(state, index)?;
+        let mut result = Vec::with_capacity(len);
+        for i in 1..=len as Integer {
+            state.raw_geti(index, i);
+            let item = T::try_from_lua(state, -1);
+            state.pop(1);
+            result.push(item.map_err(|e| e.within(PathSegment::Index(i)))?);
+        }
+        Ok(result)
+    }
+}
+
+impl<T: FromLua> FromLua for Box<[T]> {
+    fn from_lua(state: &State, index: Index) -> Option<Box<[T]>> {
+        Self::try_from_lua(state, index).ok()
+    }
+
+    fn try_from_lua(state: &State, index: Index) -> Result<Box<[T]>, ConversionError> {
+        Vec::<T>::try_from_lua(state, index).map(Vec::into_boxed_slice)
+    }
+}
+
+impl<T: FromLua, const N: usize> FromLua for [T; N] {
+    fn from_lua(state: &State, index: Index) -> Option<[T; N]> {
+        Self::try_from_lua(state, index).ok()
+    }
+
+    fn try_from_lua(state: &State, index: Index) -> Result<[T; N], ConversionError> {
+        let expected = || ConversionError::new(state, index, format!("sequence of length {}", N));
+        if state.sequence_len(index) != Some(N) { return Err(expected()); }
+        let items = Vec::<T>::try_from_lua(state, index)?;
+        <[T; N]>::try_from(items).map_err(|_| expected())
+    }
+}
+
+/// Calls `f` with each key/value pair of the table at `index`. The key is
+/// converted from a copy, so conversions like `lua_tolstring` cannot confuse
+/// `lua_next`.
+fn for_each_pair<F>(state: &State, index: Index, mut f: F) -> Result<(), ConversionError>
+where F: FnMut(&State) -> Result<(), ConversionError>
+{
+    if !state.is_table(index) {
+        return Err(ConversionError::new(state, index, "table"));
+    }
+    let index = state.abs_index(index);
+    check_nested_stack(state, index)?;
+    state.push_nil();
+    while state.next(index) {
+        state.push_value(-2);
+        if let Err(e) = f(state) {
+            let segment = key_segment(state, -3);
+            state.pop(3);
+            return Err(e.within(segment));
+        }
+        state.pop(2);
+    }
+    Ok(())
+}
+
+pub(crate) fn key_segment(state: &State, index: Index) -> PathSegment {
+    if state.is_integer(index) {
+        PathSegment::Index(state.to_integer(index))
+    } else {
+        PathSegment::Key(format!("{}", state.value(index)))
+    }
+}
+
+fn check_nested_stack(state: &State, index: Index) -> Result<(), ConversionError> {
+    if state.check_stack(4) { Ok(()) } else {
+        Err(ConversionError::new(state, index, "table nested less deeply"))
+    }
+}
+
+macro_rules! impl_map_from_lua {
+    ($($m:ident<$k:ident: $($kb:ident)+>)*) => {
+        $(
+        impl<$k: FromLua $(+ $kb)+, V: FromLua> FromLua for $m<$k, V> {
+            fn from_lua(state: &State, index: Index) -> Option<Self> {
+                Self::try_from_lua(state, index).ok()
+            }
+
+            /// Pairs are read with `lua_next`; the stack holds the value at
+            /// -2 and a copy of the key at -1 while converting.
+            fn try_from_lua(state: &State, index: Index) -> Result<Self, ConversionError> {
+                let mut result = $m::new();
+                for_each_pair(state, index, |s| {
+                    let k = $k::try_from_lua(s, -1)?;
+                    let v = V::try_from_lua(s, -2)?;
+                    result.insert(k, v);
+                    Ok(())
+                })?;
+                Ok(result)
+            }
+        }
+        )*
+    }
+}
+
+impl_map_from_lua!(HashMap<K: Eq Hash> BTreeMap<K: Ord>);
+
+/// Reads the keys whose values are truthy, matching the `{[k] = true}` layout
+/// written by `ToLua`.
+impl<T: FromLua + Eq + Hash> FromLua for HashSet<T> {
+    fn from_lua(state: &State, index: Index) -> Option<HashSet<T>> {
+        Self::try_from_lua(state, index).ok()
+    }
+
+    fn try_from_lua(state: &State, index: Index) -> Result<HashSet<T>, ConversionError> {
+        let mut result = HashSet::new();
+        for_each_pair(state, index, |s| {
+            if s.to_bool(-2) { result.insert(T::try_from_lua(s, -1)?); }
+            Ok(())
+        })?;
+        Ok(result)
+    }
 }
 
 impl FromLua for String {
     fn from_lua(state: &State, index: Index) -> Option<String> {
-        state.to_str(index).map(ToOwned::to_owned)
+        state.to_str(index)
     }
 }
 
-impl FromLua for &str {
-    fn from_lua(state: &State, index: Index) -> Option<&'static str> {
-        state.to_str(index)
+impl FromLua for Value {
+    fn from_lua(state: &State, index: Index) -> Option<Value> {
+        Some(state.value(index))
     }
 }
 
-impl<'a> FromLua for &'a [u8] {
-    fn from_lua(state: &State, index: Index) -> Option<&'a [u8]> {
-        let mut len = 0;
-        let ptr = state.tolstring(index, &mut len);
-        if ptr.is_null() { None } else {
-            Some(unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) })
-        }
+impl FromLua for OwnedRef {
+    fn from_lua(state: &State, index: Index) -> Option<OwnedRef> {
+        if state.is_none(index) { None } else { Some(OwnedRef::new(state, index)) }
+    }
+}
+
+impl FromLua for OwnedTable {
+    fn from_lua(state: &State, index: Index) -> Option<OwnedTable> {
+        OwnedTable::new(state, index)
+    }
+}
+
+impl FromLua for OwnedFunction {
+    fn from_lua(state: &State, index: Index) -> Option<OwnedFunction> {
+        OwnedFunction::new(state, index)
+    }
+}
+
+impl FromLua for LuaString {
+    fn from_lua(state: &State, index: Index) -> Option<LuaString> {
+        LuaString::new(state, index)
     }
 }
 
@@ -231,11 +667,11 @@ impl<T: FromLua> FromLuaMulti for T {
 }
 
 pub trait PushClosure<FN, ARGS, RET> {
-    fn push_closure(&self, f: FN) -> TopRef;
+    fn push_closure(&self, f: FN) -> TopRef<'_>;
 }
 
 pub trait PushMethod<T, FN, RET> {
-    fn push_method(&self, mehtod: FN) -> TopRef;
+    fn push_method(&self, mehtod: FN) -> TopRef<'_>;
 }
 
 use std::marker::PhantomData;
@@ -243,13 +679,12 @@ pub(crate) struct Method<T> {
     t1: PhantomData<T>,
 }
 
-impl<T: Sized> Method<T> where {
+impl<T: 'static> Method<T> where {
     pub unsafe extern "C" fn lua_fn(l: *mut lua_State) -> c_int {
         let state = State::from_ptr(l);
-        let p: &T = mem::transmute(state.to_userdata(1));
         let fp = state.to_pointer(ffi::lua_upvalueindex(1));
-        let fp: fn(&T, State) -> c_int = mem::transmute(fp);
-        fp(p, state)
+        let fp: fn(&mut T, &State) -> c_int = mem::transmute(fp);
+        crate::catch_panic(l, || state.with_self::<T>(|this| fp(this, &state)))
     }
 }
 
@@ -285,8 +720,8 @@ macro_rules! impl_tuple {
 
         impl<FN, RET $(,$x: FromLua)*> PushClosure<FN, ($($x,)*), RET> for State
         where FN: Fn($($x,)*) -> RET + 'static, RET: ToLuaMulti {
-            fn push_closure(&self, closure: FN) -> TopRef {
-                self.rust_closure(move |state| {
+            fn push_closure(&self, closure: FN) -> TopRef<'_> {
+                self.named_closure(std::any::type_name::<FN>(), move |state| {
                     std::ops::Fn::call(
                         &closure,
                         state.args::<($($x,)*)>(1)
@@ -329,12 +764,3 @@ impl_tuple!((A,0) (B,1) (C,2) (D,3) (E,4) (F,5) (G,6));
 impl_tuple!((A,0) (B,1) (C,2) (D,3) (E,4) (F,5) (G,6) (H,7));
 impl_tuple!((A,0) (B,1) (C,2) (D,3) (E,4) (F,5) (G,6) (H,7) (I,8));
 
-pub trait UserData {
-    fn __index(&self, state: State) -> c_int {
-        0
-    }
-
-    fn __newindex(&self, state: State) -> c_int {
-        0
-    }
-}
\ No newline at end of file
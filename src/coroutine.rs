@@ -0,0 +1,149 @@
+use crate::*;
+
+use std::any::type_name;
+use std::marker::PhantomData;
+
+/// How a call to `Coroutine::resume` ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CoroutineState<R> {
+    /// The coroutine yielded these values and can be resumed again.
+    Yielded(R),
+    /// The function returned these values and the coroutine is finished.
+    Returned(R),
+}
+
+/// A Lua coroutine driven from Rust. The thread is anchored in the registry,
+/// so the handle can be kept across calls, like an `OwnedRef`.
+pub struct Coroutine {
+    anchor: OwnedRef,
+    thread: State,
+}
+
+impl Coroutine {
+    /// [-0, +0, m] Creates a coroutine running the function at `index`, or
+    /// returns `None` if the value is not a function.
+    pub fn new(state: &State, index: Index) -> Option<Coroutine> {
+        if state.type_of(index) != Type::Function { return None; }
+        let index = state.abs_index(index);
+        let thread = unsafe { State::from_ptr(state.new_thread().as_ptr()) };
+        let anchor = OwnedRef::from_top(state);
+        state.push_value(index);
+        state.xmove(&thread, 1);
+        Some(Coroutine { anchor, thread })
+    }
+
+    /// [-0, +0, m] Creates a coroutine running `f`, which must belong to the
+    /// same Lua state as `state`.
+    pub fn from_function(state: &State, f: &OwnedFunction) -> Coroutine {
+        f.push_to(state);
+        let co = Coroutine::new(state, -1).unwrap();
+        state.pop(1);
+        co
+    }
+
+    /// The thread the coroutine runs on.
+    #[inline]
+    pub(crate) fn thread(&self) -> &State { self.anchor.check_alive(); &self.thread }
+
+    /// Returns `true` once the function has returned or raised an error.
+    pub fn is_finished(&self) -> bool {
+        match self.thread().status() {
+            ThreadStatus::Yield => false,
+            ThreadStatus::Ok => self.thread.get_top() == 0,
+            _ => true,
+        }
+    }
+
+    /// Starts or continues the coroutine. `args` are passed to the function
+    /// on the first call, and returned by `coroutine.yield` afterwards. A
+    /// panic in a native function called by the coroutine resumes unwinding
+    /// here.
+    pub fn resume<A: ToLuaMulti, R: FromLuaMulti>(&mut self, args: A) -> Result<CoroutineState<R>, LuaError> {
+        let yielded = self.resume_inner(args)?;
+        let result = R::from_lua(&self.thread, 1).ok_or_else(|| {
+            ConversionError::new(&self.thread, 1, type_name::<R>())
+        });
+        self.thread.set_top(0);
+        Ok(if yielded { CoroutineState::Yielded(result?) } else { CoroutineState::Returned(result?) })
+    }
+
+    /// Turns the coroutine into an iterator over the values it yields, which
+    /// ends when the function returns. Its return values are discarded.
+    #[allow(clippy::should_implement_trait)]
+    pub fn into_iter<T: FromLuaMulti>(self) -> CoroutineIter<T> {
+        CoroutineIter { co: self, marker: PhantomData }
+    }
+
+    /// Resumes the thread, leaving the yielded or returned values on its
+    /// stack. Returns `true` if it yielded.
+    pub(crate) fn resume_inner<A: ToLuaMulti>(&mut self, args: A) -> Result<bool, LuaError> {
+        if self.is_finished() {
+            return Err(LuaError::Runtime {
+                message: "cannot resume dead coroutine".to_owned(),
+                traceback: None,
+                value: None,
+                cause: None,
+            });
+        }
+        let thread = self.thread();
+        thread.check_stack(ffi::LUA_MINSTACK);
+        let top = thread.get_top();
+        args.to_lua(thread);
+        self.resume_pushed(thread.get_top() - top)
+    }
+
+    /// Same as `resume_inner`, with the `nargs` arguments already on the
+    /// stack of the thread.
+    pub(crate) fn resume_pushed(&mut self, nargs: c_int) -> Result<bool, LuaError> {
+        let thread = self.thread();
+        match thread.resume(None, nargs) {
+            ThreadStatus::Yield => Ok(true),
+            ThreadStatus::Ok => Ok(false),
+            status => {
+                // the thread is left as it was when the error was raised
+                thread.check_stack(ffi::LUA_MINSTACK);
+                if let Some(payload) = thread.take_panic(-1) {
+                    thread.pop(1);
+                    std::panic::resume_unwind(payload);
+                }
+                thread.record_traceback(0);
+                Err(thread.pop_error(status))
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Coroutine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Coroutine({:?})", self.anchor.reference())
+    }
+}
+
+/// An iterator over the values yielded by a coroutine, see
+/// `Coroutine::into_iter`. An error ends the iteration after it is returned.
+pub struct CoroutineIter<T> {
+    co: Coroutine,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> CoroutineIter<T> {
+    /// The coroutine being iterated.
+    pub fn coroutine(&self) -> &Coroutine { &self.co }
+}
+
+impl<T: FromLuaMulti> Iterator for CoroutineIter<T> {
+    type Item = Result<T, LuaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.co.is_finished() { return None; }
+        let result = match self.co.resume_inner(()) {
+            Ok(true) => T::from_lua(&self.co.thread, 1).ok_or_else(|| {
+                ConversionError::new(&self.co.thread, 1, type_name::<T>()).into()
+            }),
+            Ok(false) => { self.co.thread.set_top(0); return None; }
+            Err(e) => Err(e),
+        };
+        self.co.thread.set_top(0);
+        Some(result)
+    }
+}
@@ -0,0 +1,266 @@
+use crate::*;
+
+use std::fmt;
+use std::any::Any;
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// A Rust panic caught at the boundary of a native function and raised as a
+/// Lua error. In Lua it converts to `rust panic: <message>` with `tostring`;
+/// on the Rust side the payload can be taken back, see `State::resume_panic`.
+pub(crate) struct CaughtPanic {
+    message: String,
+    payload: Option<Box<dyn Any + Send>>,
+}
+
+metatable! {
+    const PANIC_METATABLE = CaughtPanic(s: State, this: Self);
+
+    "__tostring" () push { format!("rust panic: {}", this.message) }
+}
+
+/// The message of a panic payload, as printed by the default panic hook.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<dyn Any>".to_owned()
+    }
+}
+
+/// Runs the body of a native function. A panic is caught before it reaches
+/// the C frames of the interpreter and raised as a Lua error instead.
+/// All trampolines, including the ones generated by `cfn!`, go through this.
+#[inline(always)]
+pub unsafe fn catch_panic<F: FnOnce() -> c_int>(l: *mut ffi::lua_State, f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(n) => n,
+        Err(payload) => {
+            let state = State::from_ptr(l);
+            let message = panic_message(&*payload);
+            state.push_userdata(CaughtPanic { message, payload: Some(payload) }, Some(PANIC_METATABLE));
+            state.error()
+        }
+    }
+}
+
+impl State {
+    /// Returns `true` if the value at `index` is a panic raised by a native
+    /// function.
+    pub fn is_panic(&self, index: Index) -> bool {
+        self.userdata_ref::<CaughtPanic>(index).is_ok()
+    }
+
+    /// Takes the payload of the panic at `index`. Returns `None` if the value
+    /// is not a panic or the payload was already taken.
+    pub fn take_panic(&self, index: Index) -> Option<Box<dyn Any + Send>> {
+        self.userdata_mut::<CaughtPanic>(index).ok()?.payload.take()
+    }
+
+    /// If the value at `index` is a panic raised by a native function, e.g. the
+    /// error value left by `pcall`, continues unwinding with its payload.
+    pub fn resume_panic(&self, index: Index) {
+        if let Some(payload) = self.take_panic(index) {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+/// An error raised by Lua code, by the interpreter, or by a Rust callback
+/// through `State::raise`.
+#[derive(Clone, Debug)]
+pub enum LuaError {
+    /// A runtime error (`LUA_ERRRUN`).
+    Runtime {
+        message: String,
+        /// The stack traceback at the point of the error, if the call was
+        /// made with a message handler, see `State::protected_call`.
+        traceback: Option<Traceback>,
+        /// A copy of the error value, if it was not a string or a number.
+        value: Option<ErrorValue>,
+        /// The Rust error passed to `State::raise`, if that raised it.
+        cause: Option<Arc<dyn Error + Send + Sync>>,
+    },
+    /// A syntax error while loading a chunk (`LUA_ERRSYNTAX`).
+    Syntax {
+        message: String,
+        /// The chunk name as Lua displays it, e.g. `[string "x = "]`.
+        chunk: Option<String>,
+        line: Option<u32>,
+    },
+    /// A memory allocation error (`LUA_ERRMEM`).
+    Memory(String),
+    /// An error while running a `__gc` metamethod (`LUA_ERRGCMM`).
+    Gc(String),
+    /// An error while running the message handler (`LUA_ERRERR`).
+    MessageHandler(String),
+    /// A file could not be opened or read (`LUA_ERRFILE`).
+    File(String),
+    /// The script ran past its instruction limit or deadline, see
+    /// `State::set_instruction_limit`.
+    Timeout(String),
+    /// The script was stopped by an interrupt, see `State::set_interrupt`.
+    Interrupted(String),
+    /// The call succeeded but its results could not be converted.
+    Conversion(ConversionError),
+    /// A status code this crate does not know.
+    Unknown { code: c_int, message: String },
+}
+
+/// An error value that is not a string or a number, as kept by
+/// `LuaError::Runtime`. Unlike `Value` it holds no reference to the state,
+/// so errors can be sent to other threads.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorValue {
+    Nil,
+    Bool(bool),
+    /// Any other value, described without calling its metamethods, e.g.
+    /// `table: 0x55f1c3a0` or `Counter: 0x55f1c3a0` for a userdata with a
+    /// `__name`.
+    Other(String),
+}
+
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorValue::Nil => f.write_str("nil"),
+            ErrorValue::Bool(b) => write!(f, "{}", b),
+            ErrorValue::Other(description) => f.write_str(description),
+        }
+    }
+}
+
+impl LuaError {
+    /// The error message, without the traceback.
+    pub fn message(&self) -> String {
+        match self {
+            LuaError::Runtime { message, .. } | LuaError::Syntax { message, .. } |
+            LuaError::Memory(message) | LuaError::Gc(message) |
+            LuaError::MessageHandler(message) | LuaError::File(message) |
+            LuaError::Timeout(message) | LuaError::Interrupted(message) |
+            LuaError::Unknown { message, .. } => message.clone(),
+            LuaError::Conversion(e) => e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaError::Runtime { message, traceback: Some(traceback), .. } => write!(f, "{}\n{}", message, traceback),
+            LuaError::Unknown { code, message } => write!(f, "{} (status {})", message, code),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl Error for LuaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LuaError::Runtime { cause: Some(cause), .. } => Some(&**cause),
+            LuaError::Conversion(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConversionError> for LuaError {
+    fn from(e: ConversionError) -> LuaError { LuaError::Conversion(e) }
+}
+
+/// A Rust error raised into Lua by `State::raise`.
+struct RustError(Arc<dyn Error + Send + Sync>);
+
+metatable! {
+    const RUST_ERROR_METATABLE = RustError(s: State, this: Self);
+
+    "__tostring" () push { this.0.to_string() }
+}
+
+/// Splits `chunk:line: message` as produced by `luaO_chunkid`.
+fn split_location(message: &str) -> Option<(&str, u32)> {
+    let start = if message.starts_with("[string \"") { message.find("\"]")? + 2 } else { 0 };
+    let colon = start + message[start..].find(':')?;
+    let rest = &message[colon + 1..];
+    let digits = rest.find(':')?;
+    Some((&message[..colon], rest[..digits].parse().ok()?))
+}
+
+impl State {
+    /// Raises `error` as a Lua error from a native function. The error value
+    /// converts to the error's message with `tostring`, and a `LuaError`
+    /// built from it keeps the original error as its `source`.
+    pub fn raise(&self, error: impl Into<Box<dyn Error + Send + Sync>>) -> ! {
+        self.push_userdata(RustError(Arc::from(error.into())), Some(RUST_ERROR_METATABLE));
+        self.error()
+    }
+
+    /// [-1, +0, -] Pops the error value left by a failed call or load with
+    /// the given status and converts it to a `LuaError`.
+    pub fn pop_error(&self, status: ThreadStatus) -> LuaError {
+        let traceback = self.take_traceback();
+        if let Some(abort) = self.userdata_ref::<crate::limits::Abort>(-1).ok().map(|abort| *abort) {
+            self.pop(1);
+            return abort.into_error();
+        }
+
+        let mut value = None;
+        let mut cause = None;
+        let message = if self.is_string(-1) {
+            String::from_utf8_lossy(&self.to_bytes(-1).unwrap_or_default()).into_owned()
+        } else if let Ok(e) = self.userdata_ref::<RustError>(-1) {
+            cause = Some(e.0.clone());
+            e.0.to_string()
+        } else {
+            value = Some(match self.type_of(-1) {
+                Type::Nil => ErrorValue::Nil,
+                Type::Boolean => ErrorValue::Bool(self.to_bool(-1)),
+                _ => ErrorValue::Other(format!("{}: {:p}", self.describe_value(-1), self.to_pointer(-1))),
+            });
+            match self.userdata_ref::<CaughtPanic>(-1) {
+                Ok(p) => format!("rust panic: {}", p.message),
+                Err(_) => format!("(error object is a {} value)", self.describe_value(-1)),
+            }
+        };
+        self.pop(1);
+
+        match status {
+            ThreadStatus::SyntaxError => {
+                let (chunk, line) = match split_location(&message) {
+                    Some((chunk, line)) => (Some(chunk.to_owned()), Some(line)),
+                    None => (None, None),
+                };
+                LuaError::Syntax { message, chunk, line }
+            }
+            ThreadStatus::MemoryError => LuaError::Memory(message),
+            ThreadStatus::GcError => LuaError::Gc(message),
+            ThreadStatus::MessageHandlerError => LuaError::MessageHandler(message),
+            ThreadStatus::FileError => LuaError::File(message),
+            ThreadStatus::Unknown(code) => LuaError::Unknown { code, message },
+            _ => LuaError::Runtime { message, traceback, value, cause },
+        }
+    }
+
+    /// [-(nargs + 1), +nresults, -] Calls the function below the `nargs`
+    /// arguments on top of the stack in protected mode, with a message
+    /// handler that records the traceback of runtime errors. On error the
+    /// function and arguments are popped and the error is returned, except
+    /// for a panic in a native function, which resumes unwinding here.
+    pub fn protected_call(&self, nargs: c_int, nresults: c_int) -> Result<(), LuaError> {
+        let base = self.get_top() - nargs;
+        self.push_fn(Some(crate::traceback::traceback_handler));
+        self.insert(base);
+        let status = self.pcall(nargs, nresults, base);
+        self.remove(base);
+        if !status.is_err() { return Ok(()); }
+        if let Some(payload) = self.take_panic(-1) {
+            self.pop(1);
+            self.take_traceback();
+            panic::resume_unwind(payload);
+        }
+        Err(self.pop_error(status))
+    }
+}
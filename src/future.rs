@@ -0,0 +1,136 @@
+use crate::*;
+
+use std::error::Error;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Pushes the output of an async function and returns how many values it
+/// pushed.
+type AsyncOutput = Box<dyn FnOnce(&State) -> c_int>;
+
+/// The future of a running async function. It is yielded to the host, which
+/// polls it and resumes the coroutine with it once it has an output.
+struct AsyncCall {
+    future: Pin<Box<dyn Future<Output = AsyncOutput>>>,
+    output: Option<AsyncOutput>,
+}
+
+// the future is dropped with the userdata, when the coroutine awaiting it is
+// collected
+metatable! {
+    const ASYNC_CALL_METATABLE = AsyncCall(_s: State, _this: Self);
+}
+
+impl State {
+    /// [-0, +1, m] Pushes a function that runs the future returned by `f`.
+    /// The calling coroutine yields until the future resolves, then gets its
+    /// output as results, or raises its error.
+    ///
+    /// The coroutine must be driven by `Coroutine::into_future`, which polls
+    /// the future in the task awaiting the coroutine; calling the function
+    /// from anywhere else raises an error.
+    pub fn async_function<A, R, E, F, Fut>(&self, f: F) -> TopRef<'_>
+        where A: FromLuaMulti,
+              R: ToLuaMulti + 'static,
+              E: Into<Box<dyn Error + Send + Sync>> + 'static,
+              F: Fn(&State, A) -> Fut + 'static,
+              Fut: Future<Output = Result<R, E>> + 'static
+    {
+        self.named_closure(std::any::type_name::<F>(), move |state| {
+            if !state.is_yieldable() {
+                state.push_string("attempt to call an async function outside a coroutine");
+                state.error();
+            }
+            let future = f(state, state.args::<A>(1));
+            let future = async move {
+                let output: AsyncOutput = match future.await {
+                    Ok(results) => Box::new(move |state: &State| {
+                        results.to_lua(state);
+                        R::COUNT as c_int
+                    }),
+                    Err(e) => Box::new(move |state: &State| state.raise(e)),
+                };
+                output
+            };
+            state.push_userdata(AsyncCall { future: Box::pin(future), output: None }, Some(ASYNC_CALL_METATABLE));
+            state.co_yieldk(1, |state, _| {
+                let output = state.userdata_mut::<AsyncCall>(-1).ok().and_then(|mut call| call.output.take());
+                match output {
+                    Some(output) => output(state),
+                    None => {
+                        state.push_string("async function resumed outside of Coroutine::into_future");
+                        state.error()
+                    }
+                }
+            })
+        })
+    }
+}
+
+impl Coroutine {
+    /// Turns the coroutine into a future that runs the function without
+    /// arguments and resolves to its results. Futures of async functions
+    /// called by the coroutine are polled by this one, and a plain
+    /// `coroutine.yield` hands control back to the executor.
+    ///
+    /// This does not depend on any particular runtime, but as Lua states are
+    /// not `Send`, the future has to run on a local executor, e.g. a tokio
+    /// `LocalSet`.
+    pub fn into_future<R: FromLuaMulti>(self) -> CoroutineFuture<R> {
+        CoroutineFuture { co: self, marker: PhantomData }
+    }
+}
+
+/// A coroutine run as a future, see `Coroutine::into_future`.
+pub struct CoroutineFuture<R> {
+    co: Coroutine,
+    marker: PhantomData<fn() -> R>,
+}
+
+impl<R> CoroutineFuture<R> {
+    /// The coroutine being run.
+    pub fn coroutine(&self) -> &Coroutine { &self.co }
+}
+
+impl<R: FromLuaMulti> Future for CoroutineFuture<R> {
+    type Output = Result<R, LuaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        // the coroutine is resumed through `this.co` below
+        let thread = unsafe { StateRef::from_ptr(this.co.thread().as_ptr()) };
+        loop {
+            // a pending async call is the only value on the stack of the
+            // suspended thread
+            let mut nargs = 0;
+            if thread.status() == ThreadStatus::Yield && thread.get_top() == 1 {
+                if let Ok(mut call) = thread.userdata_mut::<AsyncCall>(-1) {
+                    match call.future.as_mut().poll(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(output) => call.output = Some(output),
+                    }
+                    nargs = 1;
+                }
+            }
+            let resumed = if nargs == 0 { this.co.resume_inner(()) } else { this.co.resume_pushed(nargs) };
+            match resumed {
+                Err(e) => return Poll::Ready(Err(e)),
+                Ok(false) => {
+                    let result = R::from_lua(&thread, 1).ok_or_else(|| {
+                        ConversionError::new(&thread, 1, std::any::type_name::<R>()).into()
+                    });
+                    thread.set_top(0);
+                    return Poll::Ready(result);
+                }
+                Ok(true) if thread.get_top() == 1 && thread.userdata_ref::<AsyncCall>(-1).is_ok() => {}
+                Ok(true) => {
+                    thread.set_top(0);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 
 use crate::*;
 
-pub(crate) fn init_global(this: State) {
+pub(crate) fn init_global(this: &State) {
     let g = this.global();
 
     #[cfg(target_arch = "x86_64")]
@@ -38,7 +38,7 @@ pub(crate) fn init_global(this: State) {
                 b'f' => { s.push_number(*(ptr as *const f32) as lua_Number); }
                 b'd' => { s.push_number(*(ptr as *const f64) as lua_Number); }
                 b'p' => { s.push_integer(*(ptr as *const usize) as lua_Integer); }
-                Else => panic!(""),
+                _ => s.arg_error(2, "invalid format"),
             }
         }
         1
@@ -63,7 +63,7 @@ pub(crate) fn init_global(this: State) {
                 b'f' => { *(ptr as *mut f32) = nval as f32; }
                 b'd' => { *(ptr as *mut f64) = nval as f64; }
                 b'p' => { *(ptr as *mut usize) = nval as usize; }
-                Else => panic!(""),
+                _ => s.arg_error(2, "invalid format"),
             }
         }
         0
@@ -4,7 +4,6 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 #![allow(unused_must_use)]
-#![feature(asm)]
 #![feature(trace_macros)]
 #![feature(fn_traits)]
 
@@ -14,6 +13,10 @@ use std::ops::Deref;
 pub mod ffi;
 pub mod thread;
 pub mod global;
+#[cfg(feature = "serde")]
+pub mod serde;
+#[cfg(feature = "derive")]
+pub use macro_lua_derive::{ToLua, FromLua};
 
 pub use ffi::{
     lua_Number, lua_Integer,
@@ -22,34 +25,65 @@ pub use ffi::{
 };
 
 pub type Index = c_int;
-pub type RustClosure = Box<dyn FnMut(State) -> c_int>;
+pub type RustClosure = Box<dyn FnMut(&State) -> c_int>;
 
 mod ulua;
 mod luaconf;
 mod convert;
 mod state;
+mod lua;
+mod owned;
+mod userdata;
+mod error;
+mod traceback;
+mod cache;
+mod coroutine;
+mod future;
+mod alloc;
+mod limits;
+mod sandbox;
 
 pub use convert::*;
 pub use state::*;
-
+pub use lua::*;
+pub use owned::*;
+pub use userdata::*;
+pub use error::*;
+pub use traceback::*;
+pub use cache::*;
+pub use coroutine::*;
+pub use future::*;
+pub use alloc::*;
+pub use limits::*;
+pub use sandbox::*;
+
+/// A value at a stack slot of a state, which it borrows.
 #[derive(Clone, Copy)]
-pub struct ValRef {
-    state: State,
+pub struct ValRef<'a> {
+    state: StateRef<'a>,
     index: Index
 }
 
-impl ValRef {
-    pub fn new(state: State, index: Index) -> Self {
-        ValRef { state, index: state.abs_index(index) }
+impl<'a> ValRef<'a> {
+    pub fn new(state: &'a State, index: Index) -> ValRef<'a> {
+        ValRef { state: unsafe { StateRef::from_ptr(state.as_ptr()) }, index: state.abs_index(index) }
+    }
+
+    /// The value at `index` of the same state.
+    #[inline]
+    fn at(&self, index: Index) -> ValRef<'a> {
+        ValRef { state: self.state, index: self.state.abs_index(index) }
     }
 
-    pub fn call<T: ToLuaMulti, R: FromLuaMulti>(&self, t: T) -> Result<R, CallError> {
+    /// Calls the value with `protected_call`, leaving the results on the stack.
+    pub fn call<T: ToLuaMulti, R: FromLuaMulti>(&self, t: T) -> Result<R, LuaError> {
         self.push_value(self.index);
         let top = self.get_top();
-        match self.pcall({t.to_lua(&self); T::COUNT as c_int}, R::COUNT as c_int, 0) {
-            ThreadStatus::Ok => R::from_lua(self, top).ok_or(CallError::ValueNotMatch),
-            Status => Err(CallError::VmError(Status)),
-        }
+        t.to_lua(&self);
+        self.protected_call(T::COUNT as c_int, R::COUNT as c_int)?;
+        R::from_lua(self, top).ok_or_else(|| {
+            ConversionError::new(self, top, std::any::type_name::<R>()).into()
+        })
     }
 
     #[inline]
@@ -63,26 +97,34 @@ impl ValRef {
 
     #[inline]
     pub fn check_type(&self, ty: Type) { self.state.check_type(self.index, ty); }
+
+    /// [-0, +0, m] Anchors the value in the registry, see `OwnedRef`.
+    #[inline]
+    pub fn anchor(&self) -> OwnedRef { OwnedRef::new(&self.state, self.index) }
+
+    /// [-0, +0, m] Anchors the string, see `LuaString`.
+    #[inline]
+    pub fn to_lua_string(&self) -> Option<LuaString> { LuaString::new(&self.state, self.index) }
 }
 
-pub struct TopRef(pub ValRef);
+pub struct TopRef<'a>(pub ValRef<'a>);
 
-impl Deref for TopRef {
-    type Target = ValRef;
-    fn deref(&self) -> &ValRef { &self.0 }
+impl<'a> Deref for TopRef<'a> {
+    type Target = ValRef<'a>;
+    fn deref(&self) -> &ValRef<'a> { &self.0 }
 }
 
-impl Deref for ValRef {
+impl Deref for ValRef<'_> {
     type Target = State;
     fn deref(&self) -> &State { &self.state }
 }
 
-pub struct Table(pub ValRef);
+pub struct Table<'a>(pub ValRef<'a>);
 
-impl Table {
-    pub fn geti(&self, i: impl Into<lua_Integer>) -> ValRef {
+impl<'a> Table<'a> {
+    pub fn geti(&self, i: impl Into<lua_Integer>) -> ValRef<'a> {
         self.0.geti(self.0.index, i.into());
-        self.0.val(-1)
+        self.0.at(-1)
     }
 
     pub fn seti<V: ToLua>(&self, i: impl Into<lua_Integer>, v: V) {
@@ -90,9 +132,9 @@ impl Table {
         self.0.seti(self.0.index, i.into());
     }
 
-    pub fn get(&self, k: &str) -> ValRef {
+    pub fn get(&self, k: &str) -> ValRef<'a> {
         self.0.get_field(self.0.index, k);
-        self.0.val(-1)
+        self.0.at(-1)
     }
 
     pub fn set<V: ToLua>(&self, k: &str, v: V) {
@@ -101,9 +143,9 @@ impl Table {
     }
 
     #[inline]
-    pub fn getp<T>(&self, p: *const T) -> ValRef {
+    pub fn getp<T>(&self, p: *const T) -> ValRef<'a> {
         self.0.raw_getp(self.0.index, p);
-        self.0.val(-1)
+        self.0.at(-1)
     }
 
     #[inline]
@@ -122,50 +164,76 @@ impl Table {
     pub fn unreference(&self, r: Reference) {
         self.0.unreference(self.0.index, r);
     }
+
+    /// [-0, +0, m] Anchors the table in the registry, see `OwnedTable`.
+    #[inline]
+    pub fn anchor(&self) -> OwnedTable { OwnedTable(self.0.anchor()) }
+}
+
+pub trait FromIndex<'a>: Sized {
+    /// Converts the value at `index` of a Lua state to a value of type `Self`.
+    ///
+    /// # Safety
+    ///
+    /// Borrowed results point into the value on the stack: it must stay there
+    /// while they are used. `cfn!` arguments stay on the stack for the whole
+    /// call unless the body removes them.
+    unsafe fn from_lua(state: &'a State, index: Index) -> Self;
+}
+
+impl<'a> FromIndex<'a> for &'a str {
+    #[inline]
+    unsafe fn from_lua(state: &'a State, index: Index) -> &'a str {
+        match state.bytes_at(index).and_then(|s| std::str::from_utf8(s).ok()) {
+            Some(s) => s,
+            None => state.arg_error(index, ""),
+        }
+    }
 }
 
-pub trait FromIndex: Sized {
-    /// Converts the value on top of the stack of a Lua state to a value of type
-    /// `Option<Self>`.
-    fn from_lua(state: &State, index: Index) -> Self;
+impl<'a> FromIndex<'a> for Option<&'a str> {
+    #[inline]
+    unsafe fn from_lua(s: &'a State, index: Index) -> Option<&'a str> {
+        s.bytes_at(index).and_then(|s| std::str::from_utf8(s).ok())
+    }
 }
 
-impl FromIndex for &str {
+impl<'a> FromIndex<'a> for &'a [u8] {
     #[inline]
-    fn from_lua(state: &State, index: Index) -> &'static str {
-        if let Some(s) = state.to_str(index) { s } else { state.arg_error(index, ""); }
+    unsafe fn from_lua(s: &'a State, index: Index) -> &'a [u8] {
+        match s.bytes_at(index) { Some(r) => r, None => s.arg_error(index, "") }
     }
 }
 
-impl FromIndex for Option<&str> {
+impl<'a> FromIndex<'a> for Option<&'a [u8]> {
     #[inline]
-    fn from_lua(s: &State, index: Index) -> Option<&'static str> { s.to_str(index) }
+    unsafe fn from_lua(s: &'a State, index: Index) -> Option<&'a [u8]> { s.bytes_at(index) }
 }
 
-impl FromIndex for &[u8] {
+impl FromIndex<'_> for LuaString {
     #[inline]
-    fn from_lua(s: &State, index: Index) -> &'static [u8] {
-        match s.to_bytes(index) { Some(r) => r, None => s.arg_error(index, "") }
+    unsafe fn from_lua(s: &State, index: Index) -> LuaString {
+        match LuaString::new(s, index) { Some(r) => r, None => s.arg_error(index, "") }
     }
 }
 
-impl FromIndex for Option<&[u8]> {
+impl FromIndex<'_> for Option<LuaString> {
     #[inline]
-    fn from_lua(s: &State, index: Index) -> Option<&'static [u8]> { s.to_bytes(index) }
+    unsafe fn from_lua(s: &State, index: Index) -> Option<LuaString> { LuaString::new(s, index) }
 }
 
 macro_rules! impl_number {
     (@int $($t:ty)*) => {
         $(
-            impl FromIndex for $t {
-                fn from_lua(s: &State, index: Index) -> $t {
+            impl FromIndex<'_> for $t {
+                unsafe fn from_lua(s: &State, index: Index) -> $t {
                     if s.is_integer(index) { s.to_integer(index) as $t }
                     else { s.arg_error(index, "") }
                 }
             }
 
-            impl FromIndex for Option<$t> {
-                fn from_lua(s: &State, index: Index) -> Option<$t> {
+            impl FromIndex<'_> for Option<$t> {
+                unsafe fn from_lua(s: &State, index: Index) -> Option<$t> {
                     if s.is_integer(index) {
                         Some(s.to_integer(index) as $t)
                     } else { None }
@@ -176,15 +244,15 @@ macro_rules! impl_number {
 
     (@float $($t:ty)*) => {
         $(
-            impl FromIndex for $t {
-                fn from_lua(s: &State, index: Index) -> $t {
+            impl FromIndex<'_> for $t {
+                unsafe fn from_lua(s: &State, index: Index) -> $t {
                     if s.is_number(index) { s.to_number(index) as $t }
                     else { s.arg_error(index, "") }
                 }
             }
 
-            impl FromIndex for Option<$t> {
-                fn from_lua(s: &State, index: Index) -> Option<$t> {
+            impl FromIndex<'_> for Option<$t> {
+                unsafe fn from_lua(s: &State, index: Index) -> Option<$t> {
                     if s.is_number(index) {
                         Some(s.to_number(index) as $t)
                     } else { None }
@@ -197,26 +265,26 @@ macro_rules! impl_number {
 impl_number!(@int i8 u8 i16 u16 i32 u32 i64 u64 isize usize);
 impl_number!(@float f32 f64);
 
-impl FromIndex for bool {
+impl FromIndex<'_> for bool {
     #[inline]
-    fn from_lua(state: &State, index: Index) -> bool { state.to_bool(index) }
+    unsafe fn from_lua(state: &State, index: Index) -> bool { state.to_bool(index) }
 }
 
-impl FromIndex for Option<bool> {
+impl FromIndex<'_> for Option<bool> {
     #[inline]
-    fn from_lua(s: &State, index: Index) -> Option<bool> {
+    unsafe fn from_lua(s: &State, index: Index) -> Option<bool> {
         if s.is_bool(index) { Some(s.to_bool(index)) } else { None }
     }
 }
 
-impl FromIndex for ValRef {
+impl<'a> FromIndex<'a> for ValRef<'a> {
     #[inline]
-    fn from_lua(s: &State, index: Index) -> ValRef { ValRef { state: *s, index } }
+    unsafe fn from_lua(s: &'a State, index: Index) -> ValRef<'a> { ValRef::new(s, index) }
 }
 
-impl FromIndex for Value {
+impl FromIndex<'_> for Value {
     #[inline]
-    fn from_lua(s: &State, index: Index) -> Value { s.value(index) }
+    unsafe fn from_lua(s: &State, index: Index) -> Value { s.value(index) }
 }
 
 #[macro_export]
@@ -228,7 +296,9 @@ macro_rules! cfn {
     };
 
     (@define_fn $name:ident $l:ident $body:block) => {
-        unsafe extern "C" fn $name($l: *mut $crate::ffi::lua_State) -> i32 $body
+        unsafe extern "C" fn $name($l: *mut $crate::ffi::lua_State) -> i32 {
+            $crate::catch_panic($l, || $body)
+        }
     };
 
     (@define $l:ident $body:block) => {{
@@ -243,7 +313,7 @@ macro_rules! cfn {
 
     (($s:ident $(,$v:ident : $t:ty)*) $($body_option:ident)? $body:block) => {
         cfn!(@define l {
-            let $s = $crate::State::from_ptr(l);
+            let $s = $crate::StateRef::bind(l, &l);
             cfn!(@unpack $s 1 $($v: $t)*);
             cfn!{@body_option $s $($body_option)? $body}
         })
@@ -251,7 +321,7 @@ macro_rules! cfn {
 
     (|$s:ident $(,$v:ident : $t:ty)*| $($body_option:ident)? $body:block) => {
         cfn! { @define l {
-            let $s = $crate::State::from_ptr(l);
+            let $s = $crate::StateRef::bind(l, &l);
             cfn!(@unpack $s 1 $($v: $t)*);
             cfn!{@body_option $s $($body_option)? $body}
         }}
@@ -262,10 +332,11 @@ macro_rules! cfn {
 macro_rules! metatable {
     (@method, $t:ty, ($s:ident, $this:ident, $($v:ident : $a:ty),*) $($body_option:ident)? $body:block) => {
         cfn!(@define l {
-            let $s = $crate::State::from_ptr(l);
-            let $this: &mut $t = std::mem::transmute($s.to_userdata(1));
+            let $s = $crate::StateRef::bind(l, &l);
             cfn!(@unpack $s 2 $($v: $a)*);
-            cfn!(@body_option $s $($body_option)? $body)
+            $s.with_self::<$t>(|$this| {
+                cfn!(@body_option $s $($body_option)? $body)
+            })
         })
     };
 
@@ -276,13 +347,18 @@ macro_rules! metatable {
         $t:tt($s:ident: State, $this:ident: Self) $($option:ident)?;
         $($name:tt($($arg_def:tt)*) $($body_option:ident)? $body:block)*
     ) => {{
-        fn init_metatable(meta: $crate::Table, $s: $crate::State) {
+        fn init_metatable(meta: $crate::Table, $s: &$crate::State) {
+            meta.set("__name", stringify!($t));
+            meta.set("__gc", $crate::gc_userdata::<$t> as $crate::ffi::CFunction);
+            meta.set("__metatable", false);
             metatable!(@option $($option meta)?);
             $(
-                meta.set($name, metatable!(
+                $s.push_fn(Some(metatable!(
                     @method, $t, ($s, $this, $($arg_def)*)
                     $($body_option)? $body
-                ));
+                )));
+                $s.name_function(-1, concat!(stringify!($t), ":", $name));
+                meta.set($name, $crate::TopRef($s.val(-1)));
             )*
         }
         init_metatable
@@ -295,13 +371,13 @@ macro_rules! metatable {
 #[macro_export]
 macro_rules! struct_to_table {
     (@count) => { 0 };
-    (@count $t:ident $(tts:ident)*) => { struct_to_table!(@count $($tts)*) + 1 };
+    (@count $t:ident $($tts:ident)*) => { struct_to_table!(@count $($tts)*) + 1 };
 
     (@set $table:ident, $t:ident, $field:ident) => { $table.set(stringify!($field), $t.$field); };
     (@set $table:ident, $t:ident, $field:ident, $expr:expr) => { $table.set(stringify!($field), $expr); };
 
     ($s:ident, $t:ident; $($field:ident $(= $expr:expr)?),*) => {{
-        let t = $s.table(0, 0); // TODO
+        let t = $s.table(0, struct_to_table!(@count $($field)*));
         $(struct_to_table!(@set t, $t, $field $(, $expr)?);)*
     }};
 
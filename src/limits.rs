@@ -0,0 +1,364 @@
+use crate::*;
+use crate::ffi::*;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// How many instructions run between two checks of the deadline and the
+/// interrupt callback.
+const CHECK_INTERVAL: u64 = 1000;
+
+/// Why a script was aborted by the execution limits. It is raised as a
+/// userdata, which `pop_error` turns into `LuaError::Timeout` or
+/// `LuaError::Interrupted`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Abort {
+    Instructions,
+    Deadline,
+    Interrupted,
+}
+
+impl Abort {
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Abort::Instructions => "instruction limit exceeded",
+            Abort::Deadline => "deadline exceeded",
+            Abort::Interrupted => "interrupted",
+        }
+    }
+
+    pub(crate) fn into_error(self) -> LuaError {
+        match self {
+            Abort::Interrupted => LuaError::Interrupted(self.message().to_owned()),
+            _ => LuaError::Timeout(self.message().to_owned()),
+        }
+    }
+}
+
+metatable! {
+    const ABORT_METATABLE = Abort(s: State, this: Self);
+
+    "__tostring" () push { this.message() }
+}
+
+type Interrupt = Box<dyn FnMut() -> bool + Send>;
+
+/// A hook set with `State::set_hook` before the limits. The limits hook
+/// calls it for the events it asked for, and puts it back once the limits
+/// are cleared.
+#[derive(Clone, Copy)]
+struct PreviousHook {
+    hook: extern "C" fn(*mut lua_State, *mut lua_Debug),
+    mask: HookMask,
+    count: c_int,
+    /// Instructions left before its next count event.
+    countdown: u64,
+}
+
+impl PreviousHook {
+    /// The hook of `state`, unless it is the limits hook or none.
+    fn of(state: &State) -> Option<PreviousHook> {
+        let hook = state.get_hook().filter(|&hook| !is_limits_hook(hook))?;
+        let count = state.get_hook_count();
+        Some(PreviousHook { hook, mask: state.get_hook_mask(), count, countdown: count.max(1) as u64 })
+    }
+
+    fn wants(&self, event: c_int) -> bool {
+        let mask = match event {
+            LUA_HOOKCALL | LUA_HOOKTAILCALL => MASKCALL,
+            LUA_HOOKRET => MASKRET,
+            LUA_HOOKLINE => MASKLINE,
+            _ => MASKCOUNT,
+        };
+        self.mask.contains(mask)
+    }
+
+    /// Accounts for `count` instructions. Returns `true` if a count event is
+    /// due.
+    fn count(&mut self, count: u64) -> bool {
+        if !self.mask.contains(MASKCOUNT) { return false; }
+        if count < self.countdown {
+            self.countdown -= count;
+            return false;
+        }
+        self.countdown = self.count.max(1) as u64;
+        true
+    }
+
+    fn restore(&self, state: &State) {
+        state.set_hook(Some(self.hook), self.mask, self.count);
+    }
+}
+
+/// The execution limits of a state, shared by its threads.
+#[derive(Default)]
+pub(crate) struct Limits {
+    instruction_limit: Option<u64>,
+    executed: u64,
+    deadline: Option<Instant>,
+    interrupt: Option<Interrupt>,
+    /// Set once a limit is hit. From then on every instruction raises the
+    /// error again, so a `pcall` cannot swallow it.
+    aborted: Option<Abort>,
+    previous: Option<PreviousHook>,
+}
+
+impl Limits {
+    /// Accounts for `count` more instructions and checks the limits. Returns
+    /// the number of instructions to run before the next check, or `None` if
+    /// there is nothing left to check.
+    fn check(&mut self, count: u64) -> Result<Option<u64>, Abort> {
+        if let Some(abort) = self.aborted { return Err(abort); }
+        if self.is_empty() { return Ok(None); }
+        self.executed += count;
+        let mut next = CHECK_INTERVAL;
+        if let Some(limit) = self.instruction_limit {
+            if self.executed >= limit { return Err(self.abort(Abort::Instructions)); }
+            next = next.min(limit - self.executed);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(self.abort(Abort::Deadline));
+        }
+        if self.interrupt.as_mut().is_some_and(|interrupt| interrupt()) {
+            return Err(self.abort(Abort::Interrupted));
+        }
+        Ok(Some(next))
+    }
+
+    fn abort(&mut self, abort: Abort) -> Abort {
+        self.aborted = Some(abort);
+        abort
+    }
+
+    /// The number of instructions to run before the first check.
+    fn first_check(&self) -> u64 {
+        if self.aborted.is_some() { return 1; }
+        self.instruction_limit.map_or(CHECK_INTERVAL, |n| n.saturating_sub(self.executed).clamp(1, CHECK_INTERVAL))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.instruction_limit.is_none() && self.deadline.is_none() && self.interrupt.is_none()
+    }
+
+    /// Installs the limits hook on `state`, checking after `next`
+    /// instructions, or earlier if the previous hook wants a count event.
+    fn install(&self, state: &State, next: u64) {
+        let (mask, next) = match &self.previous {
+            Some(previous) => {
+                let next = if previous.mask.contains(MASKCOUNT) { next.min(previous.countdown) } else { next };
+                (previous.mask | MASKCOUNT, next)
+            }
+            None => (MASKCOUNT, next),
+        };
+        state.set_hook(Some(limits_hook), mask, next as c_int);
+    }
+
+    /// Removes the limits hook from `state`, putting the previous hook back.
+    fn uninstall(&self, state: &State) {
+        match &self.previous {
+            Some(previous) => previous.restore(state),
+            None => state.set_hook(None, HookMask::empty(), 0),
+        }
+    }
+}
+
+/// The part of the limits that an `InterruptHandle` reaches from other
+/// threads.
+pub(crate) struct InterruptFlag {
+    requested: AtomicBool,
+    /// The threads of the state, until it is closed.
+    threads: Mutex<Option<Threads>>,
+}
+
+/// The threads that may be running Lua code, as seen by other threads, which
+/// only set their hooks: the main thread and every coroutine resumed since it
+/// was created. Coroutines are removed when they are freed.
+struct Threads {
+    main: *mut lua_State,
+    coroutines: HashSet<*mut lua_State>,
+}
+
+unsafe impl Send for Threads {}
+
+impl InterruptFlag {
+    pub(crate) fn new(main: *mut lua_State) -> Arc<InterruptFlag> {
+        let threads = Threads { main, coroutines: HashSet::new() };
+        Arc::new(InterruptFlag { requested: AtomicBool::new(false), threads: Mutex::new(Some(threads)) })
+    }
+
+    /// Called when `l` is resumed.
+    pub(crate) fn resumed(&self, l: *mut lua_State) {
+        if let Some(threads) = &mut *self.threads.lock().unwrap() {
+            if l != threads.main { threads.coroutines.insert(l); }
+        }
+    }
+
+    /// Called when the coroutine `l` is freed.
+    pub(crate) fn freed(&self, l: *mut lua_State) {
+        if let Some(threads) = &mut *self.threads.lock().unwrap() {
+            threads.coroutines.remove(&l);
+        }
+    }
+
+    /// Called when the state is closed, after which handles do nothing.
+    pub(crate) fn close(&self) {
+        *self.threads.lock().unwrap() = None;
+    }
+}
+
+fn is_limits_hook(hook: extern "C" fn(*mut lua_State, *mut lua_Debug)) -> bool {
+    hook as usize == limits_hook as *const () as usize
+}
+
+extern "C" fn limits_hook(l: *mut lua_State, ar: *mut lua_Debug) {
+    unsafe {
+        let shared = crate::ulua::shared(l);
+        let event = (*ar).event;
+        if event != LUA_HOOKCOUNT {
+            let previous = shared.limits.lock().unwrap().previous.filter(|previous| previous.wants(event));
+            if let Some(previous) = previous { (previous.hook)(l, ar); }
+            return;
+        }
+        catch_panic(l, || {
+            let state = State::from_ptr(l);
+            let count = state.get_hook_count() as u64;
+            // the lock is released before calling the previous hook or
+            // raising
+            let (checked, previous) = {
+                let mut limits = shared.limits.lock().unwrap();
+                if shared.interrupt.requested.swap(false, Ordering::AcqRel) {
+                    limits.abort(Abort::Interrupted);
+                }
+                let checked = limits.check(count);
+                let previous = limits.previous.as_mut().and_then(|previous| previous.count(count).then_some(previous.hook));
+                match checked {
+                    Ok(Some(next)) => limits.install(&state, next),
+                    Ok(None) => limits.uninstall(&state),
+                    // raise again at the next instruction of whatever catches it
+                    Err(_) => limits.install(&state, 1),
+                }
+                (checked, previous)
+            };
+            if let Some(hook) = previous { hook(l, ar); }
+            if let Err(abort) = checked {
+                state.push_userdata(abort, Some(ABORT_METATABLE));
+                state.error()
+            }
+            0
+        });
+    }
+}
+
+/// Installs the hook on a coroutine being resumed while limits are set, if
+/// it does not have it, e.g. because it was created before the limits were
+/// set, and makes it check at its first instruction if an interrupt is
+/// pending.
+pub(crate) fn hook_resumed(l: *mut lua_State) {
+    let state = unsafe { State::from_ptr(l) };
+    let shared = crate::ulua::shared(l);
+    shared.interrupt.resumed(l);
+    let limits = shared.limits.lock().unwrap();
+    if shared.interrupt.requested.load(Ordering::Acquire) {
+        limits.install(&state, 1);
+    } else if !state.get_hook().is_some_and(is_limits_hook) && (!limits.is_empty() || limits.aborted.is_some()) {
+        limits.install(&state, limits.first_check());
+    }
+}
+
+impl State {
+    /// Aborts execution with `LuaError::Timeout` once `n` more instructions
+    /// have run.
+    ///
+    /// Like the other limits, it installs a count hook on this thread.
+    /// Coroutines created from it inherit the hook, and coroutines get it
+    /// when resumed. A hook set with `set_hook` before is kept: the limits
+    /// hook calls it for the events it asked for, and `clear_limits` puts it
+    /// back. Setting a hook with `set_hook` while limits are set replaces
+    /// them on that thread.
+    /// Limits are only checked while Lua code runs, not during a long call
+    /// to a native function. Once a limit is hit, any Lua code run in the
+    /// state raises the error again, even inside `pcall`, until the limits
+    /// are set again or cleared.
+    pub fn set_instruction_limit(&self, n: u64) {
+        self.update_limits(|limits| {
+            limits.instruction_limit = Some(n);
+            limits.executed = 0;
+        });
+    }
+
+    /// Aborts execution with `LuaError::Timeout` once `deadline` has passed,
+    /// see `set_instruction_limit`. The clock is checked every 1000
+    /// instructions.
+    pub fn set_deadline(&self, deadline: Instant) {
+        self.update_limits(|limits| limits.deadline = Some(deadline));
+    }
+
+    /// Calls `f` every 1000 instructions, and aborts execution with
+    /// `LuaError::Interrupted` when it returns `true`, see
+    /// `set_instruction_limit`. `f` must not use the state.
+    pub fn set_interrupt<F: FnMut() -> bool + Send + 'static>(&self, f: F) {
+        self.update_limits(|limits| limits.interrupt = Some(Box::new(f)));
+    }
+
+    /// Removes the execution limits and withdraws a pending interrupt.
+    /// Threads still running with the hook installed for them remove it at
+    /// their next check.
+    pub fn clear_limits(&self) {
+        crate::ulua::shared(self.as_ptr()).interrupt.requested.store(false, Ordering::Release);
+        self.update_limits(|limits| *limits = Limits::default());
+    }
+
+    /// Returns a handle that interrupts the state from any thread. A hook
+    /// set with `set_hook` before is kept as for `set_instruction_limit`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        let shared = crate::ulua::shared(self.as_ptr());
+        let mut limits = shared.limits.lock().unwrap();
+        limits.previous = PreviousHook::of(self).or(limits.previous);
+        InterruptHandle(shared.interrupt.clone())
+    }
+
+    fn update_limits(&self, f: impl FnOnce(&mut Limits)) {
+        let mut limits = crate::ulua::shared(self.as_ptr()).limits.lock().unwrap();
+        let previous = PreviousHook::of(self).or(limits.previous);
+        f(&mut limits);
+        limits.aborted = None;
+        limits.previous = previous;
+        if limits.is_empty() {
+            limits.uninstall(self);
+            limits.previous = None;
+        } else {
+            limits.install(self, limits.first_check());
+        }
+    }
+}
+
+/// Interrupts a state from another thread, see `State::interrupt_handle`.
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<InterruptFlag>);
+
+impl InterruptHandle {
+    /// Makes the state raise `LuaError::Interrupted` at its next instruction,
+    /// like an interrupt callback returning `true`, whichever thread or
+    /// coroutine runs it. If no Lua code is running, the next script is
+    /// aborted; `clear_limits` withdraws the request. Does nothing once the
+    /// state is closed.
+    pub fn interrupt(&self) {
+        let threads = self.0.threads.lock().unwrap();
+        if let Some(threads) = &*threads {
+            self.0.requested.store(true, Ordering::Release);
+            // lua_sethook can be called asynchronously, e.g. from a signal;
+            // the lock keeps coroutines from being freed meanwhile
+            for &l in Some(&threads.main).into_iter().chain(&threads.coroutines) {
+                unsafe { lua_sethook(l, Some(limits_hook), LUA_MASKCOUNT, 1); }
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for InterruptHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("InterruptHandle")
+    }
+}
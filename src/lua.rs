@@ -0,0 +1,103 @@
+use crate::*;
+use crate::ffi::*;
+
+use std::ops::Deref;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// An owned Lua state. The underlying `lua_State` is closed exactly once,
+/// when this value is dropped.
+pub struct Lua {
+    state: State,
+    alive: Arc<AtomicBool>,
+}
+
+impl Lua {
+    /// Initializes a new Lua state. This function does not open any libraries
+    /// by default. Calls `luaL_newstate` internally.
+    pub fn new() -> Lua {
+        unsafe { Lua::from_ptr(luaL_newstate()) }
+    }
+
+    /// Takes ownership of a raw `lua_State`, which will be closed on drop.
+    /// The pointer must be a main thread that is not owned elsewhere.
+    pub unsafe fn from_ptr(l: *mut lua_State) -> Lua {
+        let lua = Lua { state: State::from_ptr(l), alive: Arc::new(AtomicBool::new(true)) };
+        lua.set_alive_flag(&lua.alive);
+        lua
+    }
+
+    /// Releases ownership of the wrapped `lua_State` without closing it. The
+    /// liveness flag seen by `OwnedRef`s is leaked along with it.
+    pub fn into_ptr(self) -> *mut lua_State {
+        let l = self.state.as_ptr();
+        std::mem::forget(self);
+        l
+    }
+
+    /// Returns a borrowed handle that cannot outlive this `Lua`.
+    #[inline]
+    pub fn state(&self) -> StateRef<'_> {
+        self.state.borrowed()
+    }
+
+    /// Maps to `luaL_openlibs`, and also loads the ulua globals and the
+    /// `thread` library.
+    pub fn open_libs(&self) {
+        unsafe { luaL_openlibs(self.state.as_ptr()) }
+        self.load_global();
+        self.open_thread();
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Lua { Lua::new() }
+}
+
+impl Drop for Lua {
+    /// Maps to `lua_close`, and frees the allocator of a state created by
+    /// `StateBuilder`.
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Release);
+        let allocator = self.state.allocator();
+        unsafe {
+            lua_close(self.state.as_ptr());
+            if !allocator.is_null() { drop(Box::from_raw(allocator)); }
+        }
+    }
+}
+
+impl Deref for Lua {
+    type Target = State;
+    fn deref(&self) -> &State { &self.state }
+}
+
+/// A borrowed view of a Lua state, bound to the lifetime of its owner: either
+/// a `Lua` or the frame of a native function called by Lua.
+#[derive(Clone, Copy)]
+pub struct StateRef<'a> {
+    state: *mut lua_State,
+    marker: PhantomData<&'a State>,
+}
+
+impl<'a> StateRef<'a> {
+    /// Wraps a `lua_State` for a lifetime chosen by the caller, which must
+    /// not outlive the state.
+    #[inline(always)]
+    pub(crate) unsafe fn from_ptr(l: *mut lua_State) -> StateRef<'a> {
+        StateRef { state: l, marker: PhantomData }
+    }
+
+    /// Wraps the `lua_State` passed to a native function. The `frame` anchor
+    /// keeps the handle from escaping the function body, see `cfn!`.
+    #[inline(always)]
+    pub unsafe fn bind<T: ?Sized>(l: *mut lua_State, frame: &'a T) -> StateRef<'a> {
+        StateRef::from_ptr(l)
+    }
+}
+
+impl<'a> Deref for StateRef<'a> {
+    type Target = State;
+    fn deref(&self) -> &State { State::from_ref(&self.state) }
+}
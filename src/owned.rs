@@ -0,0 +1,268 @@
+use crate::*;
+use crate::ffi::LUA_REGISTRYINDEX;
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A value anchored in the registry through `luaL_ref`. Unlike `ValRef`, it
+/// does not depend on a stack slot, so it can be kept across calls, e.g. in a
+/// host struct that holds a Lua callback. The reference is released on drop.
+///
+/// Owned references keep track of whether their `Lua` is still open: dropping
+/// one after the state was closed is a no-op, and pushing one panics. They
+/// are not `Send`, as the state is not, and releasing the reference from
+/// another thread would race with it.
+///
+/// Operations that push values take the state they run on. Cloning,
+/// dropping and inspecting a reference use a thread of the state kept for
+/// that purpose, whose stack is free whatever the other threads are doing.
+pub struct OwnedRef {
+    /// The reference thread, see `State::ref_thread`.
+    state: State,
+    reference: Reference,
+    alive: Option<Arc<AtomicBool>>,
+    marker: PhantomData<*mut ()>,
+}
+
+impl OwnedRef {
+    /// [-0, +0, m] Anchors the value at `index`. The value stays on the stack.
+    pub fn new(state: &State, index: Index) -> OwnedRef {
+        state.push_value(index);
+        OwnedRef::from_top(state)
+    }
+
+    /// [-1, +0, m] Anchors the value on top of the stack and pops it.
+    pub fn from_top(state: &State) -> OwnedRef {
+        state.check_stack_msg(2, "OwnedRef");
+        let reference = state.reference(LUA_REGISTRYINDEX);
+        OwnedRef { state: state.ref_thread(), reference, alive: state.alive_flag(), marker: PhantomData }
+    }
+
+    /// The registry reference held by this value.
+    #[inline]
+    pub fn reference(&self) -> Reference { self.reference }
+
+    /// Returns `false` once the owning `Lua` has been closed.
+    #[inline]
+    pub fn is_alive(&self) -> bool {
+        self.alive.as_ref().map(|a| a.load(Ordering::Acquire)).unwrap_or(true)
+    }
+
+    /// [-0, +1, m] Pushes the referenced value onto `state`, which must belong
+    /// to the same Lua state as this reference. Panics otherwise.
+    #[inline]
+    pub fn push_to(&self, state: &State) {
+        self.check_alive();
+        assert!(state.main_thread().as_ptr() == self.state.main_thread().as_ptr(), "OwnedRef pushed to a different Lua state");
+        state.check_stack_msg(1, "OwnedRef");
+        state.raw_geti(LUA_REGISTRYINDEX, self.reference.value() as lua_Integer);
+    }
+
+    /// Type of the referenced value.
+    pub fn type_of(&self) -> Type {
+        self.state.balance_with(|s| { self.push_to(s); s.type_of(-1) })
+    }
+
+    /// Maps to `lua_topointer` on the referenced value.
+    pub fn to_pointer(&self) -> *const c_void {
+        self.state.balance_with(|s| { self.push_to(s); s.to_pointer(-1) })
+    }
+
+    #[inline]
+    pub(crate) fn check_alive(&self) {
+        assert!(self.is_alive(), "OwnedRef used after its Lua state was closed");
+    }
+}
+
+impl Clone for OwnedRef {
+    fn clone(&self) -> OwnedRef {
+        self.push_to(&self.state);
+        OwnedRef::from_top(&self.state)
+    }
+}
+
+impl Drop for OwnedRef {
+    fn drop(&mut self) {
+        if self.is_alive() {
+            self.state.unreference(LUA_REGISTRYINDEX, self.reference);
+        }
+    }
+}
+
+impl std::fmt::Debug for OwnedRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "OwnedRef({:?})", self.reference)
+    }
+}
+
+/// An `OwnedRef` known to hold a table.
+#[derive(Clone, Debug)]
+pub struct OwnedTable(pub(crate) OwnedRef);
+
+impl OwnedTable {
+    /// [-0, +0, m] Anchors the table at `index`, or returns `None` if the value
+    /// is not a table.
+    pub fn new(state: &State, index: Index) -> Option<OwnedTable> {
+        if state.is_table(index) { Some(OwnedTable(OwnedRef::new(state, index))) } else { None }
+    }
+
+    /// [-0, +1, -] Pushes the table onto `state` for full access.
+    pub fn to_table<'a>(&self, state: &'a State) -> Table<'a> {
+        self.0.push_to(state);
+        Table(state.val(-1))
+    }
+
+    /// Reads `t[k]` on `state` without invoking metamethods.
+    pub fn raw_get<K: ToLua, R: FromLua>(&self, state: &State, k: K) -> Option<R> {
+        self.0.push_to(state);
+        state.check_stack_msg(1, "OwnedTable::raw_get");
+        state.push(k);
+        state.raw_get(-2);
+        let result = R::from_lua(state, -1);
+        state.pop(2);
+        result
+    }
+
+    /// Writes `t[k] = v` on `state` without invoking metamethods.
+    pub fn raw_set<K: ToLua, V: ToLua>(&self, state: &State, k: K, v: V) {
+        self.0.push_to(state);
+        state.check_stack_msg(2, "OwnedTable::raw_set");
+        state.push(k);
+        state.push(v);
+        state.raw_set(-3);
+        state.pop(1);
+    }
+
+    /// Maps to `lua_rawlen`.
+    pub fn raw_len(&self) -> usize {
+        self.0.state.balance_with(|s| { self.0.push_to(s); s.raw_len(-1) })
+    }
+}
+
+impl std::ops::Deref for OwnedTable {
+    type Target = OwnedRef;
+    fn deref(&self) -> &OwnedRef { &self.0 }
+}
+
+/// An `OwnedRef` known to hold a function.
+#[derive(Clone, Debug)]
+pub struct OwnedFunction(pub(crate) OwnedRef);
+
+impl OwnedFunction {
+    /// [-0, +0, m] Anchors the function at `index`, or returns `None` if the
+    /// value is not a function.
+    pub fn new(state: &State, index: Index) -> Option<OwnedFunction> {
+        if state.type_of(index) == Type::Function {
+            Some(OwnedFunction(OwnedRef::new(state, index)))
+        } else { None }
+    }
+
+    /// Calls the function on `state`, which must belong to the same Lua state,
+    /// and converts the results. The stack is left balanced.
+    pub fn call<T: ToLuaMulti, R: FromLuaMulti>(&self, state: &State, args: T) -> Result<R, LuaError> {
+        let top = state.get_top();
+        self.0.push_to(state);
+        let result = state.val(-1).call(args);
+        state.set_top(top);
+        result
+    }
+}
+
+impl std::ops::Deref for OwnedFunction {
+    type Target = OwnedRef;
+    fn deref(&self) -> &OwnedRef { &self.0 }
+}
+
+/// An owned Lua string. The string is anchored in the registry, so its bytes
+/// stay valid after the value has been popped from the stack or the callback
+/// that received it has returned.
+#[derive(Clone)]
+pub struct LuaString {
+    pub(crate) inner: OwnedRef,
+    ptr: *const u8,
+    len: usize,
+}
+
+impl LuaString {
+    /// Anchors the string (or number, converted to a string) at `index`.
+    /// The value on the stack is left untouched.
+    pub fn new(state: &State, index: Index) -> Option<LuaString> {
+        if !state.is_string(index) { return None; }
+        state.push_value(index);
+        let mut len = 0;
+        let ptr = state.tolstring(-1, &mut len) as *const u8;
+        Some(LuaString { inner: OwnedRef::from_top(state), ptr, len })
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.inner.check_alive();
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Returns `None` if the string is not valid UTF-8.
+    #[inline]
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()).ok()
+    }
+
+    #[inline]
+    pub fn to_string_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+}
+
+impl AsRef<[u8]> for LuaString {
+    fn as_ref(&self) -> &[u8] { self.as_bytes() }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &LuaString) -> bool { self.as_bytes() == other.as_bytes() }
+}
+
+impl std::fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.to_string_lossy(), f)
+    }
+}
+
+static ALIVE_KEY: u8 = 0;
+
+static REF_THREAD_KEY: u8 = 0;
+
+impl State {
+    /// Registers the flag that `OwnedRef`s use to detect a closed state.
+    pub(crate) fn set_alive_flag(&self, flag: &Arc<AtomicBool>) {
+        self.push_light_userdata(Arc::as_ptr(flag) as *mut AtomicBool);
+        self.c_reg().setp(&ALIVE_KEY, TopRef(self.val(-1)));
+    }
+
+    /// [-0, +0, m] The thread that `OwnedRef`s are cloned, released and
+    /// inspected on, created the first time. It is anchored in the registry
+    /// and only ever holds the values of those operations.
+    fn ref_thread(&self) -> State {
+        self.balance_with(|s| {
+            if s.raw_getp(LUA_REGISTRYINDEX, &REF_THREAD_KEY) != Type::Thread {
+                s.pop(1);
+                s.new_thread();
+                s.push_value(-1);
+                s.raw_setp(LUA_REGISTRYINDEX, &REF_THREAD_KEY);
+            }
+            unsafe { State::from_ptr(s.to_thread(-1).unwrap().as_ptr()) }
+        })
+    }
+
+    fn alive_flag(&self) -> Option<Arc<AtomicBool>> {
+        let p = self.balance_with(|s| {
+            s.c_reg().getp(&ALIVE_KEY);
+            s.to_userdata(-1) as *const AtomicBool
+        });
+        if p.is_null() { None } else {
+            unsafe {
+                Arc::increment_strong_count(p);
+                Some(Arc::from_raw(p))
+            }
+        }
+    }
+}
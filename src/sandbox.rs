@@ -0,0 +1,260 @@
+use crate::*;
+use crate::ffi::*;
+
+use std::fmt;
+use std::io::{self, Write};
+use std::ptr;
+use std::rc::Rc;
+
+/// Registry key of the table `push_base` builds the base functions in.
+static BASE_KEY: u8 = 0;
+
+/// The functions of the base library given to a sandbox that allows all of
+/// it. `dofile` and `loadfile` read files and run them in the global
+/// environment, so they are left out, as is `collectgarbage`, which controls
+/// the collector of the whole state.
+const BASE_FUNCTIONS: &[&str] = &[
+    "assert", "error", "getmetatable", "ipairs", "load",
+    "next", "pairs", "pcall", "print", "rawequal", "rawget", "rawlen",
+    "rawset", "select", "setmetatable", "tonumber", "tostring", "type",
+    "xpcall", "_VERSION",
+];
+
+/// Builds restricted environments for untrusted chunks from an allow-list of
+/// libraries and functions. Nothing is allowed by default:
+///
+/// ```
+/// # use macro_lua::*;
+/// # fn main() -> Result<(), LuaError> {
+/// # let state = Lua::new();
+/// # let source = "return os.time()";
+/// let sandbox = Sandbox::new()
+///     .allow_library(Library::Base)
+///     .allow_library(Library::String)
+///     .allow_function(Library::Os, "time");
+/// let env = sandbox.env(&state);
+/// let f = sandbox.load(&state, &env, source, Some("=mod"))?;
+/// f.call::<_, i64>(())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Libraries are copied, so chunks cannot change them for the host, and
+/// `load` is replaced by one that only loads text chunks in the sandbox.
+/// Globals set by the chunks go to the environment table. The base functions
+/// are created once per state rather than taken from the host's `_G`,
+/// `getmetatable` hides the metatable shared by all strings, and `print`
+/// writes to the callback given to `on_print`.
+#[derive(Clone, Debug, Default)]
+pub struct Sandbox {
+    libraries: Vec<Library>,
+    functions: Vec<(Library, String)>,
+    print: Option<PrintFn>,
+}
+
+type PrintCallback = dyn Fn(&[u8]);
+
+/// The callback of `Sandbox::on_print`.
+#[derive(Clone)]
+struct PrintFn(Rc<PrintCallback>);
+
+impl fmt::Debug for PrintFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("PrintFn") }
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox { Sandbox::default() }
+
+    /// Allows all functions of `lib`. For `Library::Base`, this is the base
+    /// library without `dofile`, `loadfile` and `collectgarbage`, with `_G`
+    /// set to the environment itself. Beware that `io`, `os`, `package` and
+    /// `debug` give full access to the host.
+    pub fn allow_library(mut self, lib: Library) -> Sandbox {
+        if !self.libraries.contains(&lib) { self.libraries.push(lib); }
+        self
+    }
+
+    /// Allows the function `name` of `lib`, e.g. `(Library::Os, "time")`.
+    /// With `Library::Base`, `name` is a global, such as `print`.
+    pub fn allow_function(mut self, lib: Library, name: &str) -> Sandbox {
+        self.functions.push((lib, name.to_owned()));
+        self
+    }
+
+    /// Sends the output of `print` to `f`, one line at a time without the
+    /// newline. By default it goes to the standard output.
+    pub fn on_print<F: Fn(&[u8]) + 'static>(mut self, f: F) -> Sandbox {
+        self.print = Some(PrintFn(Rc::new(f)));
+        self
+    }
+
+    /// [-0, +1, m] Pushes a new environment table holding the allowed
+    /// libraries and functions. Libraries other than the base one are opened
+    /// first if the state has not opened them, without setting them as
+    /// globals.
+    pub fn env<'a>(&self, state: &'a State) -> Table<'a> {
+        state.check_stack_msg(7, "sandbox");
+        let env = state.table(0, 0);
+        let e = env.0.index;
+        for &lib in &self.libraries {
+            push_library(state, lib);
+            if lib == Library::Base {
+                for &name in BASE_FUNCTIONS {
+                    state.get_field(-1, name);
+                    state.set_field(e, name);
+                }
+                env.set("_G", env.0);
+            } else {
+                let copy = state.table(0, 0);
+                state.push_nil();
+                while state.next(-3) {
+                    state.push_value(-2);
+                    state.insert(-2);
+                    state.raw_set(copy.0.index);
+                }
+                state.set_field(e, lib.name());
+            }
+            state.pop(1);
+        }
+        for (lib, name) in &self.functions {
+            push_library(state, *lib);
+            state.get_field(-1, name);
+            if *lib == Library::Base {
+                state.set_field(e, name);
+            } else {
+                if state.get_field(e, lib.name()) != Type::Table {
+                    state.pop(1);
+                    state.create_table(0, 1);
+                    state.push_value(-1);
+                    state.set_field(e, lib.name());
+                }
+                state.insert(-2);
+                state.set_field(-2, name);
+                state.pop(1);
+            }
+            state.pop(1);
+        }
+        if state.get_field(e, "print") == Type::Function {
+            let print = self.print.clone();
+            state.named_closure("print", move |s| sandboxed_print(s, print.as_ref()));
+            state.set_field(e, "print");
+        }
+        state.pop(1);
+        if state.get_field(e, "load") == Type::Function {
+            state.push_value(e);
+            state.insert(-2);
+            state.push_cclosure(Some(sandboxed_load), 2);
+            state.set_field(e, "load");
+        } else {
+            state.pop(1);
+        }
+        env
+    }
+
+    /// [-0, +1, -] Loads `source` with `env` as its `_ENV`, like
+    /// `State::load_with_env` with `Mode::Text`, so precompiled chunks are
+    /// refused.
+    pub fn load<'a, F: AsRef<[u8]>>(&self, state: &'a State, env: &Table, source: F, chunk_name: Option<&str>) -> Result<ValRef<'a>, LuaError> {
+        state.load_with_env(source, chunk_name, env, Mode::Text)
+    }
+}
+
+/// [-0, +1, e] Pushes the table of `lib`, opening it if needed. The base
+/// library is always a new table, see `push_base`.
+fn push_library(state: &State, lib: Library) {
+    if lib == Library::Base { return push_base(state); }
+    state.get_subtable(LUA_REGISTRYINDEX, "_LOADED");
+    if state.get_field(-1, lib.name()) != Type::Table {
+        state.pop(1);
+        state.requiref(lib.name(), Some(lib.loader()), false);
+    }
+    state.remove(-2);
+}
+
+/// [-0, +1, e] Pushes the table with the functions of the base library,
+/// built the first time a sandbox needs it and kept in the registry.
+/// `luaopen_base` stores them in the global table, so the registry points to
+/// the new table while it runs. `getmetatable` is replaced by the sandboxed
+/// version, and `print` is by each environment.
+fn push_base(state: &State) {
+    if state.raw_getp(LUA_REGISTRYINDEX, &BASE_KEY) == Type::Table { return; }
+    state.pop(1);
+    state.raw_geti(LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+    state.create_table(0, 0);
+    state.push_value(-1);
+    state.raw_seti(LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+    state.push_fn(Some(luaopen_base));
+    let status = state.pcall(0, 1, 0);
+    state.push_value(-3);
+    state.raw_seti(LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS);
+    if status.is_err() {
+        state.replace(-3);
+        state.pop(1);
+        state.error();
+    }
+    state.pop(1);
+    state.remove(-2);
+    state.push_fn(Some(sandboxed_getmetatable));
+    state.set_field(-2, "getmetatable");
+    state.push_value(-1);
+    state.raw_setp(LUA_REGISTRYINDEX, &BASE_KEY);
+}
+
+/// `print` as seen by sandboxed code. The one of the base library converts
+/// its arguments with the `tostring` of the host's globals, this one with
+/// `luaL_tolstring`, as Lua 5.4 does. All arguments are converted before the
+/// line is built, as `__tostring` may raise an error.
+fn sandboxed_print(s: &State, print: Option<&PrintFn>) -> c_int {
+    let n = s.get_top();
+    s.check_stack_msg(n, "print");
+    for i in 1..=n {
+        unsafe { luaL_tolstring(s.as_ptr(), i, ptr::null_mut()); }
+    }
+    let mut line = Vec::new();
+    for i in 1..=n {
+        if i > 1 { line.push(b'\t'); }
+        line.extend_from_slice(&s.to_bytes(n + i).unwrap_or_default());
+    }
+    match print {
+        Some(print) => (print.0)(&line),
+        None => {
+            line.push(b'\n');
+            // like the C one, print ignores write errors
+            let _ = io::stdout().write_all(&line);
+        }
+    }
+    0
+}
+
+/// `getmetatable` as seen by sandboxed code: the metatable of strings is
+/// shared with the host, and its `__index` is the host's `string` table, so
+/// it is not returned.
+unsafe extern "C" fn sandboxed_getmetatable(l: *mut lua_State) -> c_int {
+    let s = State::from_ptr(l);
+    s.check_any(1);
+    if s.type_of(1) == Type::String || !s.get_metatable(1) {
+        s.push_nil();
+        return 1;
+    }
+    s.get_metafield(1, "__metatable");
+    1
+}
+
+/// `load` as seen by sandboxed code: it only loads text chunks, and binds
+/// them to the sandbox unless given an environment. The upvalues are the
+/// sandbox and the real `load`.
+unsafe extern "C" fn sandboxed_load(l: *mut lua_State) -> c_int {
+    let s = State::from_ptr(l);
+    let has_env = !s.is_none(4);
+    s.set_top(4);
+    s.push_string("t");
+    s.replace(3);
+    if !has_env {
+        s.push_value(lua_upvalueindex(1));
+        s.replace(4);
+    }
+    s.push_value(lua_upvalueindex(2));
+    s.insert(1);
+    lua_callk(l, 4, LUA_MULTRET, 0, None);
+    s.get_top()
+}
@@ -0,0 +1,891 @@
+use crate::*;
+use crate::convert::{format_path, key_segment};
+
+use ::serde::{ser, de};
+use ::serde::ser::Serialize;
+use ::serde::de::{DeserializeOwned, DeserializeSeed, Visitor, IntoDeserializer};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+use libc::c_void;
+
+/// How deeply tables can be nested, in both directions. Deeper values fail
+/// with "table nested too deeply" before they can exhaust the Lua or the
+/// Rust stack.
+const MAX_DEPTH: usize = 200;
+
+/// How enum variants are laid out in Lua.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnumRepr {
+    /// `"Variant"` for unit variants, `{Variant = content}` otherwise.
+    External,
+    /// `{[tag] = "Variant", ...fields}`. Only unit, struct, and newtype
+    /// variants whose content is a table can be represented this way.
+    Internal { tag: String },
+    /// `{[tag] = "Variant", [content] = content}`.
+    Adjacent { tag: String, content: String },
+}
+
+/// How `None` and `()` are represented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NullRepr {
+    /// As `nil`. Note that `nil` leaves a hole in sequences and removes keys
+    /// from tables.
+    Nil,
+    /// As a `NULL` light userdata, see `push_null`.
+    Sentinel,
+}
+
+/// How numbers are mapped between Rust and Lua 5.3's two number subtypes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumberMode {
+    /// Rust integers become Lua integers and Rust floats become Lua floats.
+    /// Integer targets only accept values for which `is_integer` holds.
+    Distinct,
+    /// Every number becomes a Lua float. Integer targets accept any number
+    /// with an exact integer value.
+    Float,
+}
+
+/// Options for `to_lua_with` and `from_lua_with`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerdeOptions {
+    pub enum_repr: EnumRepr,
+    pub none: NullRepr,
+    pub unit: NullRepr,
+    pub numbers: NumberMode,
+}
+
+impl Default for SerdeOptions {
+    fn default() -> SerdeOptions {
+        SerdeOptions {
+            enum_repr: EnumRepr::External,
+            none: NullRepr::Nil,
+            unit: NullRepr::Nil,
+            numbers: NumberMode::Distinct,
+        }
+    }
+}
+
+/// Error raised while moving values between serde and the Lua stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub message: String,
+    /// Keys leading to the failing value, outermost first.
+    pub path: Vec<PathSegment>,
+}
+
+impl Error {
+    fn new(message: impl Into<String>) -> Error {
+        Error { message: message.into(), path: Vec::new() }
+    }
+
+    fn within(mut self, segment: PathSegment) -> Error {
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{} at '{}'", self.message, format_path(&self.path))
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error { Error::new(msg.to_string()) }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error { Error::new(msg.to_string()) }
+}
+
+/// [-0, +1, -] Pushes the `NULL` light userdata used by `NullRepr::Sentinel`.
+#[inline]
+pub fn push_null(state: &State) {
+    state.push_light_userdata(ptr::null_mut::<c_void>());
+}
+
+/// Returns `true` if the value at `index` is the `NULL` sentinel.
+#[inline]
+pub fn is_null(state: &State, index: Index) -> bool {
+    state.is_light_userdata(index) && state.to_userdata(index).is_null()
+}
+
+/// [-0, +1, m] Pushes `value` with the default options.
+pub fn to_lua<T: Serialize + ?Sized>(state: &State, value: &T) -> Result<(), Error> {
+    to_lua_with(state, value, &SerdeOptions::default())
+}
+
+/// [-0, +1, m] Pushes `value`. On error nothing is pushed.
+pub fn to_lua_with<T: Serialize + ?Sized>(state: &State, value: &T, options: &SerdeOptions) -> Result<(), Error> {
+    if !state.check_stack(1) { return Err(Error::new("not enough stack space")); }
+    let top = state.get_top();
+    let result = value.serialize(Serializer::new(state, options));
+    if result.is_err() { state.set_top(top); }
+    result
+}
+
+/// [-0, +0, m] Reads the value at `index` with the default options.
+pub fn from_lua<T: DeserializeOwned>(state: &State, index: Index) -> Result<T, Error> {
+    from_lua_with(state, index, &SerdeOptions::default())
+}
+
+/// [-0, +0, m] Reads the value at `index`.
+pub fn from_lua_with<T: DeserializeOwned>(state: &State, index: Index, options: &SerdeOptions) -> Result<T, Error> {
+    let top = state.get_top();
+    let result = T::deserialize(Deserializer::new(state, index, options));
+    state.set_top(top);
+    result
+}
+
+/// Wrapper that converts `T` through serde with the default options.
+///
+/// `ToLua` raises a Lua error if serialization fails, so it should only be
+/// used from native functions or protected calls.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: Serialize> ToLua for Serde<T> {
+    fn to_lua(self, state: &State) {
+        if let Err(e) = to_lua(state, &self.0) { state.raise(e); }
+    }
+}
+
+impl<T: DeserializeOwned> FromLua for Serde<T> {
+    fn from_lua(state: &State, index: Index) -> Option<Serde<T>> {
+        from_lua(state, index).ok().map(Serde)
+    }
+}
+
+//===========================================================================
+// Serializer
+//===========================================================================
+/// Serializes a value by pushing it onto the stack.
+#[derive(Clone, Copy)]
+pub struct Serializer<'a> {
+    state: &'a State,
+    options: &'a SerdeOptions,
+    /// The number of tables the value is nested in.
+    depth: usize,
+}
+
+impl<'a> Serializer<'a> {
+    pub fn new(state: &'a State, options: &'a SerdeOptions) -> Serializer<'a> {
+        Serializer { state, options, depth: 0 }
+    }
+
+    /// The serializer for the content of a new table. Each table needs at most
+    /// four slots: an outer table for a variant, the table, a key and a value.
+    fn nested(&self) -> Result<Serializer<'a>, Error> {
+        if self.depth >= MAX_DEPTH || !self.state.check_stack(4) {
+            return Err(Error::new("table nested too deeply"));
+        }
+        Ok(Serializer { depth: self.depth + 1, ..*self })
+    }
+
+    fn push_null(&self, repr: NullRepr) {
+        match repr {
+            NullRepr::Nil => self.state.push_nil(),
+            NullRepr::Sentinel => push_null(self.state),
+        }
+    }
+
+    fn push_integer(&self, i: Option<lua_Integer>, f: lua_Number) -> Result<(), Error> {
+        match (self.options.numbers, i) {
+            (NumberMode::Distinct, Some(i)) => self.state.push_integer(i),
+            (NumberMode::Distinct, None) => return Err(Error::new(format!("integer {} out of range", f))),
+            (NumberMode::Float, _) => self.state.push_number(f),
+        }
+        Ok(())
+    }
+
+    /// Starts a table that receives the content of `variant`. For externally
+    /// and adjacently tagged enums the table is wrapped by an outer one.
+    fn begin_variant(&self, variant: &'static str, narr: c_int, nrec: c_int, is_struct: bool) -> Result<SerializeTable<'a>, Error> {
+        let state = self.state;
+        match &self.options.enum_repr {
+            EnumRepr::External => {
+                let ser = self.nested()?;
+                let outer = state.table(0, 1).0.index;
+                state.create_table(narr, nrec);
+                Ok(SerializeTable::wrapped(ser, outer, variant.to_string()))
+            }
+            EnumRepr::Adjacent { tag, content } => {
+                let ser = self.nested()?;
+                let t = state.table(0, 2);
+                set_tag(state, t.0.index, tag, variant);
+                state.create_table(narr, nrec);
+                Ok(SerializeTable::wrapped(ser, t.0.index, content.clone()))
+            }
+            EnumRepr::Internal { tag } if is_struct => {
+                let ser = self.nested()?;
+                let t = state.table(0, nrec + 1);
+                set_tag(state, t.0.index, tag, variant);
+                Ok(SerializeTable::new(ser, t.0.index))
+            }
+            EnumRepr::Internal { .. } => {
+                Err(Error::new(format!("tuple variant {} cannot be internally tagged", variant)))
+            }
+        }
+    }
+}
+
+macro_rules! serialize_integer {
+    ($($f:ident $t:ty)*) => {
+        $(
+        fn $f(self, v: $t) -> Result<(), Error> {
+            self.push_integer(std::convert::TryFrom::try_from(v).ok(), v as lua_Number)
+        }
+        )*
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = SerializeTable<'a>;
+    type SerializeTuple = SerializeTable<'a>;
+    type SerializeTupleStruct = SerializeTable<'a>;
+    type SerializeTupleVariant = SerializeTable<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.state.push_bool(v); Ok(())
+    }
+
+    serialize_integer!(
+        serialize_i8 i8 serialize_i16 i16 serialize_i32 i32 serialize_i64 i64
+        serialize_u8 u8 serialize_u16 u16 serialize_u32 u32 serialize_u64 u64
+    );
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.state.push_number(v as lua_Number); Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.state.push_number(v); Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.state.push_string(v.encode_utf8(&mut [0; 4])); Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.state.push_string(v); Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.state.push_bytes(v); Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.push_null(self.options.none); Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.push_null(self.options.unit); Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), Error> {
+        match &self.options.enum_repr {
+            EnumRepr::External => self.state.push_string(variant),
+            EnumRepr::Internal { tag } | EnumRepr::Adjacent { tag, .. } => {
+                self.nested()?;
+                let t = self.state.table(0, 1);
+                set_tag(self.state, t.0.index, tag, variant);
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<(), Error> {
+        let state = self.state;
+        match &self.options.enum_repr {
+            EnumRepr::External => {
+                let ser = self.nested()?;
+                let t = state.table(0, 1);
+                value.serialize(ser)?;
+                set_key(state, t.0.index, variant);
+            }
+            EnumRepr::Adjacent { tag, content } => {
+                let ser = self.nested()?;
+                let t = state.table(0, 2);
+                set_tag(state, t.0.index, tag, variant);
+                value.serialize(ser)?;
+                set_key(state, t.0.index, content);
+            }
+            EnumRepr::Internal { tag } => {
+                value.serialize(self)?;
+                if !state.is_table(-1) {
+                    return Err(Error::new(format!("newtype variant {} must contain a table to be internally tagged", variant)));
+                }
+                set_tag(state, state.get_top(), tag, variant);
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeTable<'a>, Error> {
+        let ser = self.nested()?;
+        let t = self.state.table(len.unwrap_or(0) as c_int, 0);
+        Ok(SerializeTable::new(ser, t.0.index))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeTable<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeTable<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize) -> Result<SerializeTable<'a>, Error> {
+        self.begin_variant(variant, len as c_int, 0, false)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeTable<'a>, Error> {
+        let ser = self.nested()?;
+        let t = self.state.table(0, len.unwrap_or(0) as c_int);
+        Ok(SerializeTable::new(ser, t.0.index))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeTable<'a>, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize) -> Result<SerializeTable<'a>, Error> {
+        self.begin_variant(variant, 0, len as c_int, true)
+    }
+}
+
+/// [-1, +0, -] Pops a value and stores it under `key` in the table at
+/// `table`, which must be an absolute index. The key is pushed as a Lua
+/// string, so names with NULs from `#[serde(rename)]` work too.
+fn set_key(state: &State, table: Index, key: &str) {
+    state.push_string(key);
+    state.insert(-2);
+    state.raw_set(table);
+}
+
+/// [-0, +0, -] Stores the name of `variant` under `tag`.
+fn set_tag(state: &State, table: Index, tag: &str, variant: &str) {
+    state.push_string(variant);
+    set_key(state, table, tag);
+}
+
+/// Fills the table created by `Serializer`. When `wrap` is set, the table is
+/// stored under that key of the outer table at `end`.
+pub struct SerializeTable<'a> {
+    ser: Serializer<'a>,
+    table: Index,
+    next: lua_Integer,
+    wrap: Option<(Index, String)>,
+}
+
+impl<'a> SerializeTable<'a> {
+    fn new(ser: Serializer<'a>, table: Index) -> SerializeTable<'a> {
+        SerializeTable { ser, table, next: 1, wrap: None }
+    }
+
+    fn wrapped(ser: Serializer<'a>, outer: Index, key: String) -> SerializeTable<'a> {
+        let table = ser.state.get_top();
+        SerializeTable { ser, table, next: 1, wrap: Some((outer, key)) }
+    }
+
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(self.ser).map_err(|e| e.within(PathSegment::Index(self.next)))?;
+        self.ser.state.raw_seti(self.table, self.next);
+        self.next += 1;
+        Ok(())
+    }
+
+    fn push_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(self.ser).map_err(|e| e.within(PathSegment::Key(key.to_string())))?;
+        set_key(self.ser.state, self.table, key);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Some((outer, key)) = self.wrap {
+            set_key(self.ser.state, outer, &key);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeTuple for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeTupleStruct for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeTupleVariant for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeMap for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(self.ser)?;
+        let state = self.ser.state;
+        let invalid = state.is_nil(-1) || (state.type_of(-1) == Type::Number
+            && !state.is_integer(-1) && state.to_number(-1).is_nan());
+        if invalid { Err(Error::new("map key cannot be nil or NaN")) } else { Ok(()) }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let state = self.ser.state;
+        let segment = match state.value(-1) {
+            Value::Int(i) => PathSegment::Index(i),
+            key => PathSegment::Key(key.to_string()),
+        };
+        value.serialize(self.ser).map_err(|e| e.within(segment))?;
+        state.raw_set(self.table);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeStruct for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+impl<'a> ser::SerializeStructVariant for SerializeTable<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.push_field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> { self.finish() }
+}
+
+//===========================================================================
+// Deserializer
+//===========================================================================
+/// Deserializes the value at a stack index.
+pub struct Deserializer<'a> {
+    state: &'a State,
+    index: Index,
+    options: &'a SerdeOptions,
+    /// The tables being read, outermost first.
+    path: Rc<RefCell<Vec<*const c_void>>>,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(state: &'a State, index: Index, options: &'a SerdeOptions) -> Deserializer<'a> {
+        Deserializer { state, index: state.abs_index(index), options, path: Rc::default() }
+    }
+
+    fn at(&self, index: Index) -> Deserializer<'a> {
+        Deserializer { index: self.state.abs_index(index), path: self.path.clone(), ..*self }
+    }
+
+    /// Starts reading the table at the index, which is left when the result
+    /// is dropped. Fails if the table contains itself or is nested too
+    /// deeply, or if the stack has no room for `slots` more values.
+    fn enter(&self, slots: c_int) -> Result<Entered, Error> {
+        let table = self.state.to_pointer(self.index);
+        let mut path = self.path.borrow_mut();
+        if path.contains(&table) { return Err(Error::new("recursive table")); }
+        if path.len() >= MAX_DEPTH || !self.state.check_stack(slots) {
+            return Err(Error::new("table nested too deeply"));
+        }
+        path.push(table);
+        Ok(Entered(self.path.clone()))
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        Error::new(format!("{} expected, got {}", expected, self.state.typename_at(self.index)))
+    }
+
+    fn is_null(&self) -> bool {
+        self.state.is_none_or_nil(self.index) || is_null(self.state, self.index)
+    }
+
+    fn integer(&self) -> Result<lua_Integer, Error> {
+        let state = self.state;
+        if state.type_of(self.index) != Type::Number {
+            return Err(self.unexpected("integer"));
+        }
+        if state.is_integer(self.index) {
+            return Ok(state.to_integer(self.index));
+        }
+        match self.options.numbers {
+            NumberMode::Float => state.to_integerx(self.index)
+                .ok_or_else(|| Error::new(format!("integer expected, got {}", state.to_number(self.index)))),
+            NumberMode::Distinct => Err(Error::new(format!("integer expected, got float {}", state.to_number(self.index)))),
+        }
+    }
+
+    fn tag(&self, tag: &str) -> Result<String, Error> {
+        if !self.state.is_table(self.index) { return Err(self.unexpected("table")); }
+        if !self.state.check_stack(1) { return Err(Error::new("table nested too deeply")); }
+        self.state.push_string(tag);
+        self.state.raw_get(self.index);
+        let variant = self.at(-1).str().map(str::to_string);
+        self.state.pop(1);
+        variant.map_err(|e| e.within(PathSegment::Key(tag.to_string())))
+    }
+
+    /// The string at the index, which stays on the stack while it is used.
+    fn str(&self) -> Result<&str, Error> {
+        if self.state.type_of(self.index) != Type::String {
+            return Err(self.unexpected("string"));
+        }
+        let bytes = unsafe { self.state.bytes_at(self.index) }.unwrap_or_default();
+        std::str::from_utf8(bytes).map_err(|_| Error::new("string is not valid UTF-8"))
+    }
+
+    /// The bytes of the string at the index, see `str`.
+    fn bytes(&self) -> &[u8] {
+        unsafe { self.state.bytes_at(self.index) }.unwrap_or_default()
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($f:ident $visit:ident $t:ty)*) => {
+        $(
+        fn $f<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            let i = self.integer()?;
+            let v = <$t as std::convert::TryFrom<lua_Integer>>::try_from(i)
+                .map_err(|_| Error::new(format!("integer {} out of range for {}", i, stringify!($t))))?;
+            visitor.$visit(v)
+        }
+        )*
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let state = self.state;
+        match state.type_of(self.index) {
+            Type::None | Type::Nil => visitor.visit_unit(),
+            Type::Boolean => visitor.visit_bool(state.to_bool(self.index)),
+            Type::Number if state.is_integer(self.index) => visitor.visit_i64(state.to_integer(self.index)),
+            Type::Number => visitor.visit_f64(state.to_number(self.index)),
+            Type::String => match std::str::from_utf8(self.bytes()) {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(self.bytes()),
+            },
+            Type::Table => if state.sequence_len(self.index).is_some() {
+                self.deserialize_seq(visitor)
+            } else {
+                self.deserialize_map(visitor)
+            },
+            Type::LightUserdata if is_null(state, self.index) => visitor.visit_unit(),
+            _ => Err(self.unexpected("serializable value")),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.state.is_bool(self.index) {
+            visitor.visit_bool(self.state.to_bool(self.index))
+        } else { Err(self.unexpected("boolean")) }
+    }
+
+    deserialize_integer!(
+        deserialize_i8 visit_i8 i8 deserialize_i16 visit_i16 i16
+        deserialize_i32 visit_i32 i32 deserialize_i64 visit_i64 i64
+        deserialize_u8 visit_u8 u8 deserialize_u16 visit_u16 u16
+        deserialize_u32 visit_u32 u32 deserialize_u64 visit_u64 u64
+    );
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.state.type_of(self.index) == Type::Number {
+            visitor.visit_f64(self.state.to_number(self.index))
+        } else { Err(self.unexpected("number")) }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.str()?)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.state.type_of(self.index) {
+            Type::String => visitor.visit_bytes(self.bytes()),
+            Type::Table => self.deserialize_seq(visitor),
+            _ => Err(self.unexpected("string")),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.is_null() { visitor.visit_none() } else { visitor.visit_some(self) }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.is_null() { visitor.visit_unit() } else { Err(self.unexpected("nil")) }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.state.sequence_len(self.index).ok_or_else(|| self.unexpected("sequence"))?;
+        let _entered = self.enter(2)?;
+        let mut seq = SeqAccess { de: self, next: 1, len: len as lua_Integer };
+        let value = visitor.visit_seq(&mut seq)?;
+        if seq.next <= seq.len {
+            return Err(Error::new(format!("sequence has {} trailing elements", seq.len - seq.next + 1)));
+        }
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if !self.state.is_table(self.index) { return Err(self.unexpected("table")); }
+        let _entered = self.enter(4)?;
+        self.state.push_nil();
+        let key = self.state.get_top();
+        visitor.visit_map(MapAccess { de: self, key, segment: None })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        let state = self.state;
+        match &self.options.enum_repr {
+            EnumRepr::External => match state.type_of(self.index) {
+                Type::String => visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(self.str()?)),
+                Type::Table => {
+                    if !state.check_stack(3) { return Err(Error::new("table nested too deeply")); }
+                    state.push_nil();
+                    if !state.next(self.index) { return Err(Error::new("empty table for enum")); }
+                    let variant = self.at(-2).str()?.to_string();
+                    state.push_value(-2);
+                    if state.next(self.index) { return Err(Error::new("table for enum must have exactly one key")); }
+                    let segment = PathSegment::Key(variant.clone());
+                    visitor.visit_enum(EnumAccess { variant, content: Some(self.at(-1)) })
+                        .map_err(|e| e.within(segment))
+                }
+                _ => Err(self.unexpected("string or table")),
+            },
+            EnumRepr::Adjacent { tag, content } => {
+                let variant = self.tag(tag)?;
+                state.push_string(content);
+                state.raw_get(self.index);
+                let segment = PathSegment::Key(content.clone());
+                let content = if state.is_nil(-1) { None } else { Some(self.at(-1)) };
+                visitor.visit_enum(EnumAccess { variant, content }).map_err(|e| e.within(segment))
+            }
+            EnumRepr::Internal { tag } => {
+                // The content is the tagged table itself, tag field included.
+                let variant = self.tag(tag)?;
+                visitor.visit_enum(EnumAccess { variant, content: Some(self) })
+            }
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// Leaves the table entered by `Deserializer::enter` on drop.
+struct Entered(Rc<RefCell<Vec<*const c_void>>>);
+
+impl Drop for Entered {
+    fn drop(&mut self) { self.0.borrow_mut().pop(); }
+}
+
+struct SeqAccess<'a> {
+    de: Deserializer<'a>,
+    next: lua_Integer,
+    len: lua_Integer,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for SeqAccess<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.next > self.len { return Ok(None); }
+        let state = self.de.state;
+        let top = state.get_top();
+        state.raw_geti(self.de.index, self.next);
+        let result = seed.deserialize(self.de.at(-1))
+            .map_err(|e| e.within(PathSegment::Index(self.next)));
+        state.set_top(top);
+        self.next += 1;
+        result.map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> { Some((self.len - self.next + 1) as usize) }
+}
+
+/// Walks a table with `lua_next`. The current key lives in slot `key`, its
+/// value right above it, and a copy of the key is converted above that.
+struct MapAccess<'a> {
+    de: Deserializer<'a>,
+    key: Index,
+    segment: Option<PathSegment>,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for MapAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let state = self.de.state;
+        state.set_top(self.key);
+        if !state.next(self.de.index) { return Ok(None); }
+        let segment = key_segment(state, self.key);
+        state.push_value(self.key);
+        let result = seed.deserialize(self.de.at(-1)).map_err(|e| e.within(segment.clone()));
+        self.segment = Some(segment);
+        result.map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let result = seed.deserialize(self.de.at(self.key + 1));
+        match self.segment.take() {
+            Some(segment) => result.map_err(|e| e.within(segment)),
+            None => result,
+        }
+    }
+}
+
+struct EnumAccess<'a> {
+    variant: String,
+    content: Option<Deserializer<'a>>,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = VariantAccess<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantAccess<'a>), Error> {
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant.as_str()))?;
+        Ok((variant, VariantAccess { content: self.content }))
+    }
+}
+
+struct VariantAccess<'a> {
+    content: Option<Deserializer<'a>>,
+}
+
+impl<'a> VariantAccess<'a> {
+    fn content(self) -> Result<Deserializer<'a>, Error> {
+        self.content.ok_or_else(|| Error::new("enum variant content expected"))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for VariantAccess<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> { Ok(()) }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.content()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self.content()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self.content()?, visitor)
+    }
+}
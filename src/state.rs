@@ -8,11 +8,13 @@ use std::mem::MaybeUninit;
 use std::ffi::{CString, CStr};
 use std::ops::DerefMut;
 use std::sync::Mutex;
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
 
 use libc::{c_int, c_void, c_char, size_t};
 use bitflags::*;
 
-pub type InitMetatable = fn(Table, State);
+pub type InitMetatable = fn(Table, &State);
 
 /// Arithmetic operations for `lua_arith`.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -41,27 +43,56 @@ pub enum Comparison {
     Le = LUA_OPLE as isize,
 }
 
+/// Kinds of chunks accepted by `load_from`, the `mode` of `lua_load`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Source code only (`"t"`).
+    Text,
+    /// Precompiled chunks only (`"b"`).
+    Binary,
+    /// Both (`"bt"`).
+    Both,
+}
+
+impl Mode {
+    fn as_cstr(self) -> &'static CStr {
+        let mode: &'static [u8] = match self {
+            Mode::Text => b"t\0",
+            Mode::Binary => b"b\0",
+            Mode::Both => b"bt\0",
+        };
+        CStr::from_bytes_with_nul(mode).unwrap()
+    }
+}
+
+/// Converts `bytes` to a C string, cut at the first NUL byte.
+///
+/// Used for chunk names, which Lua reads by their first character: `@` for a
+/// file name, `=` for a name displayed as is, and anything else for the
+/// source of the chunk itself.
+pub(crate) fn c_string(bytes: &[u8]) -> CString {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    CString::new(&bytes[..end]).unwrap()
+}
+
 /// Status of a Lua state.
 #[must_use]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ThreadStatus {
-    Ok = LUA_OK as isize,
-    Yield = LUA_YIELD as isize,
-    RuntimeError = LUA_ERRRUN as isize,
-    SyntaxError = LUA_ERRSYNTAX as isize,
-    MemoryError = LUA_ERRMEM as isize,
-    GcError = LUA_ERRGCMM as isize,
-    MessageHandlerError = LUA_ERRERR as isize,
-    FileError = LUA_ERRFILE as isize,
-}
-
-pub enum CallError {
-    ValueNotMatch,
-    VmError(ThreadStatus),
+    Ok,
+    Yield,
+    RuntimeError,
+    SyntaxError,
+    MemoryError,
+    GcError,
+    MessageHandlerError,
+    FileError,
+    /// A status code this crate does not know.
+    Unknown(c_int),
 }
 
 impl ThreadStatus {
-    fn from_c_int(i: c_int) -> ThreadStatus {
+    pub(crate) fn from_c_int(i: c_int) -> ThreadStatus {
         match i {
             LUA_OK => ThreadStatus::Ok,
             LUA_YIELD => ThreadStatus::Yield,
@@ -71,7 +102,7 @@ impl ThreadStatus {
             LUA_ERRGCMM => ThreadStatus::GcError,
             LUA_ERRERR => ThreadStatus::MessageHandlerError,
             LUA_ERRFILE => ThreadStatus::FileError,
-            _ => panic!("Unknown Lua error code: {}", i),
+            code => ThreadStatus::Unknown(code),
         }
     }
 
@@ -83,7 +114,8 @@ impl ThreadStatus {
                 ThreadStatus::MemoryError |
                 ThreadStatus::GcError |
                 ThreadStatus::MessageHandlerError |
-                ThreadStatus::FileError => true,
+                ThreadStatus::FileError |
+                ThreadStatus::Unknown(_) => true,
             ThreadStatus::Ok |
                 ThreadStatus::Yield => false,
         }
@@ -137,18 +169,128 @@ impl Type {
     }
 }
 
+/// An owned Lua value. Reference types are anchored in the registry, so a
+/// `Value` can be stored in Rust collections and pushed back later.
+#[derive(Clone)]
 pub enum Value {
     None,
     Nil,
     Int(LUA_INTEGER),
     Num(LUA_NUMBER),
-    Str(&'static str),
+    Str(Vec<u8>),
     Bool(bool),
-    LightUserdata,
-    Table,
-    Function,
-    Userdata,
-    Thread,
+    LightUserdata(*mut c_void),
+    Table(OwnedTable),
+    Function(OwnedFunction),
+    Userdata(OwnedRef),
+    Thread(OwnedRef),
+}
+
+impl Value {
+    /// The Lua type of this value.
+    pub fn type_of(&self) -> Type {
+        match self {
+            Value::None => Type::None,
+            Value::Nil => Type::Nil,
+            Value::Int(_) | Value::Num(_) => Type::Number,
+            Value::Str(_) => Type::String,
+            Value::Bool(_) => Type::Boolean,
+            Value::LightUserdata(_) => Type::LightUserdata,
+            Value::Table(_) => Type::Table,
+            Value::Function(_) => Type::Function,
+            Value::Userdata(_) => Type::Userdata,
+            Value::Thread(_) => Type::Thread,
+        }
+    }
+
+    /// Returns `true` for `None` and `Nil`.
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        match self { Value::None | Value::Nil => true, _ => false }
+    }
+
+    /// The string contents, if this is a valid UTF-8 string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self { Value::Str(s) => str::from_utf8(s).ok(), _ => None }
+    }
+
+    fn as_ref(&self) -> Option<&OwnedRef> {
+        match self {
+            Value::Table(r) => Some(r),
+            Value::Function(r) => Some(r),
+            Value::Userdata(r) | Value::Thread(r) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+/// Follows `lua_rawequal`: numbers compare by value regardless of subtype, and
+/// reference types compare by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        use self::Value::*;
+        match (self, other) {
+            (None, None) | (Nil, Nil) => true,
+            (Int(a), Int(b)) => a == b,
+            (Num(a), Num(b)) => a == b,
+            (Int(i), Num(n)) | (Num(n), Int(i)) => {
+                let mut v = 0;
+                n.fract() == 0.0 && unsafe { crate::luaconf::lua_numtointeger(*n, &mut v) } == 1 && v == *i
+            }
+            (Str(a), Str(b)) => a == b,
+            (Bool(a), Bool(b)) => a == b,
+            (LightUserdata(a), LightUserdata(b)) => a == b,
+            _ => match (self.as_ref(), other.as_ref()) {
+                (Some(a), Some(b)) => self.type_of() == other.type_of() && a.to_pointer() == b.to_pointer(),
+                _ => false,
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::None => write!(f, "None"),
+            Value::Nil => write!(f, "Nil"),
+            Value::Int(i) => write!(f, "Int({})", i),
+            Value::Num(n) => write!(f, "Num({:?})", n),
+            Value::Str(s) => write!(f, "Str({:?})", String::from_utf8_lossy(s)),
+            Value::Bool(b) => write!(f, "Bool({})", b),
+            Value::LightUserdata(p) => write!(f, "LightUserdata({:p})", *p),
+            _ => write!(f, "{:?}({:p})", self.type_of(), self.as_ref().unwrap().to_pointer()),
+        }
+    }
+}
+
+/// Formats like Lua's `tostring`, without calling `__tostring`.
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::None | Value::Nil => write!(f, "nil"),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Num(n) => f.write_str(&format_number(*n)),
+            Value::Str(s) => f.write_str(&String::from_utf8_lossy(s)),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::LightUserdata(p) => write!(f, "userdata: {:p}", *p),
+            Value::Table(_) => write!(f, "table: {:p}", self.as_ref().unwrap().to_pointer()),
+            Value::Function(_) => write!(f, "function: {:p}", self.as_ref().unwrap().to_pointer()),
+            Value::Userdata(_) => write!(f, "userdata: {:p}", self.as_ref().unwrap().to_pointer()),
+            Value::Thread(_) => write!(f, "thread: {:p}", self.as_ref().unwrap().to_pointer()),
+        }
+    }
+}
+
+/// Formats a float with `LUAI_NUMFFORMAT` (`%.14g`), adding `.0` to integral
+/// values the way `lua_Number2str` callers do.
+fn format_number(n: LUA_NUMBER) -> String {
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        libc::snprintf(buf.as_mut_ptr() as *mut c_char, buf.len(), b"%.14g\0".as_ptr() as *const c_char, n)
+    };
+    let mut s = String::from_utf8_lossy(&buf[..len as usize]).into_owned();
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) { s.push_str(".0"); }
+    s
 }
 
 /// Represents all built-in libraries
@@ -212,7 +354,7 @@ pub struct Reference(c_int);
 pub const REFNIL: Reference = Reference(LUA_REFNIL);
 
 /// A value that will never be returned by `reference`.
-pub const NOREF: Reference = Reference(LUA_REFNIL);
+pub const NOREF: Reference = Reference(LUA_NOREF);
 
 impl Reference {
     /// Returns `true` if this reference is equal to `REFNIL`.
@@ -246,89 +388,48 @@ bitflags! {
     }
 }
 
-unsafe extern fn continue_func<F>(st: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int
-where F: FnOnce(&mut State, ThreadStatus) -> c_int
-{
-    mem::transmute::<_, Box<F>>(ctx)(&mut State::from_ptr(st), ThreadStatus::from_c_int(status))
+/// A continuation passed to `callk`, `pcallk` or `co_yieldk`. It lives in a
+/// userdata anchored to the running coroutine until it runs, so that it is
+/// dropped with the coroutine if it never does.
+struct Continuation(Option<Box<dyn FnOnce(&State, ThreadStatus) -> c_int>>);
+
+static CONTINUATIONS_KEY: u8 = 0;
+
+unsafe extern "C" fn continue_func(l: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int {
+    let state = State::from_ptr(l);
+    catch_panic(l, || state.take_continuation(ctx)(&state, ThreadStatus::from_c_int(status)))
 }
 
 /// Box for extra data.
 pub type Extra = Box<dyn any::Any + 'static + Send>;
-type ExtraHolder = *mut *mut Mutex<Option<Extra>>;
-
-unsafe extern fn alloc_func(_: *mut c_void, ptr: *mut c_void, old_size: size_t, new_size: size_t) -> *mut c_void {
-    // In GCC and MSVC, malloc uses an alignment calculated roughly by:
-    //   max(2 * sizeof(size_t), alignof(long double))
-    // The stable high-level API used here does not expose alignment directly, so
-    // we get as close as possible by using usize to determine alignment. Lua
-    // seems unlikely to require 16-byte alignment for any of its purposes.
-
-    #[inline]
-    fn divide_size(size: size_t) -> usize {
-        1 + (size - 1) / mem::size_of::<usize>()
-    }
-
-    let ptr = ptr as *mut usize;
-    if new_size == 0 {
-        // if new_size is 0, act like free()
-        if !ptr.is_null() {
-            // Lua promises to provide the correct old_size
-            drop(Vec::<usize>::from_raw_parts(ptr, 0, divide_size(old_size)));
-        }
-        ptr::null_mut()
-    } else {
-        // otherwise, act like realloc()
-        let mut vec;
-        if ptr.is_null() {
-            // old_size is a type indicator, not used here
-            vec = Vec::<usize>::with_capacity(divide_size(new_size));
-        } else {
-            // Lua promises to provide the correct old_size
-            if new_size > old_size {
-                // resulting capacity should be new_size
-                vec = Vec::<usize>::from_raw_parts(ptr, 0, divide_size(old_size));
-                vec.reserve_exact(divide_size(new_size));
-            } else {
-                // Lua assumes this will never fail
-                vec = Vec::<usize>::from_raw_parts(ptr, divide_size(new_size), divide_size(old_size));
-                vec.shrink_to_fit();
-            }
-        }
-        let res = vec.as_mut_ptr();
-        mem::forget(vec); // don't deallocate
-        res as *mut c_void
-    }
-}
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[repr(C)]
+/// A handle to a `lua_State`. It is neither `Copy` nor `Send`: code gets a
+/// `&State` from a `Lua`, a `StateRef` or a `ValRef`, and cannot keep it
+/// past their lifetime.
+#[derive(Debug, PartialEq, Eq)]
+#[repr(transparent)]
 pub struct State(*mut lua_State);
 
-unsafe impl Send for State {}
-
 impl State {
-    /// Initializes a new Lua state. This function does not open any libraries
-    /// by default. Calls `lua_newstate` internally.
-    pub fn new() -> State {
-        unsafe { State(luaL_newstate()) }
-    }
-
     /// Constructs a wrapper `State` from a raw pointer. This is suitable for use
     /// inside of native functions that accept a `lua_State` to obtain a wrapper.
     #[inline(always)]
     pub unsafe fn from_ptr(L: *mut lua_State) -> State { State(L) }
 
+    /// Views a stored pointer as a `State`, for the `Copy` handles of the
+    /// crate.
+    #[inline(always)]
+    pub(crate) fn from_ref(l: &*mut lua_State) -> &State {
+        unsafe { &*(l as *const *mut lua_State as *const State) }
+    }
+
     /// Returns an unsafe pointer to the wrapped `lua_State`.
     pub fn as_ptr(&self) -> *mut lua_State { self.0 }
 
-    /// Maps to `luaL_openlibs`.
-    pub fn open_libs(&self) {
-        unsafe { luaL_openlibs(self.0) }
-        // Init ulua
-        use crate::{thread, global};
-
-        self.load_global();
-        self.open_thread();
+    /// Returns a borrowed handle that cannot outlive this one.
+    #[inline]
+    pub fn borrowed(&self) -> StateRef<'_> {
+        unsafe { StateRef::from_ptr(self.0) }
     }
 
     #[inline]
@@ -368,7 +469,7 @@ impl State {
 
     /// Maps to `luaopen_table`.
     #[inline]
-    pub fn open_table(&mut self) -> c_int {
+    pub fn open_table(&self) -> c_int {
         unsafe { luaopen_table(self.0) }
     }
 
@@ -386,7 +487,7 @@ impl State {
 
     /// Maps to `luaopen_string`.
     #[inline]
-    pub fn open_string(&mut self) -> c_int {
+    pub fn open_string(&self) -> c_int {
         unsafe { luaopen_string(self.0) }
     }
 
@@ -414,22 +515,22 @@ impl State {
         unsafe { luaopen_package(self.0) }
     }
 
-    /// Maps to `luaL_dofile`.
-    pub fn do_file(&self, filename: &str) -> ThreadStatus {
-        let c_str = CString::new(filename).unwrap();
-        let result = unsafe {
-            luaL_dofile(self.0, c_str.as_ptr())
-        };
-        ThreadStatus::from_c_int(result)
+    /// Like `luaL_dofile`, but runs the chunk with `protected_call`. The
+    /// results are left on the stack.
+    pub fn do_file(&self, filename: &str) -> Result<(), LuaError> {
+        match self.load_file(filename) {
+            ThreadStatus::Ok => self.protected_call(0, LUA_MULTRET),
+            status => Err(self.pop_error(status)),
+        }
     }
 
-    /// Maps to `luaL_dostring`.
-    pub fn do_string(&self, s: &str) -> ThreadStatus {
-        let c_str = CString::new(s).unwrap();
-        let result = unsafe {
-            luaL_dostring(self.0, c_str.as_ptr())
-        };
-        ThreadStatus::from_c_int(result)
+    /// Like `luaL_dostring`, but runs the chunk with `protected_call`. The
+    /// results are left on the stack.
+    pub fn do_string(&self, s: &str) -> Result<(), LuaError> {
+        match self.load_string(s) {
+            ThreadStatus::Ok => self.protected_call(0, LUA_MULTRET),
+            status => Err(self.pop_error(status)),
+        }
     }
 
     /// Pushes the given value onto the stack.
@@ -441,17 +542,11 @@ impl State {
     //===========================================================================
     // State manipulation
     //===========================================================================
-    /// Maps to `lua_close`.
-    #[inline]
-    pub fn close(self) {
-        unsafe { lua_close(self.0); }
-    }
-
     /// [-0, +1, m] Maps to `lua_newthread`.
     #[inline]
-    pub fn new_thread(&self) -> State {
+    pub fn new_thread(&self) -> StateRef<'_> {
         unsafe {
-            State::from_ptr(lua_newthread(self.0))
+            StateRef::from_ptr(lua_newthread(self.0))
         }
     }
 
@@ -499,26 +594,26 @@ impl State {
 
     /// Maps to `lua_rotate`.
     #[inline]
-    pub fn rotate(&mut self, idx: Index, n: c_int) {
+    pub fn rotate(&self, idx: Index, n: c_int) {
         unsafe { lua_rotate(self.0, idx, n) }
     }
 
     /// Maps to `lua_copy`.
     #[inline]
-    pub fn copy(&mut self, from_idx: Index, to_idx: Index) {
+    pub fn copy(&self, from_idx: Index, to_idx: Index) {
         unsafe { lua_copy(self.0, from_idx, to_idx) }
     }
 
     /// Maps to `lua_checkstack`.
     #[inline]
-    pub fn check_stack(&mut self, extra: c_int) -> bool {
+    pub fn check_stack(&self, extra: c_int) -> bool {
         let result = unsafe { lua_checkstack(self.0, extra) };
         result != 0
     }
 
     /// Maps to `lua_xmove`.
     #[inline]
-    pub fn xmove(&self, to: State, n: c_int) {
+    pub fn xmove(&self, to: &State, n: c_int) {
         unsafe { lua_xmove(self.0, to.0, n) }
     }
 
@@ -629,12 +724,12 @@ impl State {
 
     /// Maps to `lua_tothread`.
     #[inline]
-    pub fn to_thread(&self, index: Index) -> Option<State> {
+    pub fn to_thread(&self, index: Index) -> Option<StateRef<'_>> {
         let state = unsafe { lua_tothread(self.0, index) };
         if state.is_null() {
             None
         } else {
-            Some(unsafe { State::from_ptr(state) })
+            Some(unsafe { StateRef::from_ptr(state) })
         }
     }
 
@@ -736,7 +831,7 @@ impl State {
     // Get functions (Lua -> stack)
     //===========================================================================
     /// Maps to `lua_getglobal`.
-    pub fn get_global(&mut self, name: &str) -> Type {
+    pub fn get_global(&self, name: &str) -> Type {
         let c_str = CString::new(name).unwrap();
         let ty = unsafe {
             lua_getglobal(self.0, c_str.as_ptr())
@@ -745,7 +840,7 @@ impl State {
     }
 
     /// Maps to `lua_gettable`.
-    pub fn get_table(&mut self, index: Index) -> Type {
+    pub fn get_table(&self, index: Index) -> Type {
         let ty = unsafe { lua_gettable(self.0, index) };
         Type::from_c_int(ty)
     }
@@ -802,17 +897,19 @@ impl State {
     ///
     /// # Example
     ///
-    /// ```ignore
-    /// unsafe { *state.new_userdata_typed() = MyStruct::new(...); }
+    /// ```
+    /// # let state = macro_lua::Lua::new();
+    /// # struct MyStruct { x: i32 }
+    /// unsafe { state.new_userdata_typed::<MyStruct>().write(MyStruct { x: 1 }); }
     /// state.set_metatable_from_registry("MyStruct");
     /// ```
     //#[unstable(reason="this is an experimental function")]
-    pub fn new_userdata_typed<T>(&mut self) -> *mut T {
+    pub fn new_userdata_typed<T>(&self) -> *mut T {
         self.new_userdata(mem::size_of::<T>() as size_t) as *mut T
     }
 
     /// Maps to `lua_getmetatable`.
-    pub fn get_metatable(&mut self, objindex: Index) -> bool {
+    pub fn get_metatable(&self, objindex: Index) -> bool {
         let result = unsafe { lua_getmetatable(self.0, objindex) };
         result != 0
     }
@@ -877,36 +974,87 @@ impl State {
     //===========================================================================
     // 'load' and 'call' functions (load and run Lua code)
     //===========================================================================
-    /// Maps to `lua_callk`.
-    // pub fn callk<F>(&mut self, nargs: c_int, nresults: c_int, continuation: F)
-    //     where F: FnOnce(&mut State, ThreadStatus) -> c_int
-    //     {
-    //         let func = continue_func::<F>;
-    //         unsafe {
-    //             let ctx = mem::transmute(Box::new(continuation));
-    //             lua_callk(self.0, nargs, nresults, ctx, Some(func));
-    //             // no yield occurred, so call the continuation
-    //             func(self.0, LUA_OK, ctx);
-    //         }
-    //     }
-
-    /// Maps to `lua_call`.
-    // #[inline]
-    // pub fn call(&self, nargs: c_int, nresults: c_int) {
-    //     unsafe { lua_call(self.0, nargs, nresults) }
-    // }
-
-    /// Maps to `lua_pcallk`.
-    // pub fn pcallk<F>(&mut self, nargs: c_int, nresults: c_int, msgh: c_int, continuation: F) -> c_int
-    //     where F: FnOnce(&mut State, ThreadStatus) -> c_int
-    //     {
-    //         let func = continue_func::<F>;
-    //         unsafe {
-    //             let ctx = mem::transmute(Box::new(continuation));
-    //             // lua_pcallk only returns if no yield occurs, so call the continuation
-    //             func(self.0, lua_pcallk(self.0, nargs, nresults, msgh, ctx, Some(func)), ctx)
-    //         }
-    //     }
+    /// [-0, +0, m] Stores `continuation` in a table keyed by the running
+    /// thread and returns the context that `continue_func` takes it back with.
+    fn anchor_continuation(&self, continuation: Box<dyn FnOnce(&State, ThreadStatus) -> c_int>) -> lua_KContext {
+        let mut continuation = Some(continuation);
+        self.balance_with(|s| {
+            if s.raw_getp(LUA_REGISTRYINDEX, &CONTINUATIONS_KEY) != Type::Table {
+                s.pop(1);
+                s.create_table(0, 0);
+                s.create_table(0, 1);
+                s.push_string("k");
+                s.set_field(-2, "__mode");
+                s.set_metatable(-2);
+                s.push_value(-1);
+                s.raw_setp(LUA_REGISTRYINDEX, &CONTINUATIONS_KEY);
+            }
+            s.push_thread();
+            if s.raw_get(-2) != Type::Table {
+                s.pop(1);
+                s.create_table(0, 1);
+                s.push_thread();
+                s.push_value(-2);
+                s.raw_set(-4);
+            }
+            s.push_userdata(Continuation(continuation.take()), Some(metatable!(
+                Continuation(s: State, this: Self);
+            )));
+            // the value is at the start of the userdata
+            let cell = s.to_userdata(-1) as *mut Continuation;
+            s.raw_setp(-2, cell);
+            cell as lua_KContext
+        })
+    }
+
+    /// Takes back a continuation stored by `anchor_continuation`.
+    fn take_continuation(&self, ctx: lua_KContext) -> Box<dyn FnOnce(&State, ThreadStatus) -> c_int> {
+        let cell = ctx as *mut Continuation;
+        let continuation = unsafe { (*cell).0.take() }.expect("continuation already ran");
+        self.balance_with(|s| {
+            s.raw_getp(LUA_REGISTRYINDEX, &CONTINUATIONS_KEY);
+            s.push_thread();
+            s.raw_get(-2);
+            s.push_nil();
+            s.raw_setp(-2, cell);
+        });
+        continuation
+    }
+
+    /// Maps to `lua_callk`. Calls the function below the `nargs` arguments on
+    /// top of the stack and then `continuation`, whose result the native
+    /// function should return. If the called function yields, the native
+    /// function is left and `continuation` runs when the coroutine is resumed,
+    /// with `ThreadStatus::Yield`. If the coroutine is collected before that,
+    /// `continuation` is dropped without running.
+    pub fn callk<F>(&self, nargs: c_int, nresults: c_int, continuation: F) -> c_int
+        where F: FnOnce(&State, ThreadStatus) -> c_int + 'static
+    {
+        if !self.is_yieldable() {
+            unsafe { lua_callk(self.0, nargs, nresults, 0, None) };
+            return continuation(self, ThreadStatus::Ok);
+        }
+        let ctx = self.anchor_continuation(Box::new(continuation));
+        unsafe { lua_callk(self.0, nargs, nresults, ctx, Some(continue_func)) };
+        // no yield occurred, so call the continuation
+        self.take_continuation(ctx)(self, ThreadStatus::Ok)
+    }
+
+    /// Maps to `lua_pcallk`. Same as `callk`, in protected mode: on error the
+    /// error value is left on the stack and `continuation` gets the error
+    /// status, whether or not the called function yielded before.
+    pub fn pcallk<F>(&self, nargs: c_int, nresults: c_int, msgh: c_int, continuation: F) -> c_int
+        where F: FnOnce(&State, ThreadStatus) -> c_int + 'static
+    {
+        if !self.is_yieldable() {
+            let status = unsafe { lua_pcallk(self.0, nargs, nresults, msgh, 0, None) };
+            return continuation(self, ThreadStatus::from_c_int(status));
+        }
+        let ctx = self.anchor_continuation(Box::new(continuation));
+        // lua_pcallk only returns if no yield occurs, so call the continuation
+        let status = unsafe { lua_pcallk(self.0, nargs, nresults, msgh, ctx, Some(continue_func)) };
+        self.take_continuation(ctx)(self, ThreadStatus::from_c_int(status))
+    }
 
     /// Maps to `lua_pcall`.
     #[inline]
@@ -917,61 +1065,131 @@ impl State {
         ThreadStatus::from_c_int(result)
     }
 
-    // TODO: mode typing?
-    /// Maps to `lua_load`.
-    // pub fn load<'l, F>(&'l mut self, mut reader: F, source: &str, mode: &str) -> ThreadStatus
-    //     where F: FnMut(&mut State) -> &'l [u8]
-    //     {
-    //         unsafe extern fn read<'l, F>(st: *mut lua_State, ud: *mut c_void, sz: *mut size_t) -> *const c_char
-    //             where F: FnMut(&mut State) -> &'l [u8]
-    //             {
-    //                 let mut state = State::from_ptr(st);
-    //                 let slice = mem::transmute::<_, &mut F>(ud)(&mut state);
-    //                 *sz = slice.len() as size_t;
-    //                 slice.as_ptr() as *const _
-    //             }
-    //         let source_c_str = CString::new(source).unwrap();
-    //         let mode_c_str = CString::new(mode).unwrap();
-    //         let result = unsafe {
-    //             lua_load(self.0, Some(read::<F>), mem::transmute(&mut reader), source_c_str.as_ptr(), mode_c_str.as_ptr())
-    //         };
-    //         ThreadStatus::from_c_int(result)
-    //     }
-
-    // returns isize because the return value is dependent on the writer - seems to
-    // be usable for anything
-    /// Maps to `lua_dump`.
-    // pub fn dump<F>(&mut self, mut writer: F, strip: bool) -> c_int
-    //     where F: FnMut(&mut State, &[u8]) -> c_int
-    //     {
-    //         unsafe extern fn write<F>(st: *mut lua_State, p: *const c_void, sz: size_t, ud: *mut c_void) -> c_int
-    //             where F: FnMut(&mut State, &[u8]) -> c_int
-    //             {
-    //                 mem::transmute::<_, &mut F>(ud)(&mut State::from_ptr(st), slice::from_raw_parts(p as *const _, sz as usize))
-    //             }
-    //         unsafe { lua_dump(self.0, Some(write::<F>), mem::transmute(&mut writer), strip as c_int) }
-    //     }
+    /// [-0, +1, -] Maps to `lua_load`. Loads a chunk streamed from `reader`
+    /// and pushes it as a function. An I/O error of `reader` is returned as
+    /// `LuaError::File`.
+    pub fn load_from<R: Read>(&self, reader: R, chunk_name: &str, mode: Mode) -> Result<ValRef<'_>, LuaError> {
+        struct Reader<R> {
+            reader: R,
+            buffer: Vec<u8>,
+            error: Option<io::Error>,
+            panic: Option<Box<dyn any::Any + Send>>,
+        }
+
+        unsafe extern "C" fn read<R: Read>(_: *mut lua_State, ud: *mut c_void, size: *mut size_t) -> *const c_char {
+            let r = &mut *(ud as *mut Reader<R>);
+            let result = loop {
+                match panic::catch_unwind(AssertUnwindSafe(|| r.reader.read(&mut r.buffer))) {
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(Ok(n)) => break n,
+                    Ok(Err(e)) => { r.error = Some(e); break 0; }
+                    Err(payload) => { r.panic = Some(payload); break 0; }
+                }
+            };
+            *size = result as size_t;
+            r.buffer.as_ptr() as *const c_char
+        }
+
+        let mut reader = Reader { reader, buffer: vec![0; 8192], error: None, panic: None };
+        let name = chunk_name.trim_start_matches(['@', '=']);
+        let chunk_name = c_string(chunk_name.as_bytes());
+        let status = unsafe {
+            lua_load(self.0, Some(read::<R>), &mut reader as *mut Reader<R> as *mut c_void, chunk_name.as_ptr(), mode.as_cstr().as_ptr())
+        };
+        if let Some(payload) = reader.panic {
+            self.pop(1);
+            panic::resume_unwind(payload);
+        }
+        if let Some(e) = reader.error {
+            self.pop(1);
+            return Err(LuaError::File(format!("cannot read {}: {}", name, e)));
+        }
+        match status {
+            LUA_OK => Ok(self.val(-1)),
+            err => Err(self.pop_error(ThreadStatus::from_c_int(err))),
+        }
+    }
+
+    /// [-0, +0, -] Maps to `lua_dump`. Writes the function at `index` as a
+    /// precompiled chunk to `writer`, without debug information if `strip`.
+    /// Fails with `io::ErrorKind::InvalidInput` if it is not a Lua function.
+    pub fn dump_to<W: Write>(&self, index: Index, writer: W, strip: bool) -> io::Result<()> {
+        struct Writer<W> {
+            writer: W,
+            error: Option<io::Error>,
+            panic: Option<Box<dyn any::Any + Send>>,
+        }
+
+        unsafe extern "C" fn write<W: Write>(_: *mut lua_State, p: *const c_void, size: size_t, ud: *mut c_void) -> c_int {
+            let w = &mut *(ud as *mut Writer<W>);
+            let bytes = slice::from_raw_parts(p as *const u8, size);
+            match panic::catch_unwind(AssertUnwindSafe(|| w.writer.write_all(bytes))) {
+                Ok(Ok(())) => return 0,
+                Ok(Err(e)) => w.error = Some(e),
+                Err(payload) => w.panic = Some(payload),
+            }
+            1
+        }
+
+        if self.type_of(index) != Type::Function || self.is_native_fn(index) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "unable to dump given function"));
+        }
+        let mut writer = Writer { writer, error: None, panic: None };
+        self.push_value(index);
+        unsafe { lua_dump(self.0, Some(write::<W>), &mut writer as *mut Writer<W> as *mut c_void, strip as c_int) };
+        self.pop(1);
+        if let Some(payload) = writer.panic {
+            panic::resume_unwind(payload);
+        }
+        match writer.error {
+            Some(e) => Err(e),
+            None => writer.writer.flush(),
+        }
+    }
+
+    /// [-0, +0, -] Same as `dump_to`, collecting the chunk in memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value at `index` is not a Lua function.
+    pub fn dump(&self, index: Index, strip: bool) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        if let Err(e) = self.dump_to(index, &mut chunk, strip) {
+            panic!("cannot dump {}: {}", self.describe_value(index), e);
+        }
+        chunk
+    }
 
     //===========================================================================
     // Coroutine functions
     //===========================================================================
     /// Maps to `lua_yieldk`.
-    pub fn co_yieldk<F>(&mut self, nresults: c_int, continuation: F) -> !
-        where F: FnOnce(&mut State, ThreadStatus) -> c_int
+    /// When the coroutine is resumed, `continuation` runs with the values
+    /// passed to `resume` on the stack, and its result is the number of
+    /// values the native function returns. It is dropped without running if
+    /// the coroutine is collected first.
+    pub fn co_yieldk<F>(&self, nresults: c_int, continuation: F) -> !
+        where F: FnOnce(&State, ThreadStatus) -> c_int + 'static
         {
-            unsafe { lua_yieldk(self.0, nresults, mem::transmute(Box::new(continuation)), Some(continue_func::<F>)) };
-            panic!("co_yieldk called in non-coroutine context; check is_yieldable first")
+            if !self.is_yieldable() {
+                // the error skips this frame, so the continuation is dropped first
+                drop(continuation);
+                self.co_yield(nresults)
+            }
+            let ctx = self.anchor_continuation(Box::new(continuation));
+            unsafe { lua_yieldk(self.0, nresults, ctx, Some(continue_func)) };
+            unreachable!()
         }
 
     /// Maps to `lua_yield`. This function is not called `yield` because it is a
     /// reserved keyword.
-    pub fn co_yield(&mut self, nresults: c_int) -> ! {
+    pub fn co_yield(&self, nresults: c_int) -> ! {
         unsafe { lua_yield(self.0, nresults) };
         panic!("co_yield called in non-coroutine context; check is_yieldable first")
     }
 
     /// Maps to `lua_resume`.
-    pub fn resume(&mut self, from: Option<&mut State>, nargs: c_int) -> ThreadStatus {
+    pub fn resume(&self, from: Option<&mut State>, nargs: c_int) -> ThreadStatus {
         let from_ptr = match from {
             Some(state) => state.0,
             None        => ptr::null_mut()
@@ -983,13 +1201,13 @@ impl State {
     }
 
     /// Maps to `lua_status`.
-    pub fn status(&mut self) -> ThreadStatus {
+    pub fn status(&self) -> ThreadStatus {
         let result = unsafe { lua_status(self.0) };
         ThreadStatus::from_c_int(result)
     }
 
     /// Maps to `lua_isyieldable`.
-    pub fn is_yieldable(&mut self) -> bool {
+    pub fn is_yieldable(&self) -> bool {
         let result = unsafe { lua_isyieldable(self.0) };
         result != 0
     }
@@ -1032,20 +1250,20 @@ impl State {
     }
 
     /// Maps to `lua_stringtonumber`.
-    pub fn string_to_number(&mut self, s: &str) -> size_t {
+    pub fn string_to_number(&self, s: &str) -> size_t {
         let c_str = CString::new(s).unwrap();
         unsafe { lua_stringtonumber(self.0, c_str.as_ptr()) }
     }
 
     /// Maps to `lua_getallocf`.
-    pub fn get_alloc_fn(&mut self) -> (lua_Alloc, *mut c_void) {
+    pub fn get_alloc_fn(&self) -> (lua_Alloc, *mut c_void) {
         let mut slot = ptr::null_mut();
         (unsafe { lua_getallocf(self.0, &mut slot) }, slot)
     }
 
     /// Maps to `lua_setallocf`.
     #[inline]
-    pub fn set_alloc_fn(&mut self, f: lua_Alloc, ud: *mut c_void) {
+    pub fn set_alloc_fn(&self, f: lua_Alloc, ud: *mut c_void) {
         unsafe { lua_setallocf(self.0, f, ud) }
     }
 
@@ -1054,23 +1272,16 @@ impl State {
     //===========================================================================
 
     /// Set extra data. Return previous value if it was set.
-    pub fn set_extra(&mut self, extra: Option<Extra>) -> Option<Extra> {
+    pub fn set_extra(&self, extra: Option<Extra>) -> Option<Extra> {
         self.with_extra(|opt_extra| mem::replace(opt_extra, extra))
     }
 
-    /// Do some actions with mutable extra.
-    pub fn with_extra<F, R>(&mut self, closure: F) -> R
+    /// Do some actions with mutable extra. The extra data is shared by all
+    /// threads of the state.
+    pub fn with_extra<F, R>(&self, closure: F) -> R
         where F: FnOnce(&mut Option<Extra>) -> R {
-            unsafe {
-                let extra_ptr = lua_getextraspace(self.0) as ExtraHolder;
-                let mutex = Box::from_raw(*extra_ptr);
-                let result = {
-                    let mut guard = mutex.lock().unwrap();
-                    closure(guard.deref_mut())
-                };
-                mem::forget(mutex);
-                result
-            }
+            let mut guard = crate::ulua::shared(self.0).extra.lock().unwrap();
+            closure(guard.deref_mut())
         }
 
     /// Unwrap and downcast extra to typed.
@@ -1079,7 +1290,7 @@ impl State {
     ///
     /// Panics if state has no attached `Extra` or it's impossible to downcast to `T`.
     ///
-    pub fn with_extra_typed<T, F, R>(&mut self, closure: F) -> R
+    pub fn with_extra_typed<T, F, R>(&self, closure: F) -> R
         where T: any::Any, F: FnOnce(&mut T) -> R {
             self.with_extra(|extra| {
                 let data = extra.as_mut().unwrap()
@@ -1126,7 +1337,7 @@ impl State {
 
     /// Maps to `lua_isfunction`.
     #[inline]
-    pub fn is_fn(&mut self, index: Index) -> bool {
+    pub fn is_fn(&self, index: Index) -> bool {
         unsafe { lua_isfunction(self.0, index) == 1 }
     }
 
@@ -1303,7 +1514,7 @@ impl State {
     }
 
     /// Maps to `luaL_getmetafield`.
-    pub fn get_metafield(&mut self, obj: Index, e: &str) -> bool {
+    pub fn get_metafield(&self, obj: Index, e: &str) -> bool {
         let c_str = CString::new(e).unwrap();
         let result = unsafe {
             luaL_getmetafield(self.0, obj, c_str.as_ptr())
@@ -1312,7 +1523,7 @@ impl State {
     }
 
     /// Maps to `luaL_callmeta`.
-    pub fn call_meta(&mut self, obj: Index, e: &str) -> bool {
+    pub fn call_meta(&self, obj: Index, e: &str) -> bool {
         let c_str = CString::new(e).unwrap();
         let result = unsafe {
             luaL_callmeta(self.0, obj, c_str.as_ptr())
@@ -1332,40 +1543,37 @@ impl State {
         unsafe { lua_tolstring(self.0, index, size as *mut usize) }
     }
 
-    /// Maps to `luaL_tolstring`. This function is not called `to_string` because
-    /// that method name is used for the `ToString` trait. This function returns
-    /// a reference to the string at the given index, on which `to_owned` may be
-    /// called.
-    pub fn to_str(&self, index: Index) -> Option<&'static str> {
-        let mut len = 0;
-        let ptr = self.tolstring(index, &mut len);
-        if ptr.is_null() { None } else {
-            unsafe {
-                let s = slice::from_raw_parts(ptr as *const u8, len as usize);
-                Some(str::from_utf8_unchecked(s))
-            }
-        }
+    /// Maps to `lua_tolstring` and copies the result. Returns `None` if the
+    /// value is neither a string nor a number, or is not valid UTF-8. Numbers
+    /// are converted in place, like `lua_tolstring` does. Use `LuaString` to
+    /// read a string without copying it.
+    pub fn to_str(&self, index: Index) -> Option<String> {
+        let bytes = unsafe { self.bytes_at(index)? };
+        str::from_utf8(bytes).ok().map(str::to_owned)
+    }
+
+    /// Maps to `lua_tolstring`, but allows arbitrary bytes. The string is
+    /// copied, see `to_str`.
+    pub fn to_bytes(&self, index: Index) -> Option<Vec<u8>> {
+        unsafe { self.bytes_at(index) }.map(<[u8]>::to_vec)
     }
 
-    /// Maps to `lua_tolstring`, but allows arbitrary bytes.
-    /// This function returns a reference to the string at the given index,
-    /// on which `to_owned` may be called.
-    pub fn to_bytes(&self, index: Index) -> Option<&'static [u8]> {
+    /// The bytes of the string at `index`, or `None` if the value is neither a
+    /// string nor a number. The caller chooses the lifetime, and must not use
+    /// the slice once the value may have left the stack.
+    #[inline]
+    pub(crate) unsafe fn bytes_at<'a>(&self, index: Index) -> Option<&'a [u8]> {
         let mut len = 0;
-        let ptr = unsafe { lua_tolstring(self.0, index, &mut len) };
-        if ptr.is_null() {
-            None
-        } else {
-            let slice = unsafe { slice::from_raw_parts(ptr as *const u8, len as usize) };
-            Some(slice)
-        }
+        let ptr = lua_tolstring(self.0, index, &mut len);
+        if ptr.is_null() { None } else { Some(slice::from_raw_parts(ptr as *const u8, len)) }
     }
 
     /// Maps to `luaL_argerror`.
     pub fn arg_error(&self, arg: Index, extramsg: &str) -> ! {
-        // nb: leaks the CString
-        let c_str = CString::new(extramsg).unwrap();
-        unsafe { luaL_argerror(self.0, arg, c_str.as_ptr()) };
+        // the message is copied into a Lua string first, nothing Rust-owned
+        // is left for the error to skip over
+        self.push_string(extramsg);
+        unsafe { luaL_argerror(self.0, arg, lua_tolstring(self.0, -1, ptr::null_mut())) };
         unreachable!()
     }
 
@@ -1373,27 +1581,27 @@ impl State {
     // omitted: luaL_optstring
 
     /// Maps to `luaL_checknumber`.
-    pub fn check_number(&mut self, arg: Index) -> lua_Number {
+    pub fn check_number(&self, arg: Index) -> lua_Number {
         unsafe { luaL_checknumber(self.0, arg) }
     }
 
     /// Maps to `luaL_optnumber`.
-    pub fn opt_number(&mut self, arg: Index, def: lua_Number) -> lua_Number {
+    pub fn opt_number(&self, arg: Index, def: lua_Number) -> lua_Number {
         unsafe { luaL_optnumber(self.0, arg, def) }
     }
 
     /// Maps to `luaL_checkinteger`.
-    pub fn check_integer(&mut self, arg: Index) -> lua_Integer {
+    pub fn check_integer(&self, arg: Index) -> lua_Integer {
         unsafe { luaL_checkinteger(self.0, arg) }
     }
 
     /// Maps to `luaL_optinteger`.
-    pub fn opt_integer(&mut self, arg: Index, def: lua_Integer) -> lua_Integer {
+    pub fn opt_integer(&self, arg: Index, def: lua_Integer) -> lua_Integer {
         unsafe { luaL_optinteger(self.0, arg, def) }
     }
 
     /// Maps to `luaL_checkstack`.
-    pub fn check_stack_msg(&mut self, sz: c_int, msg: &str) {
+    pub fn check_stack_msg(&self, sz: c_int, msg: &str) {
         let c_str = CString::new(msg).unwrap();
         unsafe { luaL_checkstack(self.0, sz, c_str.as_ptr()) }
     }
@@ -1404,12 +1612,12 @@ impl State {
     }
 
     /// Maps to `luaL_checkany`.
-    pub fn check_any(&mut self, arg: Index) {
+    pub fn check_any(&self, arg: Index) {
         unsafe { luaL_checkany(self.0, arg) }
     }
 
     /// Maps to `luaL_newmetatable`.
-    pub fn new_metatable(&mut self, tname: &str) -> bool {
+    pub fn new_metatable(&self, tname: &str) -> bool {
         let c_str = CString::new(tname).unwrap();
         let result = unsafe {
             luaL_newmetatable(self.0, c_str.as_ptr())
@@ -1418,13 +1626,13 @@ impl State {
     }
 
     /// Maps to `luaL_setmetatable`.
-    pub fn set_metatable_from_registry(&mut self, tname: &str) {
+    pub fn set_metatable_from_registry(&self, tname: &str) {
         let c_str = CString::new(tname).unwrap();
         unsafe { luaL_setmetatable(self.0, c_str.as_ptr()) }
     }
 
     /// Maps to `luaL_testudata`.
-    pub fn test_userdata(&mut self, arg: Index, tname: &str) -> *mut c_void {
+    pub fn test_userdata(&self, arg: Index, tname: &str) -> *mut c_void {
         let c_str = CString::new(tname).unwrap();
         unsafe { luaL_testudata(self.0, arg, c_str.as_ptr()) }
     }
@@ -1436,7 +1644,7 @@ impl State {
     }
 
     /// Maps to `luaL_checkudata`.
-    pub fn check_userdata(&mut self, arg: Index, tname: &str) -> *mut c_void {
+    pub fn check_userdata(&self, arg: Index, tname: &str) -> *mut c_void {
         let c_str = CString::new(tname).unwrap();
         unsafe { luaL_checkudata(self.0, arg, c_str.as_ptr()) }
     }
@@ -1448,14 +1656,14 @@ impl State {
     }
 
     /// Maps to `luaL_where`. `where` is a reserved keyword.
-    pub fn location(&mut self, lvl: c_int) {
+    pub fn location(&self, lvl: c_int) {
         unsafe { luaL_where(self.0, lvl) }
     }
 
     // omitted: luaL_error
 
     /// Maps to `luaL_checkoption`.
-    pub fn check_option(&mut self, arg: Index, def: Option<&str>, lst: &[&str]) -> usize {
+    pub fn check_option(&self, arg: Index, def: Option<&str>, lst: &[&str]) -> usize {
         use std::vec::Vec;
         use libc::c_char;
         let mut vec: Vec<*const c_char> = Vec::with_capacity(lst.len() + 1);
@@ -1477,13 +1685,13 @@ impl State {
     }
 
     /// Maps to `luaL_fileresult`.
-    pub fn file_result(&mut self, stat: c_int, fname: &str) -> c_int {
+    pub fn file_result(&self, stat: c_int, fname: &str) -> c_int {
         let c_str = CString::new(fname).unwrap();
         unsafe { luaL_fileresult(self.0, stat, c_str.as_ptr()) }
     }
 
     /// Maps to `luaL_execresult`.
-    pub fn exec_result(&mut self, stat: c_int) -> c_int {
+    pub fn exec_result(&self, stat: c_int) -> c_int {
         unsafe { luaL_execresult(self.0, stat) }
     }
 
@@ -1500,57 +1708,57 @@ impl State {
         unsafe { luaL_unref(self.0, t, reference.value()) }
     }
 
-    /// Maps to `luaL_loadfilex`.
-    pub fn load_filex(&mut self, filename: &str, mode: &str) -> ThreadStatus {
-        let result = unsafe {
-            let filename_c_str = CString::new(filename).unwrap();
-            let mode_c_str = CString::new(mode).unwrap();
-            luaL_loadfilex(self.0, filename_c_str.as_ptr(), mode_c_str.as_ptr())
+    /// Maps to `luaL_loadfilex`. A file name containing NUL fails to open
+    /// with `ThreadStatus::FileError`.
+    pub fn load_filex(&self, filename: &str, mode: &str) -> ThreadStatus {
+        let filename_c_str = match CString::new(filename) {
+            Ok(s) => s,
+            Err(_) => {
+                self.push_string(&format!("cannot open {}: invalid file name", filename));
+                return ThreadStatus::FileError;
+            }
         };
+        let mode_c_str = c_string(mode.as_bytes());
+        let result = unsafe { luaL_loadfilex(self.0, filename_c_str.as_ptr(), mode_c_str.as_ptr()) };
         ThreadStatus::from_c_int(result)
     }
 
     /// Maps to `luaL_loadfile`.
-    pub fn load_file(&mut self, filename: &str) -> ThreadStatus {
-        let c_str = CString::new(filename).unwrap();
-        let result = unsafe {
-            luaL_loadfile(self.0, c_str.as_ptr())
-        };
-        ThreadStatus::from_c_int(result)
+    pub fn load_file(&self, filename: &str) -> ThreadStatus {
+        self.load_filex(filename, "bt")
     }
 
-    /// Maps to `luaL_loadbufferx`.
-    pub fn load_bufferx(&mut self, buff: &[u8], name: &str, mode: &str) -> ThreadStatus {
-        let name_c_str = CString::new(name).unwrap();
-        let mode_c_str = CString::new(mode).unwrap();
+    /// Maps to `luaL_loadbufferx`. `name` follows the same conventions as
+    /// in `load_buffer`.
+    pub fn load_bufferx(&self, buff: &[u8], name: &str, mode: &str) -> ThreadStatus {
+        let name_c_str = c_string(name.as_bytes());
+        let mode_c_str = c_string(mode.as_bytes());
         let result = unsafe { luaL_loadbufferx(self.0, buff.as_ptr() as *const _, buff.len() as size_t, name_c_str.as_ptr(), mode_c_str.as_ptr()) };
         ThreadStatus::from_c_int(result)
     }
 
-    /// Maps to `luaL_loadstring`.
-    pub fn load_string(&mut self, source: &str) -> ThreadStatus {
-        let c_str = CString::new(source).unwrap();
-        let result = unsafe { luaL_loadstring(self.0, c_str.as_ptr()) };
+    /// Like `luaL_loadstring`, but `source` may contain NUL bytes. The chunk
+    /// is named after its source.
+    pub fn load_string(&self, source: &str) -> ThreadStatus {
+        let name = c_string(source.as_bytes());
+        let result = unsafe { luaL_loadbufferx(self.0, source.as_ptr() as *const _, source.len() as size_t, name.as_ptr(), ptr::null()) };
         ThreadStatus::from_c_int(result)
     }
 
     // omitted: luaL_newstate (covered by State constructor)
 
     /// Maps to `luaL_len`.
-    pub fn len_direct(&mut self, index: Index) -> lua_Integer {
+    pub fn len_direct(&self, index: Index) -> lua_Integer {
         unsafe { luaL_len(self.0, index) }
     }
 
-    /// Maps to `luaL_gsub`.
-    pub fn gsub(&mut self, s: &str, p: &str, r: &str) -> &str {
-        let s_c_str = CString::new(s).unwrap();
-        let p_c_str = CString::new(p).unwrap();
-        let r_c_str = CString::new(r).unwrap();
-        let ptr = unsafe {
-            luaL_gsub(self.0, s_c_str.as_ptr(), p_c_str.as_ptr(), r_c_str.as_ptr())
-        };
-        let slice = unsafe { CStr::from_ptr(ptr).to_bytes() };
-        str::from_utf8(slice).unwrap()
+    /// [-0, +1, m] Like `luaL_gsub`: replaces every occurrence of `p` in `s`
+    /// with `r`, pushes the result and returns a copy of it. Unlike the C
+    /// function it accepts strings with NULs.
+    pub fn gsub(&self, s: &str, p: &str, r: &str) -> String {
+        let result = s.replace(p, r);
+        self.push_string(&result);
+        result
     }
 
     /// Maps to `luaL_setfuncs`.
@@ -1569,7 +1777,7 @@ impl State {
     }
 
     /// Maps to `luaL_getsubtable`.
-    pub fn get_subtable(&mut self, idx: Index, fname: &str) -> bool {
+    pub fn get_subtable(&self, idx: Index, fname: &str) -> bool {
         let c_str = CString::new(fname).unwrap();
         let result = unsafe {
             luaL_getsubtable(self.0, idx, c_str.as_ptr())
@@ -1602,32 +1810,29 @@ impl State {
     }
 
     /// Maps to `luaL_argcheck`.
-    pub fn arg_check(&mut self, cond: bool, arg: Index, extramsg: &str) {
+    pub fn arg_check(&self, cond: bool, arg: Index, extramsg: &str) {
         let c_str = CString::new(extramsg).unwrap();
         unsafe {
             luaL_argcheck(self.0, cond as c_int, arg, c_str.as_ptr())
         }
     }
 
-    /// Maps to `luaL_checklstring`.
-    pub fn check_string(&mut self, n: Index) -> &str {
+    /// Maps to `luaL_checklstring`, copying the string. Raises an argument
+    /// error if it is not valid UTF-8.
+    pub fn check_string(&self, n: Index) -> String {
         let mut size = 0;
         let ptr = unsafe { luaL_checklstring(self.0, n, &mut size) };
         let slice = unsafe { slice::from_raw_parts(ptr as *const u8, size as usize) };
-        str::from_utf8(slice).unwrap()
+        match str::from_utf8(slice) {
+            Ok(s) => s.to_owned(),
+            Err(_) => self.arg_error(n, "invalid UTF-8 string"),
+        }
     }
 
-    /// Maps to `luaL_optlstring`.
-    pub fn opt_string<'a>(&'a mut self, n: Index, default: &'a str) -> &'a str {
-        let mut size = 0;
-        let c_str = CString::new(default).unwrap();
-        let ptr = unsafe { luaL_optlstring(self.0, n, c_str.as_ptr(), &mut size) };
-        if ptr == c_str.as_ptr() {
-            default
-        } else {
-            let slice = unsafe { slice::from_raw_parts(ptr as *const u8, size as usize) };
-            str::from_utf8(slice).unwrap()
-        }
+    /// Maps to `luaL_optlstring`, copying the string. Returns `default` if
+    /// the argument is absent or nil.
+    pub fn opt_string(&self, n: Index, default: &str) -> String {
+        if self.is_none_or_nil(n) { default.to_owned() } else { self.check_string(n) }
     }
 
     // omitted: luaL_checkint (use .check_integer)
@@ -1643,7 +1848,7 @@ impl State {
     // luaL_dofile and luaL_dostring implemented above
 
     /// Maps to `luaL_getmetatable`.
-    pub fn get_metatable_from_registry(&mut self, tname: &str) {
+    pub fn get_metatable_from_registry(&self, tname: &str) {
         let c_str = CString::new(tname).unwrap();
         unsafe { luaL_getmetatable(self.0, c_str.as_ptr()) }
     }
@@ -1654,82 +1859,127 @@ impl State {
     // Wrapper functions
     //===========================================================================
     #[inline]
-    pub fn val(&self, i: Index) -> ValRef { ValRef::new(*self, i) }
+    pub fn val(&self, i: Index) -> ValRef<'_> { ValRef::new(self, i) }
 
     /// [-0, +0, -]
     #[inline]
-    pub fn upval(&self, i: Index) -> ValRef {
-        ValRef::new(*self, lua_upvalueindex(i))
+    pub fn upval(&self, i: Index) -> ValRef<'_> {
+        ValRef::new(self, lua_upvalueindex(i))
     }
 
     /// [-0, +0, -]
     #[inline]
-    pub fn c_reg(&self) -> Table {
+    pub fn c_reg(&self) -> Table<'_> {
         Table(self.val(LUA_REGISTRYINDEX))
     }
 
+    /// [-0, +0, -]
+    #[inline]
+    pub fn main_thread(&self) -> StateRef<'_> {
+        unsafe {
+            lua_rawgeti(self.0, LUA_REGISTRYINDEX, LUA_RIDX_MAINTHREAD);
+            let main = StateRef::from_ptr(lua_tothread(self.0, -1));
+            lua_pop(self.0, 1);
+            main
+        }
+    }
+
     /// [-0, +1, -]
     #[inline]
-    pub fn global(&self) -> Table {
+    pub fn global(&self) -> Table<'_> {
         unsafe { lua_rawgeti(self.0, LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS); }
         Table(self.val(-1))
     }
 
-    pub fn table(&self, narr: c_int, nrec: c_int) -> Table {
+    pub fn table(&self, narr: c_int, nrec: c_int) -> Table<'_> {
         self.create_table(narr, nrec); Table(self.val(-1))
     }
 
     #[inline]
-    pub fn rust_fn(&self, fun: fn(State) -> c_int) -> TopRef {
+    pub fn rust_fn(&self, fun: fn(&State) -> c_int) -> TopRef<'_> {
         unsafe extern "C" fn call_rust_fn(l: *mut lua_State) -> c_int {
             let state = State::from_ptr(l);
             let fp = state.to_pointer(lua_upvalueindex(1));
-            let fp: fn(State) -> c_int = mem::transmute(fp);
-            fp(state)
+            let fp: fn(&State) -> c_int = mem::transmute(fp);
+            catch_panic(l, || fp(&state))
         }
 
         self.push_light_userdata(fun as usize as *mut usize);
         self.push_cclosure(Some(call_rust_fn), 1);
+        self.set_function_name(-1, None);
         TopRef(self.val(-1))
     }
 
     #[inline]
-    pub fn method<T>(&self, fun: fn(&mut T, State) -> c_int) -> TopRef {
+    pub fn method<T: 'static>(&self, fun: fn(&mut T, &State) -> c_int) -> TopRef<'_> {
         self.push_light_userdata(fun as usize as *mut usize);
         self.push_cclosure(Some(Method::<T>::lua_fn), 1);
+        self.set_function_name(-1, None);
         TopRef(self.val(-1))
     }
 
 
-    /// [-0, +1, -]
-    pub fn push_userdata<T>(&self, data: T, metatable: Option<InitMetatable>) -> &mut T {
-        let result: &mut T = unsafe { mem::transmute(self.new_userdata(mem::size_of::<T>())) };
-        mem::forget(mem::replace(result, data));
-        if let Some(m) = metatable { self.set_or_init_metatable(m); }
-        result
+    /// [-0, +1, m] Pushes `data` as a full userdata. The metatable from
+    /// `metatable`, or an empty one if `None`, records the type of `T` so that
+    /// methods can check their receiver, see `check_userdata_mut`. It is built
+    /// when the first `T` is pushed and reused for the ones after.
+    ///
+    /// The value is reached through `borrow_userdata` and
+    /// `borrow_userdata_mut`, which track borrows like methods called from
+    /// Lua do.
+    pub fn push_userdata<T: 'static>(&self, data: T, metatable: Option<InitMetatable>) {
+        let cell = self.new_userdata(mem::size_of::<UserCell<T>>()) as *mut UserCell<T>;
+        unsafe { ptr::write(cell, UserCell::new(data)); }
+        self.set_type_metatable::<T>(metatable.unwrap_or(identity_metatable));
+        self.set_userdata_type::<T>(-1);
+    }
+
+    /// [-0, +1, -] Loads `source`, text or precompiled, and pushes it as a
+    /// function. `chunk_name` is `@file` for a file name, `=name` for a name
+    /// displayed as is, or else displayed as source text. Without it the chunk
+    /// is named after its source, like `load_string`.
+    pub fn load_buffer<F: AsRef<[u8]>>(&self, source: F, chunk_name: Option<&str>) -> Result<ValRef<'_>, LuaError> {
+        self.load_chunk(source.as_ref(), chunk_name, Mode::Both)
+    }
+
+    /// [-0, +1, -] Like `load_buffer`, but the chunk gets `env` as its `_ENV`
+    /// instead of the global table, so its globals are fields of `env`. A
+    /// metatable on `env` with `__index = _G` lets the chunk read the real
+    /// globals while its assignments stay in `env`.
+    ///
+    /// A precompiled chunk is not checked by Lua and can break out of `env`,
+    /// so pass `Mode::Text` for code that is not trusted.
+    pub fn load_with_env<F: AsRef<[u8]>>(&self, source: F, chunk_name: Option<&str>, env: &Table, mode: Mode) -> Result<ValRef<'_>, LuaError> {
+        let chunk = self.load_chunk(source.as_ref(), chunk_name, mode)?;
+        self.set_chunk_env(chunk.index, env);
+        Ok(chunk)
     }
 
-    pub fn load_buffer<F: AsRef<[u8]>>(&self, source: F, chunk_name: Option<&str>) -> Result<ValRef, ThreadStatus> {
-        let buffer = source.as_ref();
-        let chunk = match chunk_name {
-            Some(name) => name.as_ptr(),
-            None       => ptr::null(),
-        };
+    /// [-0, +1, -] `load_buffer` with a mode.
+    fn load_chunk(&self, buffer: &[u8], chunk_name: Option<&str>, mode: Mode) -> Result<ValRef<'_>, LuaError> {
+        let chunk = c_string(chunk_name.map_or(buffer, str::as_bytes));
         let result = unsafe {
-            luaL_loadbuffer(self.0, buffer.as_ptr() as *const c_char, buffer.len(), chunk as *const c_char)
+            luaL_loadbufferx(self.0, buffer.as_ptr() as *const c_char, buffer.len(), chunk.as_ptr(), mode.as_cstr().as_ptr())
         };
         match result {
             LUA_OK => Ok(self.val(-1)),
-            err => Err(ThreadStatus::from_c_int(err)),
+            err => Err(self.pop_error(ThreadStatus::from_c_int(err))),
         }
     }
 
-    fn get_or_init_metatable(&self, callback: fn(Table, State)) {
+    /// Sets the first upvalue of the loaded chunk at `index`, which is its
+    /// `_ENV`, to `env`. Precompiled functions may have no upvalue.
+    fn set_chunk_env(&self, index: Index, env: &Table) {
+        self.push_value(env.0.index);
+        if self.set_upvalue(index, 1).is_none() { self.pop(1); }
+    }
+
+    fn get_or_init_metatable(&self, callback: InitMetatable) {
         let reg = self.c_reg();
         let p = callback as *const usize;
         let metatable = reg.getp(p);
         if metatable.is_nil() {
-            callback(self.table(0, 0), *self);
+            callback(self.table(0, 0), self);
             assert!(self.type_of(-1) == Type::Table);
             reg.setp(p, self.val(-1));
             self.replace(-2);
@@ -1745,36 +1995,40 @@ impl State {
         self.set_metatable(-2);
     }
 
-    pub fn rust_closure<F: 'static +  FnMut(State) -> c_int>(&self, closure: F) -> TopRef {
+    pub fn rust_closure<F: 'static +  FnMut(&State) -> c_int>(&self, closure: F) -> TopRef<'_> {
+        self.named_closure(std::any::type_name::<F>(), closure)
+    }
+
+    /// Same as `rust_closure`, with `name` shown in traceback frames.
+    pub(crate) fn named_closure<F: 'static + FnMut(&State) -> c_int>(&self, name: &str, closure: F) -> TopRef<'_> {
         unsafe extern "C" fn closure_callback(l: *mut lua_State) -> c_int {
             let state = State::from_ptr(l);
             let closure: &mut RustClosure = mem::transmute(
                 state.to_userdata(lua_upvalueindex(1))
             );
-            (*closure)(state)
+            catch_panic(l, || (*closure)(&state))
         }
 
         let closure: RustClosure = Box::new(closure);
-        let p: &mut RustClosure = unsafe {
-            mem::transmute(self.new_userdata(mem::size_of::<RustClosure>()))
-        };
-        self.set_or_init_metatable(metatable!(
+        self.push_userdata(closure, Some(metatable!(
             RustClosure(s: State, this: Self);
-            "__gc" () { ptr::drop_in_place(this); 0 }
-        ));
-        mem::forget(mem::replace(p, closure));
+        )));
         self.push_cclosure(Some(closure_callback), 1);
+        self.name_function(-1, name);
         TopRef(self.val(-1))
     }
 
-    /// [-1, +1, -]
-    pub fn trace_error(&self, s: Option<&State>) -> &'static str {
-        let err = self.to_str(-1).unwrap_or(""); self.pop(1);
+    /// [-1, +1, m] Replaces the error message on top of the stack with a
+    /// traceback of `s`, or of this thread, and returns a copy of it.
+    pub fn trace_error(&self, s: Option<&State>) -> String {
+        let thread = s.unwrap_or(self);
         unsafe {
-            let thread = s.unwrap_or(self);
-            luaL_traceback(self.0, thread.0, err.as_ptr() as *const c_char, 0);
+            // the message stays on the stack until the traceback is built
+            let err = lua_tolstring(self.0, -1, ptr::null_mut());
+            luaL_traceback(self.0, thread.0, err, 0);
         }
-        self.to_str(-1).unwrap_or("")
+        self.remove(-2);
+        self.to_str(-1).unwrap_or_default()
     }
 
     #[inline(always)]
@@ -1809,14 +2063,12 @@ impl State {
     }
 
     /// [-0, +1, -]
-    pub fn iterator<T: ToLua>(&self, iter: BoxIter<T>) -> c_int {
-        type BoxIterT = BoxIter<usize>;
-        self.push_userdata(iter, Some(
-            metatable!(
-                BoxIterT(s: State, this: Self);
-                "__gc" () { std::ptr::drop_in_place(this); 0 }
-            )
-        ));
+    pub fn iterator<T: ToLua + 'static>(&self, iter: BoxIter<T>) -> c_int {
+        fn init_metatable<T: 'static>(meta: Table, s: &State) {
+            meta.set("__gc", gc_userdata::<BoxIter<T>> as CFunction);
+            meta.set("__metatable", false);
+        }
+        self.push_userdata(iter, Some(init_metatable::<T>));
         self.push_cclosure(Some(Iter::<T>::lua_fn), 1); 1
     }
 
@@ -1832,9 +2084,9 @@ impl State {
     }
 
     #[inline(always)]
-    pub fn balance_with<T, F: FnMut(State) -> T>(&self, mut callback: F) -> T {
+    pub fn balance_with<T, F: FnMut(&State) -> T>(&self, mut callback: F) -> T {
         let top = self.get_top();
-        let result = callback(*self);
+        let result = callback(self);
         self.set_top(top);
         result
     }
@@ -1850,21 +2102,21 @@ impl State {
         }
     }
 
+    /// [-0, +0, m]
     pub fn value(&self, i: Index) -> Value {
         match unsafe { lua_type(self.0, i) } {
-            LUA_TNONE => Value::None,
             LUA_TNIL => Value::Nil,
             LUA_TNUMBER => if self.is_integer(i) {
                 Value::Int(self.to_integer(i))
             } else { Value::Num(self.to_number(i)) },
-            LUA_TSTRING => Value::Str(self.to_str(i).unwrap()),
+            LUA_TSTRING => Value::Str(self.to_bytes(i).unwrap()),
             LUA_TBOOLEAN => Value::Bool(self.to_bool(i)),
-            LUA_TLIGHTUSERDATA => Value::LightUserdata,
-            LUA_TTABLE => Value::Table,
-            LUA_TFUNCTION => Value::Function,
-            LUA_TUSERDATA => Value::Userdata,
-            LUA_TTHREAD => Value::Thread,
-            _ => panic!(""),
+            LUA_TLIGHTUSERDATA => Value::LightUserdata(self.to_userdata(i)),
+            LUA_TTABLE => Value::Table(OwnedTable(OwnedRef::new(self, i))),
+            LUA_TFUNCTION => Value::Function(OwnedFunction(OwnedRef::new(self, i))),
+            LUA_TUSERDATA => Value::Userdata(OwnedRef::new(self, i)),
+            LUA_TTHREAD => Value::Thread(OwnedRef::new(self, i)),
+            _ => Value::None,
         }
     }
 }
@@ -1881,6 +2133,6 @@ impl<T: ToLua> Iter<T> where {
         let state = State::from_ptr(l);
         let p = state.to_userdata(ffi::lua_upvalueindex(1));
         let iter: &mut BoxIter<T> = mem::transmute(p);
-        if let Some(v) = iter.next() { state.push(v); 1 } else { 0 }
+        catch_panic(l, || if let Some(v) = iter.next() { state.push(v); 1 } else { 0 })
     }
 }
\ No newline at end of file
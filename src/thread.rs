@@ -1,80 +1,275 @@
 
 use crate::*;
+use crate::ffi::*;
 
-use std::thread;
-use std::ptr;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use std::sync::mpsc::{channel, Sender, Receiver};
-#[cfg(target_os = "windows")]
-use std::os::windows::io::{RawHandle, IntoRawHandle};
-#[cfg(not(target_os = "windows"))]
-use std::os::unix::thread::RawPthread as RawHandle;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError, TryRecvError};
 
-struct RustThread {
-    state: State,
-    r_state: Reference,
-    r_udata: Reference,
-    recv_end: Receiver<bool>,
-    handle: RawHandle,
+/// A Lua value copied between the states of a worker and its parent: nil, a
+/// boolean, a number, a string or a table of those without cycles.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Nil,
+    Bool(bool),
+    Int(lua_Integer),
+    Num(lua_Number),
+    Str(Vec<u8>),
+    Table(Vec<(Message, Message)>),
 }
 
-metatable! {
-    const METATABLE = RustThread(state: State, this: Self) IndexSelf;
-
-    "handle" () push { this.handle as u64 }
-    "__gc" () { 0 }
-}
-
-#[cfg(target_os = "windows")]
-const RAW_NULL: RawHandle = ptr::null_mut();
-#[cfg(not(target_os = "windows"))]
-const RAW_NULL: RawHandle = 0;
-
-pub(crate) fn init_thread(s: State) {
-    let t = s.table(0, 4);
-    t.set("spawn", cfn!((s) {
-        s.check_type(1, Type::Function);
-
-        // Init the new state
-        let state = s.new_thread();
-        let c_reg = s.c_reg();
-        s.xmove(state, s.to_lua(s.val(1)));
-
-        // Init the data struct
-        let (sender, receiver) = channel::<bool>();
-        let thread = s.push_userdata(RustThread {
-            state,
-            r_state: c_reg.reference(s.val(-1)),
-            r_udata: NOREF,
-            recv_end: receiver,
-            handle: RAW_NULL,
-        }, Some(METATABLE));
-        thread.r_udata = c_reg.reference(s.val(-1));
-
-        // Start the thread
-        let r_state = thread.r_state;
-        let r_udata = thread.r_udata;
-        let h = thread::spawn(move || {
-            match state.pcall(0, 0, 0) {
-                ThreadStatus::Ok => {}
-                ThreadStatus::Yield => {}
-                ElseStatus => {}
+/// How deeply tables can be nested in a message.
+const MAX_MESSAGE_DEPTH: usize = 200;
+
+impl Message {
+    /// Copies the value at `index`. Metatables are not copied, and a table
+    /// reachable twice is copied twice.
+    pub fn from_lua(state: &State, index: Index) -> Result<Message, String> {
+        Message::copy(state, state.abs_index(index), &mut Vec::new())
+    }
+
+    fn copy(state: &State, index: Index, path: &mut Vec<*const c_void>) -> Result<Message, String> {
+        Ok(match state.type_of(index) {
+            Type::Nil => Message::Nil,
+            Type::Boolean => Message::Bool(state.to_bool(index)),
+            Type::Number if state.is_integer(index) => Message::Int(state.to_integer(index)),
+            Type::Number => Message::Num(state.to_number(index)),
+            Type::String => Message::Str(state.to_bytes(index).unwrap_or_default()),
+            Type::Table => {
+                let table = state.to_pointer(index);
+                if path.contains(&table) { return Err("table contains a cycle".to_owned()); }
+                if path.len() >= MAX_MESSAGE_DEPTH || !state.check_stack(3) {
+                    return Err("table is nested too deeply".to_owned());
+                }
+                path.push(table);
+                let mut entries = Vec::new();
+                state.push_nil();
+                while state.next(index) {
+                    let top = state.get_top();
+                    let entry = Message::copy(state, top - 1, path)
+                        .and_then(|k| Ok((k, Message::copy(state, top, path)?)));
+                    state.pop(1);
+                    match entry {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => { state.pop(1); return Err(e); }
+                    }
+                }
+                path.pop();
+                Message::Table(entries)
             }
-            state.c_reg().unreference(r_state);
-            state.c_reg().unreference(r_udata);
-            sender.send(true);
-        });
+            ty => return Err(format!("cannot send a {} value", state.typename_of(ty))),
+        })
+    }
+}
 
-        #[cfg(target_os = "windows")] {
-            thread.handle = h.into_raw_handle(); 
+impl ToLua for &Message {
+    fn to_lua(self, state: &State) {
+        match self {
+            Message::Nil => state.push_nil(),
+            Message::Bool(b) => state.push_bool(*b),
+            Message::Int(i) => state.push_integer(*i),
+            Message::Num(n) => state.push_number(*n),
+            Message::Str(s) => state.push_bytes(s),
+            Message::Table(entries) => {
+                state.check_stack_msg(3, "message too deep");
+                state.create_table(0, entries.len() as c_int);
+                for (k, v) in entries {
+                    state.push(k);
+                    state.push(v);
+                    state.raw_set(-3);
+                }
+            }
         }
-        #[cfg(target_os = "unix")] {
-            thread.handle = h.as_pthread_t(); 
+    }
+}
+
+impl ToLua for Message {
+    #[inline]
+    fn to_lua(self, state: &State) { state.push(&self) }
+}
+
+/// Copies the values from `start` to the top of the stack, raising an
+/// argument error for values that cannot be sent.
+fn check_messages(s: &State, start: Index) -> Vec<Message> {
+    (start..=s.get_top()).map(|i| {
+        Message::from_lua(s, i).unwrap_or_else(|e| s.arg_error(i, &e))
+    }).collect()
+}
+
+/// Pushes `true` and the values of a message, and returns how many values it
+/// pushed.
+fn push_message(s: &State, message: Vec<Message>) -> c_int {
+    s.check_stack_msg(message.len() as c_int + 1, "too many values in message");
+    s.push_bool(true);
+    for value in &message { s.push(value); }
+    message.len() as c_int + 1
+}
+
+/// One end of the channel between a worker and its parent.
+struct Channel {
+    sender: Sender<Vec<Message>>,
+    receiver: Receiver<Vec<Message>>,
+}
+
+impl Channel {
+    fn pair() -> (Channel, Channel) {
+        let (parent_sender, child_receiver) = channel();
+        let (child_sender, parent_receiver) = channel();
+        (Channel { sender: parent_sender, receiver: parent_receiver },
+         Channel { sender: child_sender, receiver: child_receiver })
+    }
+}
+
+/// A userdata holding a channel end.
+trait Endpoint: 'static {
+    fn channel(&self) -> &Channel;
+}
+
+impl Endpoint for Channel {
+    fn channel(&self) -> &Channel { self }
+}
+
+/// Adds `send`, `recv` and `try_recv` to the metatable of `T`. Unlike
+/// `metatable!` methods, they only borrow the userdata while using the
+/// channel, so errors raised while copying values leave it unborrowed.
+fn add_channel_methods<T: Endpoint>(meta: &Table, s: &State) {
+    // send(...) copies the values to the other state, and returns whether
+    // the other end is still open.
+    unsafe extern "C" fn send<T: Endpoint>(l: *mut lua_State) -> c_int {
+        catch_panic(l, || {
+            let s = State::from_ptr(l);
+            drop(s.check_userdata_ref::<T>(1));
+            let message = check_messages(&s, 2);
+            let sent = s.check_userdata_ref::<T>(1).channel().sender.send(message).is_ok();
+            s.pushx(sent)
+        })
+    }
+
+    // recv([timeout]) waits for a message, at most `timeout` milliseconds,
+    // and returns `true` and its values, or `false` and "timeout" or
+    // "closed".
+    unsafe extern "C" fn recv<T: Endpoint>(l: *mut lua_State) -> c_int {
+        catch_panic(l, || {
+            let s = State::from_ptr(l);
+            let timeout: Option<u64> = FromIndex::from_lua(&s, 2);
+            let received = {
+                let this = s.check_userdata_mut::<T>(1);
+                let receiver = &this.channel().receiver;
+                match timeout {
+                    None => receiver.recv().map_err(|_| "closed"),
+                    Some(ms) => receiver.recv_timeout(Duration::from_millis(ms)).map_err(|e| match e {
+                        RecvTimeoutError::Timeout => "timeout",
+                        RecvTimeoutError::Disconnected => "closed",
+                    }),
+                }
+            };
+            match received {
+                Ok(message) => push_message(&s, message),
+                Err(e) => s.pushx((false, e)),
+            }
+        })
+    }
+
+    // try_recv() is `recv` without waiting, failing with "empty" or "closed".
+    unsafe extern "C" fn try_recv<T: Endpoint>(l: *mut lua_State) -> c_int {
+        catch_panic(l, || {
+            let s = State::from_ptr(l);
+            let received = s.check_userdata_mut::<T>(1).channel().receiver.try_recv();
+            match received {
+                Ok(message) => push_message(&s, message),
+                Err(TryRecvError::Empty) => s.pushx((false, "empty")),
+                Err(TryRecvError::Disconnected) => s.pushx((false, "closed")),
+            }
+        })
+    }
+
+    let methods: [(&str, CFunction); 3] = [("send", send::<T>), ("recv", recv::<T>), ("try_recv", try_recv::<T>)];
+    for &(name, f) in &methods {
+        s.push_fn(Some(f));
+        s.name_function(-1, &format!("{}:{}", crate::userdata::short_type_name::<T>(), name));
+        meta.set(name, TopRef(s.val(-1)));
+    }
+}
+
+metatable! {
+    const CHANNEL_METHODS = Channel(s: State, this: Self) IndexSelf;
+}
+
+const CHANNEL_METATABLE: InitMetatable = |meta, s| {
+    add_channel_methods::<Channel>(&meta, s);
+    CHANNEL_METHODS(meta, s)
+};
+
+/// A Lua chunk running in its own state on its own OS thread, returned by
+/// `thread.worker`. Dropping it closes the channel and detaches the thread.
+struct Worker {
+    channel: Channel,
+    handle: Option<JoinHandle<Result<Vec<Message>, String>>>,
+}
+
+impl Endpoint for Worker {
+    fn channel(&self) -> &Channel { &self.channel }
+}
+
+metatable! {
+    const WORKER_METHODS = Worker(s: State, this: Self) IndexSelf;
+
+    // Waits for the chunk to return, and returns `true` and its results, or
+    // `false` and the error, like `pcall`.
+    "join" () {
+        match this.handle.take().map(JoinHandle::join) {
+            Some(Ok(Ok(results))) => push_message(&s, results),
+            Some(Ok(Err(e))) => s.pushx((false, e.as_str())),
+            Some(Err(_)) => s.pushx((false, "worker panicked")),
+            None => s.pushx((false, "worker already joined")),
         }
-        #[cfg(target_os = "linux")] {
-            thread.handle = 0; 
+    }
+    "is_finished" () push { this.handle.as_ref().is_none_or(JoinHandle::is_finished) }
+}
+
+const WORKER_METATABLE: InitMetatable = |meta, s| {
+    add_channel_methods::<Worker>(&meta, s);
+    WORKER_METHODS(meta, s)
+};
+
+/// Runs the source of a worker in a new state, with the channel and the
+/// arguments as varargs.
+fn run_worker(source: Vec<u8>, channel: Channel, args: Vec<Message>) -> Result<Vec<Message>, String> {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.load_buffer(&source, Some("=worker")).map_err(|e| e.to_string())?;
+    lua.push_userdata(channel, Some(CHANNEL_METATABLE));
+    lua.check_stack_msg(args.len() as c_int, "too many arguments");
+    for arg in &args { lua.push(arg); }
+    lua.protected_call(args.len() as c_int + 1, LUA_MULTRET).map_err(|e| e.to_string())?;
+    (1..=lua.get_top()).map(|i| {
+        Message::from_lua(&lua, i).map_err(|e| format!("cannot return value #{}: {}", i, e))
+    }).collect()
+}
+
+pub(crate) fn init_thread(s: &State) {
+    let t = s.table(0, 3);
+    // thread.worker(source, ...) runs a chunk in a new state on a new OS
+    // thread, which shares nothing with this one. The chunk gets a channel
+    // and copies of the arguments; both ends exchange copies of values
+    // through `send` and `recv`.
+    t.set("worker", cfn!((s, source: &[u8]) {
+        let args = check_messages(&s, 2);
+        let source = source.to_vec();
+        let (channel, child) = Channel::pair();
+        let spawned = thread::Builder::new().name("lua worker".to_owned()).spawn(move || {
+            run_worker(source, child, args)
+        });
+        match spawned {
+            Ok(handle) => {
+                s.push_userdata(Worker { channel, handle: Some(handle) }, Some(WORKER_METATABLE));
+                1
+            }
+            Err(e) => {
+                s.push_string(&format!("cannot spawn thread: {}", e));
+                s.error()
+            }
         }
-        return 1;
     }));
 
     t.set("sleep", cfn!((s, time: u64) push {
@@ -85,4 +280,4 @@ pub(crate) fn init_thread(s: State) {
         thread::yield_now();
     }));
     s.global().set("thread", t.0);
-}
\ No newline at end of file
+}
@@ -0,0 +1,191 @@
+use crate::*;
+use crate::ffi::*;
+
+use std::{fmt, ptr};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// What kind of function a `Frame` is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    /// A Lua function.
+    Lua,
+    /// The main chunk of a loaded script.
+    Main,
+    /// A native function registered from Rust, see `State::name_function`.
+    Rust,
+    /// Any other C function.
+    C,
+}
+
+/// One level of the call stack, as captured by `State::stack_frames`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The `short_src` of the function, e.g. `[string "..."]` or `[C]`.
+    pub source: String,
+    /// The current line, if the function is a Lua function.
+    pub line: Option<u32>,
+    /// The registered name of a Rust function, or else the name Lua infers
+    /// from the call site, e.g. `print` or `obj:method`.
+    pub name: Option<String>,
+    pub kind: FrameKind,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)?;
+        if let Some(line) = self.line { write!(f, ":{}", line)?; }
+        match (&self.name, self.kind) {
+            (_, FrameKind::Main) => f.write_str(": in main chunk"),
+            (Some(name), _) => write!(f, ": in function '{}'", name),
+            (None, _) => f.write_str(": in ?"),
+        }
+    }
+}
+
+/// The call stack at the point of a runtime error, captured by the message
+/// handler of `State::protected_call`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Traceback {
+    /// The traceback as formatted by `luaL_traceback`.
+    pub text: String,
+    /// The frames, innermost first.
+    pub frames: Vec<Frame>,
+}
+
+impl fmt::Display for Traceback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.text) }
+}
+
+metatable! {
+    const TRACEBACK_METATABLE = Traceback(_s: State, _this: Self);
+}
+
+static NAMES_KEY: u8 = 0;
+/// Registry key of the weak-keyed table holding the last recorded traceback
+/// of each thread.
+static TRACEBACKS_KEY: u8 = 0;
+
+/// Message handler used by `State::protected_call`. It leaves the error value
+/// untouched and stores the `Traceback` in the registry.
+pub(crate) unsafe extern "C" fn traceback_handler(l: *mut lua_State) -> c_int {
+    catch_panic(l, || {
+        let state = State::from_ptr(l);
+        state.record_traceback(1);
+        state.set_top(1);
+        1
+    })
+}
+
+fn c_str(p: *const c_char) -> Option<String> {
+    if p.is_null() { None } else { Some(unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned()) }
+}
+
+impl State {
+    /// [-0, +1, m] Pushes the weak-keyed table that maps Rust functions to
+    /// their names.
+    fn push_function_names(&self) {
+        self.push_weak_table(&NAMES_KEY);
+    }
+
+    /// [-0, +1, m] Pushes the weak-keyed table stored in the registry at `key`,
+    /// creating it if needed.
+    fn push_weak_table(&self, key: &'static u8) {
+        if self.raw_getp(LUA_REGISTRYINDEX, key) != Type::Table {
+            self.pop(1);
+            self.create_table(0, 0);
+            self.create_table(0, 1);
+            self.push_string("k");
+            self.set_field(-2, "__mode");
+            self.set_metatable(-2);
+            self.push_value(-1);
+            self.raw_setp(LUA_REGISTRYINDEX, key);
+        }
+    }
+
+    /// Marks the function at `index` as a Rust function named `name` in
+    /// traceback frames. Functions created by `rust_fn`, `rust_closure`,
+    /// `push_closure`, `method`, `metatable!` and `UserData` are marked
+    /// automatically.
+    pub fn name_function(&self, index: Index, name: &str) {
+        self.set_function_name(index, Some(name));
+    }
+
+    /// Marks the function at `index` as a Rust function. Without a `name` the
+    /// frame falls back to the name Lua infers from the call site.
+    pub(crate) fn set_function_name(&self, index: Index, name: Option<&str>) {
+        let index = self.abs_index(index);
+        self.push_function_names();
+        self.push_value(index);
+        match name {
+            Some(name) => self.push_string(name),
+            None => self.push_bool(true),
+        }
+        self.raw_set(-3);
+        self.pop(1);
+    }
+
+    /// Captures the call stack from `level` outwards, where level 0 is the
+    /// running function.
+    pub fn stack_frames(&self, level: c_int) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut level = level;
+        self.push_function_names();
+        while let Some(mut ar) = self.get_stack(level) {
+            unsafe { lua_getinfo(self.as_ptr(), b"Slnf\0".as_ptr() as *const c_char, &mut ar); }
+            self.raw_get(-2);
+            let rust_name = self.to_str(-1);
+            let is_rust = !self.is_nil(-1);
+            self.pop(1);
+
+            let kind = match c_str(ar.what).as_deref() {
+                Some("main") => FrameKind::Main,
+                Some("C") if is_rust => FrameKind::Rust,
+                Some("C") => FrameKind::C,
+                _ => FrameKind::Lua,
+            };
+            frames.push(Frame {
+                source: c_str(ar.short_src.as_ptr()).unwrap_or_default(),
+                line: if ar.currentline > 0 { Some(ar.currentline as u32) } else { None },
+                name: rust_name.or_else(|| c_str(ar.name)),
+                kind,
+            });
+            level += 1;
+        }
+        self.pop(1);
+        frames
+    }
+
+    /// [-0, +0, m] Records the call stack from `level` outwards as the
+    /// traceback of the next error of this thread returned by `pop_error`.
+    pub(crate) fn record_traceback(&self, level: c_int) {
+        let frames = self.stack_frames(level);
+        unsafe { luaL_traceback(self.as_ptr(), self.as_ptr(), ptr::null(), level) };
+        let text = self.to_str(-1).unwrap_or_default();
+        self.pop(1);
+        self.push_weak_table(&TRACEBACKS_KEY);
+        self.push_thread();
+        self.push_userdata(Traceback { text, frames }, Some(TRACEBACK_METATABLE));
+        self.raw_set(-3);
+        self.pop(1);
+    }
+
+    /// [-0, +0, -] Takes the traceback recorded by the last error of this
+    /// thread handled by `protected_call`.
+    pub(crate) fn take_traceback(&self) -> Option<Traceback> {
+        self.balance_with(|s| {
+            if s.raw_getp(LUA_REGISTRYINDEX, &TRACEBACKS_KEY) != Type::Table { return None; }
+            s.push_thread();
+            s.raw_get(-2);
+            let traceback = s.userdata_mut::<Traceback>(-1).ok().map(|mut t| {
+                Traceback { text: std::mem::take(&mut t.text), frames: std::mem::take(&mut t.frames) }
+            });
+            if traceback.is_some() {
+                s.push_thread();
+                s.push_nil();
+                s.raw_set(-4);
+            }
+            traceback
+        })
+    }
+}
@@ -1,14 +1,35 @@
-
 #ifndef __ULUA_H__
 #define __ULUA_H__
 
 #define lua_lock(L) ulua_lock(L)
 #define lua_unlock(L) ulua_unlock(L)
 #define luai_userstateopen(L) ulua_init_lock(L)
-// #define luai_userstatethread(L,L1) ulua_init_lock(L1)
+#define luai_userstatethread(L,L1) ulua_init_thread(L,L1)
+#define luai_userstatefree(L,L1) ulua_free_thread(L,L1)
+#define luai_userstateclose(L) ulua_close_lock(L)
+#define luai_userstateresume(L,n) ulua_resume(L)
+
+/* Errors jump over native frames; ulua_throw first drops the guards those
+** frames own, counted from the ones alive when the protected call began. */
+#if defined(LUA_USE_POSIX)
+#define LUAI_THROW(L,c)		(ulua_throw(L, (c)->b.guards), _longjmp((c)->b.buf, 1))
+#define LUAI_TRY(L,c,a) \
+	(c)->b.guards = ulua_guards(L); if (_setjmp((c)->b.buf) == 0) { a }
+#else
+#define LUAI_THROW(L,c)		(ulua_throw(L, (c)->b.guards), longjmp((c)->b.buf, 1))
+#define LUAI_TRY(L,c,a) \
+	(c)->b.guards = ulua_guards(L); if (setjmp((c)->b.buf) == 0) { a }
+#endif
+#define luai_jmpbuf		struct { jmp_buf buf; size_t guards; }
 
 extern void ulua_lock(lua_State * L);
 extern void ulua_unlock(lua_State * L);
 extern void ulua_init_lock(lua_State * L);
+extern void ulua_init_thread(lua_State * L, lua_State * L1);
+extern void ulua_free_thread(lua_State * L, lua_State * L1);
+extern void ulua_close_lock(lua_State * L);
+extern void ulua_resume(lua_State * L);
+extern size_t ulua_guards(lua_State * L);
+extern void ulua_throw(lua_State * L, size_t guards);
 
 #endif /* __ULUA_H__ */
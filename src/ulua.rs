@@ -1,57 +1,173 @@
-
 use crate::ffi::*;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::mem::transmute;
-
-struct SpinLock {
-    locked: AtomicBool,
-}
+use crate::state::Extra;
+use crate::alloc::Allocator;
+use crate::limits::{Limits, InterruptFlag};
+use crate::State;
 
-#[cfg(target_os = "windows")]
-#[inline]
-fn asm_pause() { unsafe { asm!("pause"::::); }}
+use std::{hint, mem, thread};
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
-#[cfg(not(target_os = "windows"))]
-#[inline]
-fn asm_pause() {}
+/// A fair spin lock: threads get the lock in the order they asked for it, so
+/// the `lua_unlock`/`lua_lock` pair of `luai_threadyield` lets a waiting
+/// thread in.
+struct TicketLock {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+}
 
-impl SpinLock {
-    pub const fn new() -> SpinLock {
-        SpinLock { locked: AtomicBool::new(false) }
+impl TicketLock {
+    const fn new() -> TicketLock {
+        TicketLock { next: AtomicUsize::new(0), serving: AtomicUsize::new(0) }
     }
 
-    pub fn lock(&mut self) {
-        while !self.locked.compare_and_swap(false, true, Ordering::Relaxed) {
-            asm_pause();
+    fn lock(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        while self.serving.load(Ordering::Acquire) != ticket {
+            if spins < 100 {
+                spins += 1;
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
         }
     }
 
-    pub fn unlock(&mut self) {
-        self.locked.store(false, Ordering::Relaxed);
+    fn unlock(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
     }
 }
 
-#[repr(C)]
-struct Extra {
-    spin: SpinLock,
+/// Data shared by all threads of a Lua state. The extra space of each
+/// `lua_State` holds a pointer to it: it is allocated when the state is
+/// opened, shared with every thread created by `lua_newthread` and freed
+/// when the state is closed.
+pub(crate) struct Shared {
+    lock: TicketLock,
+    /// The value of `State::set_extra`.
+    pub(crate) extra: Mutex<Option<Extra>>,
+    /// The allocator installed by `StateBuilder`, if any.
+    pub(crate) allocator: AtomicPtr<Allocator>,
+    /// The execution limits, see `State::set_instruction_limit`.
+    pub(crate) limits: Mutex<Limits>,
+    /// Shared with the `InterruptHandle`s of the state.
+    pub(crate) interrupt: Arc<InterruptFlag>,
 }
 
 #[inline]
-fn get_extra(l: *mut lua_State) -> &'static mut Extra {
-    unsafe { transmute(lua_getextraspace(l)) }
+fn extra_space(l: *mut lua_State) -> *mut *mut Shared {
+    unsafe { lua_getextraspace(l) as *mut *mut Shared }
+}
+
+#[inline]
+pub(crate) fn shared(l: *mut lua_State) -> &'static Shared {
+    unsafe { &**extra_space(l) }
 }
 
 #[no_mangle]
 extern "C" fn ulua_lock(l: *mut lua_State) {
-    get_extra(l).spin.lock();
+    shared(l).lock.lock();
 }
 
 #[no_mangle]
 extern "C" fn ulua_unlock(l: *mut lua_State) {
-    get_extra(l).spin.unlock();
+    shared(l).lock.unlock();
 }
 
 #[no_mangle]
 extern "C" fn ulua_init_lock(l: *mut lua_State) {
-    *get_extra(l) = Extra { spin: SpinLock::new() };
-}
\ No newline at end of file
+    // f_luaopen calls this last, once nothing can fail, so a failing
+    // lua_newstate never allocates it and close_state never needs to free it
+    let shared = Box::new(Shared {
+        lock: TicketLock::new(),
+        extra: Mutex::new(None),
+        allocator: AtomicPtr::new(ptr::null_mut()),
+        limits: Mutex::default(),
+        interrupt: InterruptFlag::new(l),
+    });
+    unsafe { *extra_space(l) = Box::into_raw(shared); }
+}
+
+#[no_mangle]
+extern "C" fn ulua_init_thread(l: *mut lua_State, l1: *mut lua_State) {
+    unsafe { *extra_space(l1) = *extra_space(l); }
+}
+
+#[no_mangle]
+extern "C" fn ulua_free_thread(l: *mut lua_State, l1: *mut lua_State) {
+    shared(l).interrupt.freed(l1);
+}
+
+#[no_mangle]
+extern "C" fn ulua_close_lock(l: *mut lua_State) {
+    // lua_close holds the lock until the state is freed, nothing waits on it
+    let shared = unsafe { Box::from_raw(*extra_space(l)) };
+    // interrupt handles may outlive the state
+    shared.interrupt.close();
+}
+
+#[no_mangle]
+extern "C" fn ulua_resume(l: *mut lua_State) {
+    crate::limits::hook_resumed(l);
+}
+
+/// A value owned by a native frame, dropped in place if a Lua error jumps
+/// over that frame, see `State::with_guard`.
+struct Guard {
+    value: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+thread_local! {
+    /// The guards of the native frames on this thread's stack, innermost
+    /// last. Coroutines run on the same stack, so one list serves them all.
+    static GUARDS: RefCell<Vec<Guard>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Registers `value` as a guard until dropped, after which `value` has been
+/// dropped too. Guards must be dropped in the reverse order they were made.
+pub(crate) struct GuardEntry<'a, G> {
+    value: &'a mut mem::ManuallyDrop<G>,
+}
+
+impl<'a, G> GuardEntry<'a, G> {
+    pub(crate) fn new(value: &'a mut mem::ManuallyDrop<G>) -> GuardEntry<'a, G> {
+        unsafe fn drop_guard<G>(value: *mut ()) {
+            ptr::drop_in_place(value as *mut G);
+        }
+        let guard = Guard { value: &mut **value as *mut G as *mut (), drop: drop_guard::<G> };
+        GUARDS.with(|guards| guards.borrow_mut().push(guard));
+        GuardEntry { value }
+    }
+
+    pub(crate) fn get(&mut self) -> &mut G {
+        self.value
+    }
+}
+
+impl<G> Drop for GuardEntry<'_, G> {
+    fn drop(&mut self) {
+        let guard = GUARDS.with(|guards| guards.borrow_mut().pop());
+        debug_assert!(guard.is_some_and(|guard| guard.value == &mut **self.value as *mut G as *mut ()));
+        unsafe { mem::ManuallyDrop::drop(self.value); }
+    }
+}
+
+#[no_mangle]
+extern "C" fn ulua_guards(_: *mut lua_State) -> usize {
+    GUARDS.try_with(|guards| guards.borrow().len()).unwrap_or(0)
+}
+
+#[no_mangle]
+extern "C" fn ulua_throw(_: *mut lua_State, len: usize) {
+    // the frames owning the guards past len are about to be skipped
+    let _ = GUARDS.try_with(|guards| {
+        while guards.borrow().len() > len {
+            let guard = guards.borrow_mut().pop().unwrap();
+            unsafe { (guard.drop)(guard.value) }
+        }
+    });
+}
@@ -0,0 +1,514 @@
+use crate::*;
+use crate::ffi::*;
+
+use std::{any, fmt, mem, ptr};
+use std::any::TypeId;
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// A Rust type exposed to Lua as a full userdata. The metatable is built from
+/// the registration hooks the first time a value of the type is pushed, and
+/// cached in the registry, see `State::push_typed_userdata`.
+///
+/// ```
+/// # use macro_lua::*;
+/// struct Point { x: f64, y: f64 }
+///
+/// impl UserData for Point {
+///     fn add_methods(methods: &mut UserDataMethods<Self>) {
+///         methods.add_method("len", |_, this, ()| (this.x * this.x + this.y * this.y).sqrt());
+///     }
+///
+///     fn add_fields(fields: &mut UserDataFields<Self>) {
+///         fields.add_field_get("x", |_, this| this.x);
+///         fields.add_field_set("x", |_, this, x: f64| this.x = x);
+///     }
+/// }
+///
+/// let lua = Lua::new();
+/// lua.open_libs();
+/// lua.push_typed_userdata(Point { x: 0.0, y: 4.0 });
+/// lua.set_global("p");
+/// lua.do_string("p.x = 3 assert(p:len() == 5)").unwrap();
+/// ```
+pub trait UserData: Sized + 'static {
+    /// Name stored as `__name` in the metatable. Defaults to the type name
+    /// without its module path.
+    fn type_name() -> &'static str { short_type_name::<Self>() }
+
+    /// Registers methods, which Lua code calls as `value:name(...)`.
+    fn add_methods(methods: &mut UserDataMethods<Self>) {}
+
+    /// Registers fields, read and written as `value.name`.
+    fn add_fields(fields: &mut UserDataFields<Self>) {}
+
+    /// Registers metamethods such as `__tostring` or `__eq`. `__gc` is always
+    /// set to drop the value, and `__index` and `__newindex` are only
+    /// generated when not registered here.
+    fn add_meta_methods(methods: &mut UserDataMethods<Self>) {}
+}
+
+pub(crate) fn short_type_name<T>() -> &'static str {
+    let name = any::type_name::<T>();
+    let end = name.find('<').unwrap_or(name.len());
+    match name[..end].rfind("::") {
+        Some(i) => &name[i + 2..],
+        None => name,
+    }
+}
+
+/// Registration hook for methods and metamethods, see `UserData`.
+pub struct UserDataMethods<'a, T> {
+    table: Table<'a>,
+    marker: PhantomData<fn(&T)>,
+}
+
+impl<T: UserData> UserDataMethods<'_, T> {
+    /// Adds a method taking `&T`. Arguments after `self` are converted to `A`.
+    /// `self` stays borrowed while the method runs, see `State::with_guard`.
+    pub fn add_method<A, R, F>(&mut self, name: &str, method: F)
+        where A: FromLuaMulti, R: ToLuaMulti, F: Fn(&State, &T, A) -> R + 'static
+    {
+        let full_name = format!("{}:{}", T::type_name(), name);
+        self.table.set(name, self.table.0.named_closure(&full_name, move |state| {
+            let args = state.args::<A>(2);
+            let cell = check_this::<T>(state);
+            state.with_guard(|| cell.borrow().unwrap_or_else(|e| raise_arg_error(state, 1, e)), |this| {
+                method(state, this, args).to_lua(state);
+                R::COUNT as c_int
+            })
+        }));
+    }
+
+    /// Adds a method taking `&mut T`.
+    pub fn add_method_mut<A, R, F>(&mut self, name: &str, mut method: F)
+        where A: FromLuaMulti, R: ToLuaMulti, F: FnMut(&State, &mut T, A) -> R + 'static
+    {
+        let full_name = format!("{}:{}", T::type_name(), name);
+        self.table.set(name, self.table.0.named_closure(&full_name, move |state| {
+            let args = state.args::<A>(2);
+            let cell = check_this::<T>(state);
+            state.with_guard(|| cell.borrow_mut().unwrap_or_else(|e| raise_arg_error(state, 1, e)), |this| {
+                method(state, this, args).to_lua(state);
+                R::COUNT as c_int
+            })
+        }));
+    }
+
+    /// Adds a function that does not take `self`, e.g. a constructor stored in
+    /// the methods table.
+    pub fn add_function<A, R, F>(&mut self, name: &str, function: F)
+        where A: FromLuaMulti, R: ToLuaMulti, F: Fn(&State, A) -> R + 'static
+    {
+        let full_name = format!("{}.{}", T::type_name(), name);
+        self.table.set(name, self.table.0.named_closure(&full_name, move |state| {
+            function(state, state.args::<A>(1)).to_lua(state);
+            R::COUNT as c_int
+        }));
+    }
+}
+
+/// Registration hook for fields, see `UserData`.
+pub struct UserDataFields<'a, T> {
+    getters: Table<'a>,
+    setters: Table<'a>,
+    marker: PhantomData<fn(&T)>,
+}
+
+impl<T: UserData> UserDataFields<'_, T> {
+    /// Adds a readable field.
+    pub fn add_field_get<R, F>(&mut self, name: &str, getter: F)
+        where R: ToLua, F: Fn(&State, &T) -> R + 'static
+    {
+        let full_name = format!("{}.{}", T::type_name(), name);
+        self.getters.set(name, self.getters.0.named_closure(&full_name, move |state| {
+            let cell = check_this::<T>(state);
+            state.with_guard(|| cell.borrow().unwrap_or_else(|e| raise_arg_error(state, 1, e)), |this| {
+                state.push(getter(state, this));
+                1
+            })
+        }));
+    }
+
+    /// Adds a writable field. The assigned value is converted to `V`.
+    pub fn add_field_set<V, F>(&mut self, name: &str, mut setter: F)
+        where V: FromLua, F: FnMut(&State, &mut T, V) + 'static
+    {
+        let full_name = format!("{}.{}", T::type_name(), name);
+        self.setters.set(name, self.setters.0.named_closure(&full_name, move |state| {
+            let value = state.args::<V>(2);
+            let cell = check_this::<T>(state);
+            state.with_guard(|| cell.borrow_mut().unwrap_or_else(|e| raise_arg_error(state, 1, e)), |this| {
+                setter(state, this, value);
+                0
+            })
+        }));
+    }
+}
+
+/// The receiver of a `UserData` method, checked against `T`.
+fn check_this<T: UserData>(state: &State) -> &UserCell<T> {
+    state.userdata_cell::<T>(1, T::type_name()).unwrap_or_else(|e| raise_arg_error(state, 1, e))
+}
+
+/// Raises `bad argument #arg (msg)`. The formatted message is dropped before
+/// the error is thrown, as the Rust frames it lives in are not unwound.
+fn raise_arg_error(state: &State, arg: Index, msg: impl fmt::Display) -> ! {
+    let msg = msg.to_string();
+    state.push_string(&msg);
+    drop(msg);
+    unsafe { luaL_argerror(state.as_ptr(), arg, lua_tolstring(state.as_ptr(), -1, ptr::null_mut())) };
+    unreachable!()
+}
+
+/// `__gc` of a userdata pushed by `push_userdata::<T>`. Lua code can get
+/// hold of it and call it, so it checks the type of its argument and drops
+/// the value at most once. A value that is still borrowed is not dropped.
+#[doc(hidden)]
+pub unsafe extern "C" fn gc_userdata<T: 'static>(l: *mut lua_State) -> c_int {
+    match State::from_ref(&l).userdata_cell::<T>(1, "") {
+        Ok(cell) => catch_panic(l, || { cell.drop_value(); 0 }),
+        Err(_) => 0,
+    }
+}
+
+/// `__index` with the methods table as upvalue 1 and the getters as upvalue 2.
+unsafe extern "C" fn index_userdata(l: *mut lua_State) -> c_int {
+    lua_pushvalue(l, 2);
+    if lua_rawget(l, lua_upvalueindex(1)) != LUA_TNIL { return 1; }
+    lua_pushvalue(l, 2);
+    if lua_rawget(l, lua_upvalueindex(2)) == LUA_TNIL { return 1; }
+    lua_pushvalue(l, 1);
+    lua_call(l, 1, 1);
+    1
+}
+
+/// `__newindex` with the setters table as upvalue 1 and the type name as
+/// upvalue 2.
+unsafe extern "C" fn newindex_userdata(l: *mut lua_State) -> c_int {
+    lua_pushvalue(l, 2);
+    if lua_rawget(l, lua_upvalueindex(1)) == LUA_TNIL {
+        let state = State::from_ptr(l);
+        let msg = format!("no writable field '{}' in {}",
+                          state.to_str(2).as_deref().unwrap_or("?"), state.to_str(lua_upvalueindex(2)).unwrap_or_default());
+        state.push_string(&msg);
+        // the error does not unwind the Rust frames
+        drop(msg);
+        state.error();
+    }
+    lua_pushvalue(l, 1);
+    lua_pushvalue(l, 3);
+    lua_call(l, 2, 0);
+    0
+}
+
+/// The `InitMetatable` of a `UserData` type, for `push_userdata`.
+pub fn init_userdata_metatable<T: UserData>(meta: Table, state: &State) {
+    let methods = state.table(0, 0);
+    T::add_methods(&mut UserDataMethods { table: Table(methods.0), marker: PhantomData });
+    let getters = state.table(0, 0);
+    let setters = state.table(0, 0);
+    T::add_fields(&mut UserDataFields { getters: Table(getters.0), setters: Table(setters.0), marker: PhantomData });
+    T::add_meta_methods(&mut UserDataMethods { table: Table(meta.0), marker: PhantomData });
+
+    meta.set("__name", T::type_name());
+    meta.set("__gc", gc_userdata::<T> as CFunction);
+    if meta.get("__metatable").is_nil() { meta.set("__metatable", false); }
+    if meta.get("__index").is_nil() {
+        if state.table_is_empty(getters.0.index) {
+            meta.set("__index", methods.0);
+        } else {
+            state.push_value(methods.0.index);
+            state.push_value(getters.0.index);
+            state.push_cclosure(Some(index_userdata), 2);
+            meta.set("__index", TopRef(state.val(-1)));
+        }
+    }
+    if meta.get("__newindex").is_nil() && !state.table_is_empty(setters.0.index) {
+        state.push_value(setters.0.index);
+        state.push_string(T::type_name());
+        state.push_cclosure(Some(newindex_userdata), 2);
+        meta.set("__newindex", TopRef(state.val(-1)));
+    }
+    state.set_top(meta.0.index);
+}
+
+impl State {
+    /// [-0, +1, m] Pushes `data` as a full userdata with the metatable of its
+    /// `UserData` implementation, which drops it when collected.
+    pub fn push_typed_userdata<T: UserData>(&self, data: T) {
+        self.push_userdata(data, Some(init_userdata_metatable::<T>));
+    }
+}
+
+static TYPE_KEY: u8 = 0;
+
+/// Registry key of the metatables of `push_userdata`, keyed by the bytes of
+/// the `TypeId` of their type, see `push_type_key`.
+static METATABLES_KEY: u8 = 0;
+
+/// `UserCell::borrow` once the value has been dropped.
+const DROPPED: isize = isize::MIN;
+
+/// Layout of a userdata pushed by `push_userdata`. The value comes first, so
+/// `to_userdata` keeps pointing at it.
+#[repr(C)]
+pub(crate) struct UserCell<T> {
+    value: UnsafeCell<T>,
+    /// Number of live shared borrows, -1 while mutably borrowed, or
+    /// `DROPPED`.
+    borrow: Cell<isize>,
+}
+
+impl<T> UserCell<T> {
+    pub(crate) fn new(value: T) -> UserCell<T> {
+        UserCell { value: UnsafeCell::new(value), borrow: Cell::new(0) }
+    }
+
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *mut T { self.value.get() }
+
+    fn borrow(&self) -> Result<UserRef<'_, T>, UserDataError> {
+        match self.borrow.get() {
+            -1 => Err(UserDataError::BorrowedMut),
+            DROPPED => Err(UserDataError::Dropped),
+            n => { self.borrow.set(n + 1); Ok(UserRef { cell: self, anchor: Anchor(None) }) }
+        }
+    }
+
+    fn borrow_mut(&self) -> Result<UserRefMut<'_, T>, UserDataError> {
+        match self.borrow.get() {
+            0 => { self.borrow.set(-1); Ok(UserRefMut { cell: self, anchor: Anchor(None) }) }
+            DROPPED => Err(UserDataError::Dropped),
+            _ => Err(UserDataError::Borrowed),
+        }
+    }
+
+    /// Drops the value unless it is borrowed or already dropped.
+    fn drop_value(&self) {
+        if self.borrow.get() == 0 {
+            self.borrow.set(DROPPED);
+            unsafe { ptr::drop_in_place(self.as_ptr()); }
+        }
+    }
+}
+
+/// Why a userdata could not be borrowed as a `T`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserDataError {
+    /// The value is not a userdata holding a `T`.
+    TypeMismatch { expected: String, found: String },
+    /// A mutable borrow was requested while the value is borrowed.
+    Borrowed,
+    /// The value is mutably borrowed, e.g. by a method further up the stack.
+    BorrowedMut,
+    /// The value was dropped by its `__gc` metamethod.
+    Dropped,
+}
+
+impl fmt::Display for UserDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserDataError::TypeMismatch { expected, found } => write!(f, "{} expected, got {}", expected, found),
+            UserDataError::Borrowed => f.write_str("userdata already borrowed"),
+            UserDataError::BorrowedMut => f.write_str("userdata already mutably borrowed"),
+            UserDataError::Dropped => f.write_str("userdata already dropped"),
+        }
+    }
+}
+
+impl std::error::Error for UserDataError {}
+
+/// A shared borrow of a userdata value, released on drop. The value is kept
+/// alive until then, even if it is removed from the stack.
+pub struct UserRef<'a, T> {
+    cell: &'a UserCell<T>,
+    anchor: Anchor<'a>,
+}
+
+impl<T> Deref for UserRef<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &*self.cell.as_ptr() } }
+}
+
+impl<T> Drop for UserRef<'_, T> {
+    fn drop(&mut self) { self.cell.borrow.set(self.cell.borrow.get() - 1); }
+}
+
+/// A mutable borrow of a userdata value, released on drop, see `UserRef`.
+pub struct UserRefMut<'a, T> {
+    cell: &'a UserCell<T>,
+    anchor: Anchor<'a>,
+}
+
+impl<T> Deref for UserRefMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T { unsafe { &*self.cell.as_ptr() } }
+}
+
+impl<T> DerefMut for UserRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.cell.as_ptr() } }
+}
+
+impl<T> Drop for UserRefMut<'_, T> {
+    fn drop(&mut self) { self.cell.borrow.set(0); }
+}
+
+/// A registry reference keeping a borrowed userdata from being collected.
+/// Borrows made by this crate while the value is known to stay on the stack
+/// go without one.
+struct Anchor<'a>(Option<(&'a State, Reference)>);
+
+impl Anchor<'_> {
+    /// [-0, +0, m]
+    fn new(state: &State, index: Index) -> Anchor<'_> {
+        state.push_value(index);
+        Anchor(Some((state, state.reference(LUA_REGISTRYINDEX))))
+    }
+}
+
+impl Drop for Anchor<'_> {
+    fn drop(&mut self) {
+        if let Some((state, reference)) = self.0 { state.unreference(LUA_REGISTRYINDEX, reference); }
+    }
+}
+
+/// Metatable of userdata pushed without one, so they still carry a type.
+pub(crate) fn identity_metatable(meta: Table, state: &State) {}
+
+impl State {
+    /// [-0, +0, m] Sets the metatable of `T` on the userdata on top of the
+    /// stack. It is built by `init` the first time, and shared by all values
+    /// of `T` from then on.
+    pub(crate) fn set_type_metatable<T: 'static>(&self, init: InitMetatable) {
+        self.check_stack_msg(4, "userdata metatable");
+        if self.raw_getp(LUA_REGISTRYINDEX, &METATABLES_KEY) != Type::Table {
+            self.pop(1);
+            self.create_table(0, 0);
+            self.push_value(-1);
+            self.raw_setp(LUA_REGISTRYINDEX, &METATABLES_KEY);
+        }
+        self.push_type_key::<T>();
+        if self.raw_get(-2) != Type::Table {
+            self.pop(1);
+            init(self.table(0, 0), self);
+            assert!(self.type_of(-1) == Type::Table);
+            self.push_type_key::<T>();
+            self.push_value(-2);
+            self.raw_set(-4);
+        }
+        self.remove(-2);
+        self.set_metatable(-2);
+    }
+
+    /// [-0, +1, m] Pushes the key of `T` among the cached metatables. The
+    /// address of the `InitMetatable` cannot serve, as functions with the
+    /// same code may be merged.
+    fn push_type_key<T: 'static>(&self) {
+        let id = TypeId::of::<T>();
+        let bytes = unsafe { std::slice::from_raw_parts(&id as *const TypeId as *const u8, mem::size_of::<TypeId>()) };
+        self.push_bytes(bytes);
+    }
+
+    /// Records `T` as the type of the userdata at `index`, unless its
+    /// metatable already names one.
+    pub(crate) fn set_userdata_type<T: 'static>(&self, index: Index) {
+        self.balance_with(|s| {
+            if !s.get_metatable(index) { return; }
+            if s.raw_getp(-1, &TYPE_KEY) == Type::Nil {
+                unsafe { ptr::write(s.new_userdata_typed::<TypeId>(), TypeId::of::<T>()); }
+                s.raw_setp(-3, &TYPE_KEY);
+                s.push_string(short_type_name::<T>());
+                if s.get_field(-3, "__name") == Type::Nil {
+                    s.pop(1);
+                    s.set_field(-3, "__name");
+                }
+            } else {
+                let id = unsafe { *(s.to_userdata(-1) as *const TypeId) };
+                assert!(id == TypeId::of::<T>(), "metatable shared by different userdata types");
+            }
+        })
+    }
+
+    /// The name of the value at `index` for error messages: the `__name`
+    /// metafield if it is a string, or else the type name.
+    pub fn describe_value(&self, index: Index) -> String {
+        if self.get_metafield(index, "__name") {
+            let name = self.to_str(-1);
+            self.pop(1);
+            if let Some(name) = name { return name; }
+        }
+        if self.is_light_userdata(index) { "light userdata".into() } else { self.typename_at(index).into() }
+    }
+
+    fn userdata_cell<T: 'static>(&self, index: Index, expected: &str) -> Result<&UserCell<T>, UserDataError> {
+        let p = self.to_userdata(index);
+        let matches = !p.is_null() && !self.is_light_userdata(index) && self.get_metatable(index) && {
+            let found = self.raw_getp(-1, &TYPE_KEY) == Type::Userdata
+                && unsafe { *(self.to_userdata(-1) as *const TypeId) } == TypeId::of::<T>();
+            self.pop(2);
+            found
+        };
+        if matches { Ok(unsafe { &*(p as *const UserCell<T>) }) } else {
+            Err(UserDataError::TypeMismatch { expected: expected.into(), found: self.describe_value(index) })
+        }
+    }
+
+    /// [-0, +0, m] Borrows the `T` at `index`, which must have been pushed
+    /// with `push_userdata::<T>`. Fails if the value is mutably borrowed.
+    pub fn borrow_userdata<T: 'static>(&self, index: Index) -> Result<UserRef<'_, T>, UserDataError> {
+        let mut this = self.userdata_ref::<T>(index)?;
+        this.anchor = Anchor::new(self, index);
+        Ok(this)
+    }
+
+    /// [-0, +0, m] Mutably borrows the `T` at `index`. Fails if the value is
+    /// borrowed.
+    pub fn borrow_userdata_mut<T: 'static>(&self, index: Index) -> Result<UserRefMut<'_, T>, UserDataError> {
+        let mut this = self.userdata_mut::<T>(index)?;
+        this.anchor = Anchor::new(self, index);
+        Ok(this)
+    }
+
+    /// Like `borrow_userdata`, for callers that keep the value on the stack
+    /// while the borrow lives.
+    pub(crate) fn userdata_ref<T: 'static>(&self, index: Index) -> Result<UserRef<'_, T>, UserDataError> {
+        self.userdata_cell::<T>(index, short_type_name::<T>())?.borrow()
+    }
+
+    /// Like `borrow_userdata_mut`, without keeping the value alive.
+    pub(crate) fn userdata_mut<T: 'static>(&self, index: Index) -> Result<UserRefMut<'_, T>, UserDataError> {
+        self.userdata_cell::<T>(index, short_type_name::<T>())?.borrow_mut()
+    }
+
+    /// Like `borrow_userdata`, but raises an argument error on failure.
+    pub fn check_userdata_ref<T: 'static>(&self, arg: Index) -> UserRef<'_, T> {
+        self.borrow_userdata(arg).unwrap_or_else(|e| raise_arg_error(self, arg, e))
+    }
+
+    /// Like `borrow_userdata_mut`, but raises an argument error on failure,
+    /// e.g. `bad argument #1 to 'len' (Point expected, got Rect)`.
+    pub fn check_userdata_mut<T: 'static>(&self, arg: Index) -> UserRefMut<'_, T> {
+        self.borrow_userdata_mut(arg).unwrap_or_else(|e| raise_arg_error(self, arg, e))
+    }
+
+    /// Calls `f` with `self` of a method, the `T` at index 1, mutably
+    /// borrowed through `with_guard`. Used by `metatable!`.
+    #[doc(hidden)]
+    pub fn with_self<T: 'static>(&self, f: impl FnOnce(&mut T) -> c_int) -> c_int {
+        let cell = self.userdata_cell::<T>(1, short_type_name::<T>()).unwrap_or_else(|e| raise_arg_error(self, 1, e));
+        self.with_guard(|| cell.borrow_mut().unwrap_or_else(|e| raise_arg_error(self, 1, e)), |this| f(this))
+    }
+
+    /// Calls `f` with the guard returned by `guard`, typically a userdata
+    /// borrow, and drops it afterwards. If `f` raises an error or yields,
+    /// the guard is dropped before the error jumps over this frame, so that
+    /// the value does not stay borrowed; dropping it must not use the state.
+    #[doc(hidden)]
+    pub fn with_guard<G, F: FnOnce(&mut G) -> c_int>(&self, guard: impl FnOnce() -> G, f: F) -> c_int {
+        let mut guard = mem::ManuallyDrop::new(guard());
+        let mut entry = crate::ulua::GuardEntry::new(&mut guard);
+        f(entry.get())
+    }
+}
@@ -0,0 +1,92 @@
+use macro_lua::*;
+use std::fs;
+use std::path::PathBuf;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("macro-lua-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn entries(dir: &PathBuf) -> Vec<PathBuf> {
+    let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    entries.sort();
+    entries
+}
+
+fn run(lua: &Lua, cache: &ChunkCache, source: &str) -> i64 {
+    let f = cache.load(lua, source, "=chunk").unwrap();
+    let result = f.call::<_, i64>(()).unwrap();
+    lua.set_top(0);
+    result
+}
+
+#[test]
+fn cached_chunks_keep_line_numbers_unless_stripped() {
+    let lua = Lua::new();
+    let source = "local x = nil\nreturn x.y";
+    for &strip in &[false, true] {
+        let cache = ChunkCache::new().strip(strip);
+        let first = cache.load(&lua, source, "=chunk").unwrap().call::<_, ()>(()).unwrap_err();
+        assert!(first.message().contains("chunk:2"), "{}", first);
+        lua.set_top(0);
+        let second = cache.load(&lua, source, "=chunk").unwrap().call::<_, ()>(()).unwrap_err();
+        assert_eq!(second.message().contains("chunk:2"), !strip, "{}", second);
+        lua.set_top(0);
+    }
+}
+
+#[test]
+fn threads_can_store_the_same_chunk() {
+    use std::sync::{Arc, Barrier};
+
+    let dir = temp_dir("threads");
+    let barrier = Arc::new(Barrier::new(8));
+    let threads: Vec<_> = (0..8).map(|_| {
+        let (dir, barrier) = (dir.clone(), barrier.clone());
+        std::thread::spawn(move || {
+            let lua = Lua::new();
+            for i in 0..20 {
+                let cache = ChunkCache::with_dir(&dir).unwrap();
+                barrier.wait();
+                assert_eq!(run(&lua, &cache, &format!("return {}", i)), i);
+            }
+        })
+    }).collect();
+    for thread in threads { thread.join().unwrap(); }
+    let entries = entries(&dir);
+    assert_eq!(entries.len(), 20);
+    assert!(entries.iter().all(|p| p.extension() == Some("luac".as_ref())));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn entries_are_checked_against_the_source() {
+    let dir = temp_dir("collide");
+    let lua = Lua::new();
+    let cache = ChunkCache::with_dir(&dir).unwrap();
+    assert_eq!(run(&lua, &cache, "return 1"), 1);
+    let one = entries(&dir);
+    assert_eq!(run(&lua, &cache, "return 2"), 2);
+    let both = entries(&dir);
+    assert_eq!(both.len(), 2);
+    let two = both.iter().find(|p| !one.contains(p)).unwrap();
+
+    // an entry of another chunk stored under this key is not used
+    fs::copy(two, &one[0]).unwrap();
+    let fresh = ChunkCache::with_dir(&dir).unwrap();
+    assert_eq!(run(&lua, &fresh, "return 1"), 1);
+    assert_ne!(fs::read(&one[0]).unwrap(), fs::read(two).unwrap());
+
+    // nor is plain bytecode, e.g. from an older version of the cache
+    lua.load_string("return 2");
+    fs::write(&one[0], lua.dump(-1, true)).unwrap();
+    lua.set_top(0);
+    let fresh = ChunkCache::with_dir(&dir).unwrap();
+    assert_eq!(run(&lua, &fresh, "return 1"), 1);
+    assert_eq!(run(&lua, &ChunkCache::with_dir(&dir).unwrap(), "return 1"), 1);
+
+    fresh.clear().unwrap();
+    assert!(entries(&dir).is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
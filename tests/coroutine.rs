@@ -0,0 +1,54 @@
+use macro_lua::*;
+use std::rc::Rc;
+
+#[test]
+fn continuations_run_on_resume() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let f = lua.rust_closure(|s: &State| s.co_yieldk(0, |s, status| {
+        assert_eq!(status, ThreadStatus::Yield);
+        s.push_integer(s.to_integer(-1) * 2);
+        1
+    }));
+    lua.global().set("f", f);
+    lua.do_string(r#"
+        local co = coroutine.wrap(function() return f() end)
+        co()
+        assert(co(21) == 42)
+    "#).unwrap();
+}
+
+#[test]
+fn yielding_outside_a_coroutine_drops_the_continuation() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let token = Rc::new(());
+    let captured = token.clone();
+    let f = lua.rust_closure(move |s: &State| {
+        let token = captured.clone();
+        s.co_yieldk(0, move |_, _| { drop(token); 0 })
+    });
+    lua.global().set("f", f);
+    lua.do_string(r#"
+        for i = 1, 10 do
+            local ok, err = pcall(f)
+            assert(not ok and err:find("outside a coroutine"), err)
+        end
+    "#).unwrap();
+    assert_eq!(Rc::strong_count(&token), 2);
+}
+
+#[test]
+fn unfinished_continuations_are_dropped_with_the_coroutine() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let token = Rc::new(());
+    let captured = token.clone();
+    let f = lua.rust_closure(move |s: &State| {
+        let token = captured.clone();
+        s.co_yieldk(0, move |_, _| { drop(token); 0 })
+    });
+    lua.global().set("f", f);
+    lua.do_string("coroutine.wrap(f)() collectgarbage() collectgarbage()").unwrap();
+    assert_eq!(Rc::strong_count(&token), 2);
+}
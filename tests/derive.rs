@@ -0,0 +1,138 @@
+use macro_lua::*;
+
+#[derive(Debug, Clone, PartialEq, ToLua, FromLua)]
+struct Server {
+    host: String,
+    #[lua(rename = "portNumber")]
+    port: u16,
+    #[lua(default)]
+    tags: Vec<String>,
+    #[lua(skip)]
+    cached: Option<i64>,
+    #[lua(flatten)]
+    limits: Limits,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, ToLua, FromLua)]
+struct Limits {
+    #[lua(default = "default_timeout")]
+    timeout: i64,
+}
+
+fn default_timeout() -> i64 { 30 }
+
+#[derive(Debug, Clone, PartialEq, ToLua, FromLua)]
+struct Pair(i64, String);
+
+#[derive(Debug, Clone, PartialEq, ToLua, FromLua)]
+struct Meters(f64);
+
+#[derive(Debug, Clone, PartialEq, ToLua, FromLua)]
+enum External {
+    Unit,
+    #[lua(rename = "new")]
+    Newtype(i64),
+    Struct { x: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, ToLua, FromLua)]
+#[lua(tag = "kind")]
+enum Internal {
+    Empty,
+    Point { x: i64, y: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, ToLua, FromLua)]
+#[lua(tag = "t", content = "c")]
+enum Adjacent {
+    Num(i64),
+    Pair(i64, i64),
+}
+
+fn round_trip<T: ToLua + FromLua + Clone + PartialEq + std::fmt::Debug>(lua: &Lua, value: T) {
+    lua.push(value.clone());
+    assert_eq!(lua.try_arg::<T>(-1).unwrap(), value);
+    lua.pop(1);
+}
+
+fn server() -> Server {
+    Server {
+        host: "localhost".into(),
+        port: 8080,
+        tags: vec!["a".into(), "b".into()],
+        cached: None,
+        limits: Limits { timeout: 5 },
+    }
+}
+
+#[test]
+fn values_round_trip() {
+    let lua = Lua::new();
+    round_trip(&lua, server());
+    round_trip(&lua, Pair(1, "one".into()));
+    round_trip(&lua, Meters(2.5));
+    round_trip(&lua, External::Unit);
+    round_trip(&lua, External::Newtype(3));
+    round_trip(&lua, External::Struct { x: 4 });
+    round_trip(&lua, Internal::Empty);
+    round_trip(&lua, Internal::Point { x: 1, y: 2 });
+    round_trip(&lua, Adjacent::Num(7));
+    round_trip(&lua, Adjacent::Pair(8, 9));
+    assert_eq!(lua.get_top(), 0);
+}
+
+#[test]
+fn attributes_shape_the_table() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.global().set("s", Server { cached: Some(1), ..server() });
+    lua.global().set("e", External::Newtype(3));
+    lua.global().set("i", Internal::Point { x: 1, y: 2 });
+    lua.global().set("a", Adjacent::Pair(8, 9));
+    lua.do_string(r#"
+        assert(s.host == "localhost" and s.portNumber == 8080 and s.port == nil)
+        assert(s.cached == nil and s.timeout == 5 and s.limits == nil)
+        assert(#s.tags == 2 and s.tags[2] == "b")
+        assert(e.new == 3)
+        assert(i.kind == "Point" and i.x == 1 and i.y == 2)
+        assert(a.t == "Pair" and a.c[1] == 8 and a.c[2] == 9)
+    "#).unwrap();
+}
+
+#[test]
+fn missing_fields_use_defaults() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("s = { host = 'h', portNumber = 1, cached = 5 }").unwrap();
+    lua.get_global("s");
+    let s = lua.try_arg::<Server>(-1).unwrap();
+    assert_eq!(s.tags, Vec::<String>::new());
+    assert_eq!(s.cached, None);
+    assert_eq!(s.limits.timeout, 30);
+}
+
+#[test]
+fn errors_report_the_path() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("s = { host = 'h', portNumber = 1, tags = { 'a', false } }").unwrap();
+    lua.get_global("s");
+    let err = lua.try_arg::<Server>(-1).unwrap_err();
+    assert_eq!(err.path_string(), "tags[2]");
+    assert_eq!(err.found, "boolean");
+
+    lua.do_string("i = { kind = 'Circle' }").unwrap();
+    lua.get_global("i");
+    assert!(lua.try_arg::<Internal>(-1).is_err());
+    assert!(lua.arg::<External>(-1).is_none());
+}
+
+#[test]
+fn compile_pass() {
+    trybuild::TestCases::new().pass("tests/derive/pass/*.rs");
+}
+
+#[test]
+fn compile_fail() {
+    trybuild::TestCases::new().compile_fail("tests/derive/fail/*.rs");
+}
@@ -0,0 +1,9 @@
+use macro_lua::FromLua;
+
+#[derive(FromLua)]
+#[lua(content = "c")]
+enum E {
+    A(i64),
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use macro_lua::FromLua;
+
+#[derive(FromLua)]
+struct S {
+    #[lua(rename = "b")]
+    a: i64,
+    b: i64,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use macro_lua::FromLua;
+
+struct NotLua;
+
+#[derive(FromLua)]
+struct S {
+    a: NotLua,
+}
+
+fn main() {}
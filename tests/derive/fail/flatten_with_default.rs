@@ -0,0 +1,14 @@
+use macro_lua::FromLua;
+
+#[derive(FromLua)]
+struct Inner {
+    a: i64,
+}
+
+#[derive(FromLua)]
+struct S {
+    #[lua(flatten, default)]
+    inner: Inner,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use macro_lua::ToLua;
+
+#[derive(ToLua)]
+#[lua(tag = "kind")]
+enum E {
+    Pair(i64, i64),
+}
+
+fn main() {}
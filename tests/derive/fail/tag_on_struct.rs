@@ -0,0 +1,9 @@
+use macro_lua::ToLua;
+
+#[derive(ToLua)]
+#[lua(tag = "kind")]
+struct S {
+    a: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use macro_lua::ToLua;
+
+#[derive(ToLua)]
+union U {
+    a: u32,
+    b: f32,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use macro_lua::ToLua;
+
+#[derive(ToLua)]
+struct S {
+    #[lua(renamed = "x")]
+    a: i64,
+}
+
+fn main() {}
@@ -0,0 +1,28 @@
+use macro_lua::{FromLua, ToLua};
+
+#[derive(Default, ToLua, FromLua)]
+struct Inner {
+    #[lua(rename = "n")]
+    count: i64,
+}
+
+#[derive(ToLua, FromLua)]
+struct Outer {
+    #[lua(default = "String::new")]
+    name: String,
+    #[lua(skip)]
+    #[allow(dead_code)]
+    hidden: Vec<u8>,
+    #[lua(flatten)]
+    inner: Inner,
+}
+
+#[derive(ToLua, FromLua)]
+#[lua(tag = "type")]
+enum Shape {
+    #[lua(rename = "circle")]
+    Circle { r: f64 },
+    Empty,
+}
+
+fn main() {}
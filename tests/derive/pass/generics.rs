@@ -0,0 +1,22 @@
+use macro_lua::{FromLua, Lua, ToLua};
+
+#[derive(ToLua, FromLua)]
+struct Wrapper<T> {
+    inner: T,
+    items: Vec<T>,
+}
+
+#[derive(ToLua, FromLua)]
+enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+fn main() {
+    let lua = Lua::new();
+    lua.push(Wrapper { inner: 1i64, items: vec![2, 3] });
+    let w = lua.arg::<Wrapper<i64>>(-1).unwrap();
+    assert_eq!(w.items.len(), 2);
+    lua.push(Either::<i64, String>::Right("r".into()));
+    assert!(matches!(lua.arg::<Either<i64, String>>(-1), Some(Either::Right(_))));
+}
@@ -0,0 +1,117 @@
+use macro_lua::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) { return output; }
+    }
+}
+
+/// Pending for `n` polls, then ready.
+struct Pending(u32);
+
+impl Future for Pending {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+        if self.0 == 0 { return Poll::Ready(()); }
+        self.0 -= 1;
+        Poll::Pending
+    }
+}
+
+fn setup() -> Lua {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.async_function(|_, (a, b): (i64, i64)| async move {
+        Pending(3).await;
+        Ok::<_, std::io::Error>(a + b)
+    });
+    lua.set_global("add");
+    lua.async_function(|_, message: String| async move {
+        Pending(1).await;
+        Err::<(), _>(std::io::Error::new(std::io::ErrorKind::Other, message))
+    });
+    lua.set_global("fail");
+    lua
+}
+
+fn coroutine(lua: &Lua, source: &str) -> Coroutine {
+    assert_eq!(lua.load_string(source), ThreadStatus::Ok);
+    let f = OwnedFunction::new(lua, -1).unwrap();
+    lua.pop(1);
+    Coroutine::from_function(lua, &f)
+}
+
+#[test]
+fn async_functions_resolve() {
+    let lua = setup();
+    let co = coroutine(&lua, "return add(1, 2) + add(3, 4)");
+    assert_eq!(block_on(co.into_future::<i64>()).unwrap(), 10);
+}
+
+#[test]
+fn errors_are_raised_in_lua() {
+    let lua = setup();
+    let co = coroutine(&lua, r#"
+        local ok, err = pcall(fail, "boom")
+        assert(not ok and tostring(err):find("boom"))
+        fail("again")
+    "#);
+    let err = block_on(co.into_future::<()>()).unwrap_err();
+    assert!(err.message().contains("again"), "{}", err);
+}
+
+#[test]
+fn plain_yields_go_back_to_the_executor() {
+    let lua = setup();
+    let co = coroutine(&lua, "coroutine.yield() return add(1, 1)");
+    assert_eq!(block_on(co.into_future::<i64>()).unwrap(), 2);
+}
+
+#[test]
+fn calls_outside_a_coroutine_fail() {
+    let lua = setup();
+    let err = lua.do_string("add(1, 2)").unwrap_err();
+    assert!(err.message().contains("outside a coroutine"), "{}", err);
+}
+
+#[test]
+fn cancelled_calls_drop_their_future() {
+    use std::rc::Rc;
+
+    struct Guard(Rc<()>);
+
+    let lua = setup();
+    let guards = Rc::new(());
+    let held = guards.clone();
+    lua.async_function(move |_, ()| {
+        let guard = Guard(held.clone());
+        async move {
+            Pending(u32::MAX).await;
+            drop(guard);
+            Ok::<_, std::io::Error>(())
+        }
+    });
+    lua.set_global("forever");
+
+    let mut future = Box::pin(coroutine(&lua, "forever()").into_future::<()>());
+    let waker = Waker::from(Arc::new(NoopWaker));
+    assert!(future.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert_eq!(Rc::strong_count(&guards), 3);
+
+    drop(future);
+    lua.gc(GcOption::Collect, 0);
+    assert_eq!(Rc::strong_count(&guards), 2);
+}
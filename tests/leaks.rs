@@ -0,0 +1,81 @@
+//! Errors raised from native code longjmp over the Rust frames, so anything
+//! they own at that point is never dropped. These tests count the bytes the
+//! current thread allocates through Rust around such errors.
+
+use macro_lua::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = ALLOCATED.try_with(|a| a.set(a.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The bytes allocated and not freed by `f`.
+fn leaked(f: impl FnOnce()) -> isize {
+    let before = ALLOCATED.with(Cell::get);
+    f();
+    ALLOCATED.with(Cell::get) - before
+}
+
+struct Point {
+    x: i64,
+}
+
+impl UserData for Point {
+    fn add_fields(fields: &mut UserDataFields<Self>) {
+        fields.add_field_get("x", |_, this| this.x);
+        fields.add_field_set("x", |_, this, x: i64| this.x = x);
+    }
+}
+
+#[test]
+fn missing_fields_do_not_leak() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.push_typed_userdata(Point { x: 0 });
+    lua.set_global("p");
+    let run = || lua.do_string("for i = 1, 1000 do pcall(function() p.missing = i end) end").unwrap();
+    run();
+    assert!(leaked(run) < 1000, "missing field errors leak");
+}
+
+#[test]
+fn failing_sandboxed_prints_do_not_leak() {
+    let lua = Lua::new();
+    let sandbox = Sandbox::new().allow_library(Library::Base).on_print(|_| ());
+    let env = sandbox.env(&lua);
+    sandbox.load(&lua, &env, r#"
+        local bad = setmetatable({}, {__tostring = function() error("no") end})
+        for i = 1, 1000 do pcall(print, string_arg, bad) end
+    "#, None).unwrap();
+    let f = OwnedFunction::new(&lua, -1).unwrap();
+    env.set("string_arg", "x".repeat(100));
+    let run = || f.call::<_, ()>(&lua, ()).unwrap();
+    run();
+    assert!(leaked(run) < 1000, "failing prints leak");
+}
+
+#[test]
+fn states_too_large_for_the_limit_do_not_leak() {
+    for limit in (0..16 << 10).step_by(512) {
+        let leaked = leaked(|| drop(StateBuilder::new().memory_limit(limit).build()));
+        assert_eq!(leaked, 0, "limit {}", limit);
+    }
+}
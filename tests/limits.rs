@@ -0,0 +1,162 @@
+use macro_lua::*;
+use macro_lua::ffi::{lua_Debug, lua_State};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+fn lua() -> Lua {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua
+}
+
+#[test]
+fn instruction_limit_stops_loops() {
+    let lua = lua();
+    lua.set_instruction_limit(10_000);
+    match lua.do_string("while true do end") {
+        Err(LuaError::Timeout(message)) => assert_eq!(message, "instruction limit exceeded"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn pcall_cannot_swallow_an_abort() {
+    let lua = lua();
+    lua.set_instruction_limit(10_000);
+    let result = lua.do_string("for i = 1, 10 do pcall(function() while true do end end) end");
+    assert!(matches!(result, Err(LuaError::Timeout(_))), "{:?}", result);
+}
+
+#[test]
+fn clearing_limits_allows_running_again() {
+    let lua = lua();
+    lua.set_instruction_limit(1000);
+    assert!(lua.do_string("for i = 1, 1e6 do end").is_err());
+    lua.clear_limits();
+    lua.do_string("for i = 1, 1e5 do end").unwrap();
+}
+
+static COUNT_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn count_hook(_: *mut lua_State, _: *mut lua_Debug) {
+    COUNT_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn limits_keep_an_earlier_count_hook() {
+    let lua = lua();
+    lua.set_hook(Some(count_hook), MASKCOUNT, 100);
+    lua.set_instruction_limit(100_000);
+    lua.do_string("for i = 1, 1000 do end").unwrap();
+    let events = COUNT_EVENTS.load(Ordering::Relaxed);
+    assert!(events >= 9, "{}", events);
+
+    lua.clear_limits();
+    assert!(lua.get_hook().unwrap() as usize == count_hook as *const () as usize);
+    assert!(lua.get_hook_mask() == MASKCOUNT && lua.get_hook_count() == 100);
+    lua.do_string("for i = 1, 1000 do end").unwrap();
+    assert!(COUNT_EVENTS.load(Ordering::Relaxed) >= events + 9);
+}
+
+static LINE_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn line_hook(_: *mut lua_State, _: *mut lua_Debug) {
+    LINE_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test]
+fn limits_keep_an_earlier_line_hook() {
+    let lua = lua();
+    lua.set_hook(Some(line_hook), MASKLINE, 0);
+    lua.set_deadline(Instant::now() + Duration::from_secs(60));
+    lua.do_string("local x = 1\nx = x + 1\nx = x + 1").unwrap();
+    assert_eq!(LINE_EVENTS.load(Ordering::Relaxed), 3);
+    lua.set_instruction_limit(10);
+    assert!(matches!(lua.do_string("while true do end"), Err(LuaError::Timeout(_))));
+    lua.clear_limits();
+    assert!(lua.get_hook_mask() == MASKLINE);
+}
+
+#[test]
+fn deadline_stops_loops() {
+    let lua = lua();
+    lua.set_deadline(Instant::now() + Duration::from_millis(20));
+    assert!(matches!(lua.do_string("while true do end"), Err(LuaError::Timeout(_))));
+}
+
+#[test]
+fn interrupt_callback_and_handle() {
+    let lua = lua();
+    let mut calls = 0;
+    lua.set_interrupt(move || { calls += 1; calls > 3 });
+    assert!(matches!(lua.do_string("while true do end"), Err(LuaError::Interrupted(_))));
+    lua.clear_limits();
+
+    let handle = lua.interrupt_handle();
+    let thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        handle.interrupt();
+    });
+    assert!(matches!(lua.do_string("while true do end"), Err(LuaError::Interrupted(_))));
+    thread.join().unwrap();
+}
+
+#[test]
+fn limits_apply_to_coroutines() {
+    let lua = lua();
+    lua.set_instruction_limit(10_000);
+    let result = lua.do_string("coroutine.wrap(function() while true do end end)()");
+    assert!(matches!(result, Err(LuaError::Timeout(_))), "{:?}", result);
+}
+
+#[test]
+fn interrupts_reach_running_coroutines() {
+    let lua = lua();
+    // the handle is made once the coroutine runs
+    let thread = std::rc::Rc::new(std::cell::Cell::new(None));
+    let spawned = thread.clone();
+    let start = lua.rust_closure(move |s: &State| {
+        let handle = s.interrupt_handle();
+        spawned.set(Some(std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.interrupt();
+        })));
+        0
+    });
+    lua.global().set("start", start);
+    let result = lua.do_string("coroutine.wrap(function() start() while true do end end)()");
+    assert!(matches!(result, Err(LuaError::Interrupted(_))), "{:?}", result);
+    thread.take().unwrap().join().unwrap();
+    lua.clear_limits();
+    lua.do_string("coroutine.wrap(function() for i = 1, 1e5 do end end)()").unwrap();
+}
+
+#[test]
+fn memory_limit_raises_memory_errors() {
+    let lua = StateBuilder::new().memory_limit(1 << 20).build().unwrap();
+    lua.open_libs();
+    let result = lua.do_string("local t = {} for i = 1, 1e7 do t[i] = i end");
+    assert!(matches!(result, Err(LuaError::Memory(_))), "{:?}", result);
+    let usage = lua.memory_usage().unwrap();
+    assert!(usage.used <= 1 << 20 && usage.peak <= 1 << 20);
+    lua.do_string("x = 1").unwrap();
+}
+
+#[test]
+fn unprotected_memory_errors_panic() {
+    let lua = StateBuilder::new().memory_limit(1 << 20).build().unwrap();
+    lua.open_libs();
+    // leave the memory used up
+    let result = lua.do_string("t = {} local s = ('x'):rep(1000) for i = 1, 1e7 do t[i] = s .. i end");
+    assert!(matches!(result, Err(LuaError::Memory(_))), "{:?}", result);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        lua.get_global("t");
+        for i in 1..100_000 {
+            lua.push_string(&format!("{:01000}", i));
+            lua.raw_seti(-2, i);
+        }
+    }));
+    let payload = result.unwrap_err();
+    let message = payload.downcast_ref::<String>().unwrap();
+    assert!(message.contains("not enough memory"), "{}", message);
+}
@@ -0,0 +1,107 @@
+use macro_lua::*;
+
+#[test]
+fn borrowed_handles_share_the_state() {
+    let lua = Lua::new();
+    let state = lua.state();
+    state.push(42);
+    assert_eq!(lua.get_top(), 1);
+    assert_eq!(lua.main_thread().as_ptr(), lua.as_ptr());
+    assert_eq!(lua.arg::<i64>(-1), Some(42));
+}
+
+#[test]
+fn native_functions_get_a_borrowed_state() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let f = lua.rust_closure(|s: &State| s.pushx(s.get_top() as i64 * 10));
+    lua.global().set("f", f);
+    lua.do_string("assert(f(1, 2, 3) == 30)").unwrap();
+}
+
+#[test]
+fn owned_refs_outlive_the_state() {
+    let lua = Lua::new();
+    lua.push("kept");
+    let owned = OwnedRef::new(&lua, -1);
+    assert!(owned.is_alive());
+    drop(lua);
+    assert!(!owned.is_alive());
+    // dropping after close must not touch the freed state
+    drop(owned);
+}
+
+#[test]
+#[should_panic(expected = "OwnedRef used after its Lua state was closed")]
+fn pushing_an_owned_ref_after_close_panics() {
+    let lua = Lua::new();
+    lua.push_nil();
+    let owned = OwnedRef::from_top(&lua);
+    let other = Lua::new();
+    drop(lua);
+    owned.push_to(&other);
+}
+
+#[test]
+fn chunks_load_with_an_environment_and_mode() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let env = lua.table(0, 0);
+    let f = lua.load_with_env("x = 1", Some("=env"), &env, Mode::Text).unwrap();
+    f.call::<_, ()>(()).unwrap();
+    env.get("x");
+    assert_eq!(lua.arg::<i64>(-1), Some(1));
+    assert_eq!(lua.get_global("x"), Type::Nil);
+    lua.set_top(0);
+
+    assert_eq!(lua.load_string("return 2"), ThreadStatus::Ok);
+    let binary = lua.dump(-1, true);
+    lua.set_top(0);
+    let env = lua.table(0, 0);
+    assert!(lua.load_with_env(&binary, Some("=env"), &env, Mode::Text).is_err());
+    let f = lua.load_with_env(&binary, Some("=env"), &env, Mode::Both).unwrap();
+    assert_eq!(f.call::<_, i64>(()).unwrap(), 2);
+}
+
+#[test]
+#[should_panic(expected = "cannot dump")]
+fn dumping_a_native_function_panics() {
+    let lua = Lua::new();
+    lua.rust_closure(|_: &State| 0);
+    lua.dump(-1, false);
+}
+
+#[test]
+fn errors_can_be_sent_across_threads() {
+    fn run(lua: &Lua, source: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        lua.do_string(source)?;
+        Ok(())
+    }
+
+    let lua = Lua::new();
+    lua.open_libs();
+    let err = run(&lua, "error(setmetatable({}, {__name = 'Custom'}))").unwrap_err();
+    let err = std::thread::spawn(move || err).join().unwrap();
+    match err.downcast_ref::<LuaError>() {
+        Some(LuaError::Runtime { value: Some(ErrorValue::Other(description)), .. }) => {
+            assert!(description.starts_with("Custom: 0x"), "{}", description);
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+    match lua.do_string("error(false)") {
+        Err(LuaError::Runtime { value: Some(ErrorValue::Bool(false)), .. }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn threads_only_run_in_their_own_state() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string(r#"
+        assert(thread.spawn == nil)
+        local w = thread.worker("local channel, x = ... return x * 2", 21)
+        local ok, result = w:join()
+        assert(ok and result == 42)
+    "#).unwrap();
+}
@@ -0,0 +1,90 @@
+use macro_lua::*;
+
+#[test]
+fn owned_ref_survives_stack_changes() {
+    let lua = Lua::new();
+    lua.push("anchored");
+    let owned = OwnedRef::from_top(&lua);
+    assert_eq!(lua.get_top(), 0);
+    lua.gc(GcOption::Collect, 0);
+    owned.push_to(&lua);
+    assert_eq!(lua.to_str(-1).as_deref(), Some("anchored"));
+}
+
+#[test]
+fn drop_releases_the_reference() {
+    let lua = Lua::new();
+    lua.push(1);
+    let first = OwnedRef::from_top(&lua);
+    let reference = first.reference();
+    drop(first);
+    lua.push(2);
+    let second = OwnedRef::from_top(&lua);
+    // luaL_ref reuses freed slots
+    assert_eq!(second.reference(), reference);
+}
+
+#[test]
+fn clone_holds_its_own_reference() {
+    let lua = Lua::new();
+    lua.push("shared");
+    let a = OwnedRef::from_top(&lua);
+    let b = a.clone();
+    assert_ne!(a.reference(), b.reference());
+    drop(a);
+    b.push_to(&lua);
+    assert_eq!(lua.to_str(-1).as_deref(), Some("shared"));
+}
+
+#[test]
+#[should_panic(expected = "OwnedRef pushed to a different Lua state")]
+fn push_to_another_state_panics() {
+    let lua = Lua::new();
+    let other = Lua::new();
+    lua.push_nil();
+    let owned = OwnedRef::from_top(&lua);
+    owned.push_to(&other);
+}
+
+#[test]
+fn owned_table_and_function() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("t = {x = 1}; function add(a, b) return a + b end").unwrap();
+    lua.global().get("t");
+    let t = OwnedTable::new(&lua, -1).unwrap();
+    lua.global().get("add");
+    let add = OwnedFunction::new(&lua, -1).unwrap();
+    lua.set_top(0);
+    t.raw_set(&lua, "y", 2);
+    assert_eq!(t.raw_get::<_, i64>(&lua, "x"), Some(1));
+    assert_eq!(t.raw_get::<_, i64>(&lua, "y"), Some(2));
+    assert_eq!(add.call::<_, i64>(&lua, (2, 3)).unwrap(), 5);
+    assert_eq!(lua.get_top(), 0);
+}
+
+#[test]
+fn lua_string_lossy() {
+    let lua = Lua::new();
+    lua.push_bytes(b"ok\xff");
+    let s = LuaString::new(&lua, -1).unwrap();
+    assert_eq!(s.to_str(), None);
+    assert_eq!(s.to_string_lossy(), "ok\u{fffd}");
+}
+
+#[test]
+fn owned_refs_do_not_need_room_on_the_stack() {
+    let lua = Lua::new();
+    lua.push_string("x");
+    let owned = OwnedRef::from_top(&lua);
+    let f = lua.rust_closure(move |s: &State| {
+        // use up the slots a native function is given
+        for _ in 0..macro_lua::ffi::LUA_MINSTACK { s.push_nil(); }
+        let copy = owned.clone();
+        assert!(copy.type_of() == Type::String);
+        drop(copy);
+        0
+    });
+    lua.global().set("f", f);
+    lua.do_string("f()").unwrap();
+}
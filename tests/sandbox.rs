@@ -0,0 +1,129 @@
+use macro_lua::*;
+
+fn run(lua: &Lua, sandbox: &Sandbox, source: &str) -> Result<(), LuaError> {
+    let env = sandbox.env(lua);
+    let chunk = sandbox.load(lua, &env, source, Some("=sandbox"));
+    let result = chunk.and_then(|f| f.call::<_, ()>(()));
+    lua.set_top(0);
+    result
+}
+
+#[test]
+fn nothing_is_allowed_by_default() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let sandbox = Sandbox::new();
+    run(&lua, &sandbox, "assert(print == nil)").unwrap_err();
+    run(&lua, &sandbox, "x = os").unwrap();
+    run(&lua, &sandbox, "return os.time()").unwrap_err();
+}
+
+#[test]
+fn only_allowed_functions_are_visible() {
+    let lua = Lua::new();
+    let sandbox = Sandbox::new()
+        .allow_library(Library::Base)
+        .allow_library(Library::String)
+        .allow_function(Library::Os, "time");
+    run(&lua, &sandbox, r#"
+        assert(type(os.time()) == "number")
+        assert(os.execute == nil and os.getenv == nil)
+        assert(io == nil and debug == nil and package == nil and require == nil)
+        assert(dofile == nil and loadfile == nil)
+        assert(_G == _ENV)
+        assert(("x"):rep(3) == "xxx")
+    "#).unwrap();
+}
+
+#[test]
+fn globals_stay_in_the_environment() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let sandbox = Sandbox::new().allow_library(Library::Base).allow_library(Library::String);
+    run(&lua, &sandbox, "leaked = 1 string.upper = nil").unwrap();
+    lua.do_string(r#"
+        assert(leaked == nil)
+        assert(string.upper("a") == "A")
+    "#).unwrap();
+}
+
+#[test]
+fn precompiled_chunks_are_refused() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let sandbox = Sandbox::new().allow_library(Library::Base).allow_library(Library::String);
+    let env = sandbox.env(&lua);
+    assert!(sandbox.load(&lua, &env, b"\x1bLua\x53\x00", None).is_err());
+    lua.set_top(0);
+    run(&lua, &sandbox, r#"
+        local f, err = load(string.dump(function() end))
+        assert(f == nil and err:find("binary"))
+        assert(load("x = 1"))()
+        assert(x == 1)
+    "#).unwrap();
+}
+
+#[test]
+fn base_functions_do_not_touch_the_host_globals() {
+    let lua = Lua::new();
+    let sandbox = Sandbox::new().allow_library(Library::Base).on_print(|_| ());
+    run(&lua, &sandbox, r#"
+        print("from the sandbox", 1, nil, {})
+        assert(tostring(1) == "1" and _VERSION)
+        assert(collectgarbage == nil)
+    "#).unwrap();
+    for name in &["print", "tostring", "_G", "_VERSION"] {
+        assert_eq!(lua.get_global(name), Type::Nil, "{}", name);
+        lua.pop(1);
+    }
+}
+
+#[test]
+fn the_string_metatable_is_hidden() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let sandbox = Sandbox::new().allow_library(Library::Base).allow_library(Library::String);
+    run(&lua, &sandbox, r#"
+        assert(getmetatable("") == nil)
+        assert(getmetatable(setmetatable({}, {__metatable = "locked"})) == "locked")
+        local mt = {}
+        assert(getmetatable(setmetatable({}, mt)) == mt)
+        assert(("x"):upper() == "X")
+    "#).unwrap();
+    lua.do_string(r#"assert(getmetatable("").__index == string)"#).unwrap();
+}
+
+#[test]
+fn print_goes_to_the_callback() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let lua = Lua::new();
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let sink = lines.clone();
+    let sandbox = Sandbox::new()
+        .allow_function(Library::Base, "print")
+        .allow_function(Library::Base, "pcall")
+        .allow_function(Library::Base, "assert")
+        .allow_function(Library::Base, "error")
+        .allow_function(Library::Base, "setmetatable")
+        .on_print(move |line| sink.borrow_mut().push(String::from_utf8_lossy(line).into_owned()));
+    run(&lua, &sandbox, r#"
+        print("a", 1, nil)
+        print()
+        local bad = setmetatable({}, {__tostring = function() error("no") end})
+        assert(not pcall(print, "before", bad))
+    "#).unwrap();
+    assert_eq!(*lines.borrow(), vec!["a\t1\tnil".to_owned(), String::new()]);
+}
+
+#[test]
+fn base_functions_are_built_once() {
+    let lua = Lua::new();
+    let sandbox = Sandbox::new().allow_library(Library::Base);
+    let first = sandbox.env(&lua);
+    let second = sandbox.env(&lua);
+    first.get("tostring");
+    second.get("tostring");
+    assert!(lua.raw_equal(-1, -2));
+}
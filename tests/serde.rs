@@ -0,0 +1,143 @@
+use macro_lua::*;
+use macro_lua::serde::{from_lua, to_lua};
+use ::serde::{Deserialize, Deserializer, Serialize};
+use ::serde::de::{MapAccess, SeqAccess, Visitor};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Node {
+    name: String,
+    children: Vec<Node>,
+}
+
+fn chain(depth: usize) -> Node {
+    let mut node = Node { name: "leaf".into(), children: vec![] };
+    for _ in 0..depth {
+        node = Node { name: "inner".into(), children: vec![node] };
+    }
+    node
+}
+
+#[test]
+fn nested_values_round_trip() {
+    let lua = Lua::new();
+    let value = chain(20);
+    to_lua(&lua, &value).unwrap();
+    assert_eq!(from_lua::<Node>(&lua, -1).unwrap(), value);
+    assert_eq!(lua.get_top(), 1);
+}
+
+#[test]
+fn serializing_deep_values_fails_cleanly() {
+    let lua = Lua::new();
+    let err = to_lua(&lua, &chain(300)).unwrap_err();
+    assert_eq!(err.message, "table nested too deeply");
+    assert_eq!(lua.get_top(), 0);
+}
+
+#[test]
+fn deserializing_deep_tables_fails_cleanly() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("t = {} for i = 1, 1000 do t = {t} end").unwrap();
+    lua.get_global("t");
+    let err = from_lua::<Any>(&lua, -1).unwrap_err();
+    assert!(err.message.contains("table nested too deeply"), "{}", err);
+    assert_eq!(lua.get_top(), 1);
+}
+
+#[test]
+fn deserializing_cyclic_tables_fails_cleanly() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("t = {} t[1] = t").unwrap();
+    lua.get_global("t");
+    let err = from_lua::<Any>(&lua, -1).unwrap_err();
+    assert!(err.message.contains("recursive table"), "{}", err);
+
+    lua.do_string("m = {} m.self = m").unwrap();
+    lua.get_global("m");
+    let err = from_lua::<BTreeMap<String, Any>>(&lua, -1).unwrap_err();
+    assert!(err.message.contains("recursive table"), "{}", err);
+    assert_eq!(lua.get_top(), 2);
+}
+
+#[test]
+fn shared_tables_are_not_cycles() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("local x = {1, 2} s = {x, x}").unwrap();
+    lua.get_global("s");
+    assert_eq!(from_lua::<Vec<Vec<i64>>>(&lua, -1).unwrap(), vec![vec![1, 2], vec![1, 2]]);
+}
+
+/// Accepts any value, walking into every table.
+#[derive(Debug)]
+struct Any;
+
+impl<'de> Deserialize<'de> for Any {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Any, D::Error> {
+        struct AnyVisitor;
+        impl<'de> Visitor<'de> for AnyVisitor {
+            type Value = Any;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("anything") }
+            fn visit_unit<E>(self) -> Result<Any, E> { Ok(Any) }
+            fn visit_i64<E>(self, _: i64) -> Result<Any, E> { Ok(Any) }
+            fn visit_str<E>(self, _: &str) -> Result<Any, E> { Ok(Any) }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Any, A::Error> {
+                while seq.next_element::<Any>()?.is_some() {}
+                Ok(Any)
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Any, A::Error> {
+                while map.next_entry::<Any, Any>()?.is_some() {}
+                Ok(Any)
+            }
+        }
+        d.deserialize_any(AnyVisitor)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Tagged {
+    #[serde(rename = "a\0b")]
+    Unit,
+    Fields {
+        #[serde(rename = "x\0y")]
+        x: i64,
+    },
+}
+
+#[test]
+fn names_with_nuls_are_plain_keys() {
+    use macro_lua::serde::{from_lua_with, to_lua_with, EnumRepr, SerdeOptions};
+
+    let lua = Lua::new();
+    for repr in vec![
+        EnumRepr::External,
+        EnumRepr::Internal { tag: "t\0ag".into() },
+        EnumRepr::Adjacent { tag: "t\0ag".into(), content: "c\0ontent".into() },
+    ] {
+        let options = SerdeOptions { enum_repr: repr, ..SerdeOptions::default() };
+        for value in vec![Tagged::Unit, Tagged::Fields { x: 1 }] {
+            to_lua_with(&lua, &value, &options).unwrap();
+            assert_eq!(from_lua_with::<Tagged>(&lua, -1, &options).unwrap(), value);
+            lua.pop(1);
+        }
+    }
+}
+
+#[test]
+fn serde_errors_are_raised_with_their_message() {
+    use macro_lua::serde::Serde;
+
+    let lua = Lua::new();
+    lua.open_libs();
+    let f = lua.rust_closure(|s: &State| { s.push(Serde(chain(300))); 1 });
+    lua.global().set("deep", f);
+    lua.do_string(r#"
+        local ok, err = pcall(deep)
+        assert(not ok)
+        assert(tostring(err):find("^table nested too deeply at 'children%[1%]"), tostring(err))
+    "#).unwrap();
+}
@@ -0,0 +1,78 @@
+use macro_lua::*;
+
+#[test]
+fn to_str_copies_the_string() {
+    let lua = Lua::new();
+    lua.push("transient");
+    let s = lua.to_str(-1).unwrap();
+    lua.pop(1);
+    lua.gc(GcOption::Collect, 0);
+    assert_eq!(s, "transient");
+}
+
+#[test]
+fn to_str_rejects_invalid_utf8() {
+    let lua = Lua::new();
+    lua.push_bytes(b"\xff\xfe");
+    assert_eq!(lua.to_str(-1), None);
+    assert_eq!(lua.to_bytes(-1).as_deref(), Some(&b"\xff\xfe"[..]));
+}
+
+#[test]
+fn to_str_converts_numbers() {
+    let lua = Lua::new();
+    lua.push(12);
+    assert_eq!(lua.to_str(-1).as_deref(), Some("12"));
+    lua.push_nil();
+    assert_eq!(lua.to_str(-1), None);
+}
+
+#[test]
+fn lua_string_stays_valid_after_pop() {
+    let lua = Lua::new();
+    lua.push_string(&"x".repeat(100));
+    let s = LuaString::new(&lua, -1).unwrap();
+    lua.pop(1);
+    lua.gc(GcOption::Collect, 0);
+    assert_eq!(s.to_str(), Some("x".repeat(100).as_str()));
+    assert_eq!(lua.get_top(), 0);
+}
+
+#[test]
+fn trace_error_keeps_the_message() {
+    let lua = Lua::new();
+    lua.push("boom");
+    let traceback = lua.trace_error(None);
+    assert!(traceback.starts_with("boom\nstack traceback:"), "{}", traceback);
+    assert_eq!(lua.get_top(), 1);
+    assert_eq!(lua.to_str(-1).unwrap(), traceback);
+}
+
+#[test]
+fn check_string_raises_argument_errors() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let f = lua.rust_closure(|s: &State| {
+        let name = s.check_string(1);
+        let suffix = s.opt_string(2, "!");
+        s.pushx(name + &suffix)
+    });
+    lua.global().set("greet", f);
+    lua.do_string(r#"
+        assert(greet("hi") == "hi!" and greet("hi", "?") == "hi?")
+        local ok, err = pcall(greet, "\xff")
+        assert(not ok and err:find("bad argument #1") and err:find("UTF%-8"), err)
+        ok, err = pcall(greet, "hi", {})
+        assert(not ok and err:find("bad argument #2"), err)
+    "#).unwrap();
+}
+
+#[test]
+fn gsub_returns_an_owned_copy() {
+    let lua = Lua::new();
+    let s = lua.gsub("a.b.c", ".", "\0");
+    lua.pop(1);
+    lua.gc(GcOption::Collect, 0);
+    assert_eq!(s, "a\0b\0c");
+    assert_eq!(lua.get_top(), 0);
+}
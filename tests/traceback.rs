@@ -0,0 +1,51 @@
+use macro_lua::*;
+
+fn traceback(err: &LuaError) -> &Traceback {
+    match err {
+        LuaError::Runtime { traceback: Some(traceback), .. } => traceback,
+        other => panic!("no traceback: {:?}", other),
+    }
+}
+
+#[test]
+fn runtime_errors_carry_a_traceback() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let err = lua.do_string("function inner() error('boom') end inner()").unwrap_err();
+    let tb = traceback(&err);
+    assert!(tb.frames.iter().any(|f| f.name.as_deref() == Some("inner")), "{}", tb);
+    assert!(tb.text.contains("inner"));
+}
+
+#[test]
+fn threads_keep_their_own_traceback() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.do_string("function in_co() error('co') end").unwrap();
+    lua.do_string("function in_main() error('main') end").unwrap();
+    lua.get_global("in_co");
+    let f = OwnedFunction::new(&lua, -1).unwrap();
+    lua.pop(1);
+    let mut co = Coroutine::from_function(&lua, &f);
+    let co_err = co.resume::<_, ()>(()).unwrap_err();
+    assert!(traceback(&co_err).text.contains("in_co"));
+
+    let err = lua.do_string("in_main()").unwrap_err();
+    let tb = traceback(&err);
+    assert!(tb.frames.iter().any(|f| f.name.as_deref() == Some("in_main")), "{}", tb);
+    assert!(!tb.text.contains("in_co"));
+}
+
+#[test]
+fn panics_do_not_leave_a_traceback_behind() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let f = lua.rust_closure(|_: &State| panic!("native"));
+    lua.global().set("native", f);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| lua.do_string("native()")));
+    assert!(result.is_err());
+    lua.set_top(0);
+    let err = lua.do_string("error('plain', 0)").unwrap_err();
+    let tb = traceback(&err);
+    assert!(!tb.text.contains("native"), "{}", tb);
+}
@@ -0,0 +1,9 @@
+use macro_lua::*;
+
+fn main() {
+    let lua = Lua::new();
+    lua.push("borrowed");
+    let s: &str = FromIndex::from_lua(&lua, -1);
+    lua.pop(1);
+    println!("{}", s);
+}
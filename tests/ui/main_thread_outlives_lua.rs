@@ -0,0 +1,8 @@
+use macro_lua::*;
+
+fn main() {
+    let lua = Lua::new();
+    let main = lua.main_thread();
+    drop(lua);
+    main.push_nil();
+}
@@ -0,0 +1,8 @@
+use macro_lua::*;
+
+fn main() {
+    let lua = Lua::new();
+    lua.push_nil();
+    let owned = OwnedRef::from_top(&lua);
+    std::thread::spawn(move || drop(owned));
+}
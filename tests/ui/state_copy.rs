@@ -0,0 +1,8 @@
+use macro_lua::*;
+
+fn main() {
+    let lua = Lua::new();
+    let raw: State = *lua;
+    drop(lua);
+    raw.push_nil();
+}
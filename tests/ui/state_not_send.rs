@@ -0,0 +1,6 @@
+use macro_lua::*;
+
+fn main() {
+    let lua = Lua::new();
+    std::thread::spawn(move || lua.push_nil());
+}
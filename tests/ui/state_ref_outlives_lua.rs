@@ -0,0 +1,9 @@
+use macro_lua::*;
+
+fn main() {
+    let state = {
+        let lua = Lua::new();
+        lua.state()
+    };
+    state.push_nil();
+}
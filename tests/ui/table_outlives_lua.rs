@@ -0,0 +1,8 @@
+use macro_lua::*;
+
+fn main() {
+    let lua = Lua::new();
+    let globals = lua.global();
+    drop(lua);
+    globals.set("x", 1);
+}
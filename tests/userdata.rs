@@ -0,0 +1,252 @@
+use macro_lua::*;
+use std::cell::Cell;
+use std::rc::Rc;
+
+struct Counter {
+    count: i64,
+    drops: Rc<Cell<u32>>,
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) { self.drops.set(self.drops.get() + 1); }
+}
+
+impl UserData for Counter {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("get", |_, this, ()| this.count);
+        methods.add_method_mut("add", |_, this, n: i64| { this.count += n; this.count });
+        methods.add_method("call", |s, _, ()| {
+            s.push_value(2);
+            if s.pcall(0, 0, 0) != ThreadStatus::Ok { s.error() }
+        });
+    }
+
+    fn add_fields(fields: &mut UserDataFields<Self>) {
+        fields.add_field_get("count", |_, this| this.count);
+        fields.add_field_set("count", |_, this, n: i64| this.count = n);
+    }
+
+    fn add_meta_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("__tostring", |_, this, ()| format!("Counter({})", this.count));
+    }
+}
+
+fn counter(lua: &Lua, drops: &Rc<Cell<u32>>) {
+    lua.push_typed_userdata(Counter { count: 0, drops: drops.clone() });
+    lua.set_global("c");
+}
+
+#[test]
+fn methods_fields_and_metamethods() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.do_string(r#"
+        assert(c:add(2) == 2 and c:get() == 2)
+        c.count = 10
+        assert(c.count == 10 and c:get() == 10)
+        assert(tostring(c) == "Counter(10)")
+        assert(not pcall(function() c.missing = 1 end))
+    "#).unwrap();
+    lua.get_global("c");
+    assert_eq!(lua.borrow_userdata::<Counter>(-1).unwrap().count, 10);
+}
+
+#[test]
+fn wrong_receivers_are_rejected() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.do_string(r#"
+        local ok, err = pcall(c.get, {})
+        assert(not ok and err:find("Counter"), err)
+        ok, err = pcall(c.add, c, "x")
+        assert(not ok)
+    "#).unwrap();
+    lua.get_global("c");
+    assert!(lua.borrow_userdata::<String>(-1).is_err());
+}
+
+#[test]
+fn collected_values_are_dropped_once() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.do_string("c = nil collectgarbage() collectgarbage()").unwrap();
+    assert_eq!(drops.get(), 1);
+    counter(&lua, &drops);
+    drop(lua);
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn reentrant_borrows_are_errors() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.do_string(r#"
+        c:call(function() assert(c:get() == 0) end)
+        local ok = pcall(c.call, c, function() c:add(1) end)
+        assert(ok == false)
+        -- the borrow taken by `call` was released by the error
+        assert(c:add(1) == 1)
+        assert(not pcall(c.add, c, "x"))
+        c.count = 5
+        assert(not pcall(function() c.count = {} end))
+        assert(c:add(1) == 6)
+    "#).unwrap();
+}
+
+#[test]
+fn borrows_keep_the_value_alive() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    lua.push_typed_userdata(Counter { count: 7, drops: drops.clone() });
+    let this = lua.borrow_userdata::<Counter>(-1).unwrap();
+    lua.pop(1);
+    lua.do_string("collectgarbage() collectgarbage()").unwrap();
+    assert_eq!(this.count, 7);
+    assert_eq!(drops.get(), 0);
+    drop(this);
+    lua.do_string("collectgarbage() collectgarbage()").unwrap();
+    assert_eq!(drops.get(), 1);
+}
+
+struct Acc(i64);
+
+metatable! {
+    const ACC_METATABLE = Acc(s: State, this: Self) IndexSelf;
+
+    "add" (n: i64) push { this.0 += n; this.0 }
+    "fail" () { s.push_string("failed"); s.error() }
+    "results" () { s.pushx((this.0, "x")) }
+    "wait" () { this.0 += 1; s.co_yield(0) }
+    "grow" (n: i64) { s.push_string(&"x".repeat(n as usize)); 1 }
+    "callback" () { s.push_value(2); s.callk(0, 0, |_, _| 0) }
+}
+
+#[test]
+fn metatable_methods_release_borrows_on_error() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.push_userdata(Acc(0), Some(ACC_METATABLE));
+    lua.set_global("a");
+    lua.do_string(r#"
+        assert(a:add(2) == 2)
+        local ok, err = pcall(a.fail, a)
+        assert(not ok and err == "failed")
+        assert(not pcall(a.add, a, "x"))
+        assert(a:add(1) == 3)
+        local n, x = a:results()
+        assert(n == 3 and x == "x")
+        assert(not pcall(a.add, {}, 1))
+    "#).unwrap();
+}
+
+#[test]
+fn methods_can_yield() {
+    let lua = Lua::new();
+    lua.open_libs();
+    lua.push_userdata(Acc(0), Some(ACC_METATABLE));
+    lua.set_global("a");
+    lua.do_string(r#"
+        local co = coroutine.wrap(function() a:wait() return a:add(10) end)
+        co()
+        -- the borrow taken by `wait` was released by the yield
+        assert(a:add(1) == 2)
+        assert(co() == 12)
+    "#).unwrap();
+}
+
+#[test]
+fn method_errors_keep_their_status_and_frames() {
+    let lua = StateBuilder::new().memory_limit(1 << 20).build().unwrap();
+    lua.open_libs();
+    lua.push_userdata(Acc(0), Some(ACC_METATABLE));
+    lua.set_global("a");
+    let result = lua.do_string("a:grow(4 * 1024 * 1024)");
+    assert!(matches!(result, Err(LuaError::Memory(_))), "{:?}", result);
+    let result = lua.do_string("function inner() error('boom') end a:callback(inner)");
+    match result {
+        Err(LuaError::Runtime { traceback: Some(tb), .. }) => {
+            assert!(tb.text.contains("in function 'inner'"), "{}", tb);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    lua.do_string("assert(a:add(1) == 1)").unwrap();
+}
+
+#[test]
+fn metatables_are_hidden_from_lua() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.do_string(r#"
+        assert(getmetatable(c) == false)
+        local f = coroutine.wrap(function() end)
+        assert(getmetatable(f) == nil)
+    "#).unwrap();
+}
+
+#[test]
+fn gc_metamethods_drop_once_and_check_the_type() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.push_typed_userdata(Counter { count: 0, drops: drops.clone() });
+    lua.set_global("other");
+    lua.get_global("c");
+    assert!(lua.get_metatable(-1));
+    lua.get_field(-1, "__gc");
+    lua.set_global("gc");
+    lua.pop(2);
+    lua.do_string(r#"
+        gc(c) gc(c) gc({}) gc(io.stdout)
+        local ok, err = pcall(c.get, c)
+        assert(not ok and err:find("already dropped"), err)
+    "#).unwrap();
+    assert_eq!(drops.get(), 1);
+    drop(lua);
+    assert_eq!(drops.get(), 2);
+}
+
+struct A(i64);
+struct B(i64);
+
+fn empty_a(_: Table, _: &State) {}
+fn empty_b(_: Table, _: &State) {}
+
+#[test]
+fn metatables_are_cached_per_type() {
+    let lua = Lua::new();
+    lua.push_userdata(A(1), None);
+    lua.push_userdata(B(2), None);
+    lua.push_userdata(A(3), Some(empty_a));
+    lua.push_userdata(B(4), Some(empty_b));
+    assert_eq!(lua.borrow_userdata::<A>(1).unwrap().0, 1);
+    assert_eq!(lua.borrow_userdata::<B>(2).unwrap().0, 2);
+    assert_eq!(lua.borrow_userdata::<A>(3).unwrap().0, 3);
+    assert_eq!(lua.borrow_userdata::<B>(4).unwrap().0, 4);
+    assert!(lua.borrow_userdata::<B>(1).is_err());
+    assert!(lua.borrow_userdata::<A>(4).is_err());
+    assert_eq!(lua.describe_value(1), "A");
+    assert_eq!(lua.describe_value(2), "B");
+}
+
+#[test]
+fn pushed_values_are_reached_through_borrows() {
+    let lua = Lua::new();
+    lua.open_libs();
+    let drops = Rc::new(Cell::new(0));
+    counter(&lua, &drops);
+    lua.get_global("c");
+    lua.borrow_userdata_mut::<Counter>(-1).unwrap().count = 5;
+    lua.do_string("assert(c:get() == 5)").unwrap();
+}